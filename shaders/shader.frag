@@ -1,10 +1,13 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 
-layout(binding = 1) uniform sampler2D texSampler;
+// Length must match MAX_TEXTURES in texture.rs
+layout(binding = 1) uniform sampler2D textures[16];
 
 layout(location = 0) in vec3 fragColor;
 layout(location = 1) in vec2 fragTexCoord;
+// The same for all instances of a draw
+layout(location = 2) flat in uint textureIndex;
 
 layout(location = 0) out vec4 outColor;
 
@@ -16,8 +19,9 @@ void main() {
     // outColor = texture(texSampler, fragTexCoord);
     // outColor = vec4(ceil(texture(texSampler, fragTexCoord)).rgb * fragColor, 1.0);
 
-    if (texture(texSampler, fragTexCoord).rgb == vec3(0.0, 0.0, 0.0)) {
+    vec4 texColor = texture(textures[textureIndex], fragTexCoord);
+    if (texColor.rgb == vec3(0.0, 0.0, 0.0)) {
     	discard;
     }
-    outColor = vec4(ceil(texture(texSampler, fragTexCoord)).rgb * fragColor, 0.1);
+    outColor = vec4(ceil(texColor).rgb * fragColor, 0.1);
 }
\ No newline at end of file
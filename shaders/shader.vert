@@ -3,17 +3,33 @@
 
 
 layout(binding = 0) uniform UniformBufferObject {
-    mat4 model;
     mat4 view;
     mat4 proj;
 } ubo;
 
+// Must match DrawData in vulkan.rs and cull.comp
+struct DrawData {
+    mat4 model;
+    uint textureIndex;
+};
+
+// Any binding works, the presenter finds it by reflection
+layout(std430, binding = 2) readonly buffer Draws {
+    DrawData draws[];
+};
+
 layout(location = 0) in vec3 inPosition;
 layout(location = 1) in vec3 inColor;
 layout(location = 2) in vec2 inTexCoord;
 
+// Per instance, must match InstanceVertex in vulkan.rs
+layout(location = 3) in mat4 instanceModel;
+layout(location = 7) in vec4 instanceColor;
+layout(location = 8) in uint drawIndex;
+
 layout(location = 0) out vec3 fragColor;
 layout(location = 1) out vec2 fragTexCoord;
+layout(location = 2) flat out uint fragTextureIndex;
 
 out gl_PerVertex {
     vec4 gl_Position;
@@ -23,9 +39,11 @@ void main() {
 	// gl_Position = ubo_proj * ubo_view * ubo_model * vec4(inPosition, 0.0, 1.0);
     // gl_Position = vec4(inPosition, 0.0, 1.0);
     // gl_Position = ubo.model * vec4(inPosition, 0.0, 1.0);
-    gl_Position = ubo.proj * ubo.view * ubo.model * vec4(inPosition, 1.0);
-    fragColor = inColor;
+    DrawData draw = draws[drawIndex];
+    gl_Position = ubo.proj * ubo.view * draw.model * instanceModel * vec4(inPosition, 1.0);
+    fragColor = inColor * instanceColor.rgb;
     fragTexCoord = inTexCoord;
+    fragTextureIndex = draw.textureIndex;
 }
 
 
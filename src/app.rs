@@ -4,30 +4,50 @@
  * Kicking everything off and calling the last function
  */
 use vulkan;
-use input;
-use presenter::{Presenter};
+use input::{self, Action};
+use presenter::{Presenter, PresenterConfig};
+use pipeline::PipelineDesc;
+use scene::ObjectId;
 
 use std::ffi::CString;
 
 use std::ops::Drop;
 
-use voodoo_winit::winit::{ControlFlow};
-use std::mem;
 use std::time;
+use std::fs;
+use std::thread;
+use std::path::PathBuf;
 
-use cgmath::{Matrix3, Matrix4};
+use cgmath::{Matrix3, Matrix4, Vector3};
+use image::RgbaImage;
 
-use voodoo::{Result as VdResult, ApplicationInfo, MemoryMapFlags};
+use voodoo::{Result as VdResult, ApplicationInfo, Extent2d};
+use error::{Result, ResultExt};
 
 
 lazy_static! {
     pub static ref APP_NAME: CString = CString::new("My App").unwrap();
 }
 
+static SCREENSHOT_DIR: &str = "screenshots";
+
+
+/// The pipelines cycled through with `Action::CyclePipeline`.
+const PIPELINE_PRESETS: [(&str, fn() -> PipelineDesc); 5] = [
+    ("opaque", PipelineDesc::opaque),
+    ("wireframe", PipelineDesc::wireframe),
+    ("alpha blended", PipelineDesc::alpha_blended),
+    ("additive", PipelineDesc::additive),
+    ("lines", PipelineDesc::lines),
+];
 
 pub struct App {
     pub info: ApplicationInfo<'static>,
     presenter: Presenter,
+    /// The object drawing the loaded mesh, spun around over time.
+    object: ObjectId,
+    /// Copies of the object added at runtime, standing still next to it.
+    copies: Vec<ObjectId>,
     start_time: time::Instant,
 }
 
@@ -41,28 +61,79 @@ impl App {
             .build()
     }
 
-    pub fn new() -> VdResult<App> {
+    pub fn new(config: PresenterConfig) -> Result<App> {
         let info = Self::init_info(&APP_NAME);
 
-        let presenter = Presenter::new(info.clone());
+        let presenter = Presenter::new(info.clone(), config)?;
 
+        Self::with_presenter(info, presenter)
+    }
+
+    /// Creates an app rendering offscreen, without opening a window.
+    pub fn headless(extent: Extent2d) -> Result<App> {
+        let info = Self::init_info(&APP_NAME);
 
+        let presenter = Presenter::headless(info.clone(), PresenterConfig::default(), extent)?;
+
+        Self::with_presenter(info, presenter)
+    }
+
+    fn with_presenter(info: ApplicationInfo<'static>,
+                      mut presenter: Presenter) -> Result<App> {
         let start_time = time::Instant::now();
 
+        let model_mesh = presenter.model_mesh;
+        let object = presenter.add_object(model_mesh);
 
         Ok(App {
             info,
             presenter: presenter,
+            object,
+            copies: Vec::new(),
             start_time,
         })
     }
 
-    fn update_uniform_buffer(&mut self) -> VdResult<()> {
+    /// Seconds passed since the app was started.
+    fn elapsed_time(&self) -> f32 {
         let current_time = time::Instant::now();
         let elapsed = current_time.duration_since(self.start_time);
-        let time = elapsed.as_secs() as f32 + (elapsed.subsec_nanos() as f32 * 1e-9);
+        elapsed.as_secs() as f32 + (elapsed.subsec_nanos() as f32 * 1e-9)
+    }
+
+    /// Moves the objects to where they are `time` seconds after the start
+    /// time.
+    fn update_scene(&mut self, time: f32) {
+        let rotation = Matrix3::from_angle_z(cgmath::Rad(time)) *
+            Matrix3::from_angle_x(cgmath::Rad(time / 2.0));
+
+        self.presenter.scene.set_transform(self.object, Matrix4::from(rotation));
+    }
+
+    /// Adds a copy of the object, placed in a row next to the others.
+    fn add_copy(&mut self) {
+        let mut copy = match self.presenter.scene.object(self.object) {
+            Some(object) => object.clone(),
+            None => return,
+        };
+        let offset = (self.copies.len() + 1) as f32;
+        copy.transform = Matrix4::from_translation(Vector3::new(0.0, -offset, 0.0));
+        let id = self.presenter.scene.add_object(copy);
+        self.copies.push(id);
+        println!("Objects: {}", self.presenter.scene.object_count());
+    }
+
+    /// Removes the last copy added, if any.
+    fn remove_copy(&mut self) {
+        if let Some(id) = self.copies.pop() {
+            self.presenter.scene.remove_object(id);
+            println!("Objects: {}", self.presenter.scene.object_count());
+        }
+    }
 
-        let extent = self.presenter.swapchain.as_ref().unwrap().extent().clone();
+    /// Returns the uniform buffer contents of the camera.
+    fn uniform_buffer_object(&self) -> vulkan::UniformBufferObject {
+        let extent = self.presenter.viewport_extent();
 
         let mut proj = cgmath::perspective(cgmath::Rad(45.0f32.to_radians()),
             extent.width() as f32 / extent.height() as f32, 0.1, 10.0);
@@ -73,49 +144,118 @@ impl App {
         let scale = cgmath::Matrix4::from_scale(1.5);
         proj[1][1] *= -1.0;
 
-        let rotation = Matrix3::from_angle_z(cgmath::Rad(time)) *
-            Matrix3::from_angle_x(cgmath::Rad(time / 2.0));
-
-        let model = Matrix4::from(rotation);
-
-        let ubo = vulkan::UniformBufferObject {
-            model: model.into(),
+        vulkan::UniformBufferObject {
             view: (view * scale).into(),
             proj: proj.into(),
-        };
+        }
+    }
 
-        let mut data = unsafe {
-            self.presenter.uniform_buffer_memory.map(0, mem::size_of::<vulkan::UniformBufferObject>() as u64,
-                MemoryMapFlags::empty()).unwrap()
+
+    /// Saves the frame captured after a `CaptureFrame` action as a PNG in
+    /// the screenshot directory, once it has been drawn.
+    fn save_screenshot(&mut self) {
+        let frame = match self.presenter.take_captured_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(err) => {
+                println!("Unable to capture frame: {}", err);
+                return;
+            }
         };
-        data.copy_from_slice(&[ubo]);
-        self.presenter.uniform_buffer_memory.unmap(data);
 
-        Ok(())
-    }
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap();
+        let path = PathBuf::from(SCREENSHOT_DIR)
+            .join(format!("screenshot-{}-{:03}.png",
+                timestamp.as_secs(), timestamp.subsec_millis()));
 
+        match fs::create_dir_all(SCREENSHOT_DIR).and_then(|_| frame.save(&path)) {
+            Ok(()) => println!("Saved screenshot to {}", path.display()),
+            Err(err) => println!("Unable to save screenshot to {}: {}", path.display(), err),
+        }
+    }
 
-    pub fn main_loop(&mut self) -> VdResult<()> {
+    pub fn main_loop(&mut self) -> Result<()> {
         let mut exit = false;
+        let mut actions = Vec::new();
 
         loop {
-            self.presenter.events_loop.poll_events(|event| {
-                // Add support for window resizing
-                match input::update(event) {
-                    ControlFlow::Break => { exit = true; },
-                    _ => ()
+            if let Some(events_loop) = self.presenter.events_loop.as_mut() {
+                events_loop.poll_events(|event| {
+                    actions.push(input::update(event));
+                });
+            }
+
+            for action in actions.drain(..) {
+                match action {
+                    Action::Exit => { exit = true; },
+                    Action::CaptureFrame => {
+                        if let Err(err) = self.presenter.request_capture() {
+                            println!("Unable to capture frame: {}", err);
+                        }
+                    },
+                    Action::Resize(..) => self.presenter.resize(),
+                    Action::CyclePresentPolicy => {
+                        let policy = self.presenter.present_policy().next();
+                        self.presenter.set_present_policy(policy)
+                            .context("changing present policy")?;
+                        println!("Present policy: {:?}", policy);
+                    },
+                    Action::CyclePipeline => {
+                        let current = self.presenter.pipeline_desc();
+                        let next = PIPELINE_PRESETS.iter()
+                            .position(|&(_, preset)| preset() == current)
+                            .map_or(0, |i| (i + 1) % PIPELINE_PRESETS.len());
+                        let (name, preset) = PIPELINE_PRESETS[next];
+                        // Wireframes need a device feature which may be missing
+                        match self.presenter.set_pipeline_desc(preset()) {
+                            Ok(()) => println!("Pipeline: {}", name),
+                            Err(err) => println!("Failed to change pipeline to {}: {}", name, err),
+                        }
+                    },
+                    Action::ToggleSplitScreen => {
+                        let viewports = if self.presenter.viewports().len() == 1 {
+                            vulkan::ViewportRect::columns(2)
+                        } else {
+                            vec![vulkan::ViewportRect::full()]
+                        };
+                        self.presenter.set_viewports(viewports)
+                            .context("changing viewports")?;
+                    },
+                    Action::AddObject => self.add_copy(),
+                    Action::RemoveObject => self.remove_copy(),
+                    Action::Continue => (),
                 }
-            });
+            }
 
             if exit { break; }
 
-            self.update_uniform_buffer().unwrap();
-            self.presenter.draw_frame().unwrap();
+            // Minimized, wait until the window is restored
+            if self.presenter.is_paused() {
+                thread::sleep(time::Duration::from_millis(16));
+                continue;
+            }
+
+            let time = self.elapsed_time();
+            self.update_scene(time);
+            let ubo = self.uniform_buffer_object();
+            self.presenter.draw_frame(&ubo)
+                .context("drawing frame")?;
+            self.save_screenshot();
         }
 
         self.presenter.device.wait_idle();
         Ok(())
     }
+
+    /// Renders the scene as it is `time` seconds after the start time and
+    /// reads the frame back.
+    pub fn render_offscreen(&mut self, time: f32) -> VdResult<RgbaImage> {
+        self.update_scene(time);
+        let ubo = self.uniform_buffer_object();
+        self.presenter.capture_frame(&ubo)
+    }
 }
 
 impl Drop for App {
@@ -129,11 +269,6 @@ impl Drop for App {
 //    self
 //}
 
-//pub fn print_info(&self) {
-//    println!("Using Device: {} (type: {:?})",
-//             self.device.physical_device().name(), self.device.physical_device().ty());
-//}
-
 //fn surface_get_dimensions(surface: &Arc<Surface<winit::Window>>) -> [u32; 2] {
 //    if let Some(dimensions) = surface.window().get_inner_size() {
 //        let dimensions: (u32, u32) = dimensions.to_physical(surface.window().get_hidpi_factor()).into();
@@ -0,0 +1,559 @@
+/*
+ * bcn.rs
+ * CPU decompression of BC1-BC5 and BC7 block compressed
+ * textures into RGBA8, for devices without BC support.
+ * BC6H holds HDR texels, which RGBA8 can't store, so it isn't
+ * decompressed.
+ */
+
+/// The block compressed formats which can be decompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockFormat {
+    Bc1,
+    Bc2,
+    Bc3,
+    Bc4 { signed: bool },
+    Bc5 { signed: bool },
+    Bc7,
+}
+
+impl BlockFormat {
+    /// Size of one compressed 4x4 block in bytes.
+    pub fn block_bytes(&self) -> usize {
+        match *self {
+            BlockFormat::Bc1 | BlockFormat::Bc4 { .. } => 8,
+            _ => 16,
+        }
+    }
+}
+
+type Block = [[u8; 4]; 16];
+
+/// Decompresses an image of `width` x `height` pixels into tightly packed
+/// RGBA8 pixels. Returns `None` if `data` is too short.
+///
+/// Channels match how the GPU samples each format: BC4 fills red, BC5 red
+/// and green. Signed formats decompress to snorm bytes.
+pub fn decompress(format: BlockFormat, width: u32, height: u32, data: &[u8]) -> Option<Vec<u8>> {
+    let (width, height) = (width as usize, height as usize);
+    let blocks_x = (width + 3) / 4;
+    let blocks_y = (height + 3) / 4;
+    let block_bytes = format.block_bytes();
+    if data.len() < blocks_x * blocks_y * block_bytes {
+        return None;
+    }
+
+    let mut pixels = vec![0; width * height * 4];
+    let mut block = [[0; 4]; 16];
+    for (i, src) in data.chunks(block_bytes).take(blocks_x * blocks_y).enumerate() {
+        decode_block(format, src, &mut block);
+
+        let (bx, by) = (i % blocks_x * 4, i / blocks_x * 4);
+        for (j, texel) in block.iter().enumerate() {
+            let (x, y) = (bx + j % 4, by + j / 4);
+            // Blocks on the right and bottom edge may extend past the image
+            if x < width && y < height {
+                let offset = (y * width + x) * 4;
+                pixels[offset..offset + 4].copy_from_slice(texel);
+            }
+        }
+    }
+    Some(pixels)
+}
+
+fn decode_block(format: BlockFormat, src: &[u8], out: &mut Block) {
+    match format {
+        BlockFormat::Bc1 => decode_color(src, true, out),
+        BlockFormat::Bc2 => {
+            decode_color(&src[8..], false, out);
+            for (i, texel) in out.iter_mut().enumerate() {
+                let nibble = (src[i / 2] >> (i % 2 * 4)) & 0xf;
+                texel[3] = nibble * 17;
+            }
+        },
+        BlockFormat::Bc3 => {
+            decode_color(&src[8..], false, out);
+            decode_channel(src, false, out, 3);
+        },
+        BlockFormat::Bc4 { signed } => {
+            decode_channel(src, signed, out, 0);
+            for texel in out.iter_mut() {
+                *texel = [texel[0], 0, 0, one(signed)];
+            }
+        },
+        BlockFormat::Bc5 { signed } => {
+            decode_channel(src, signed, out, 0);
+            decode_channel(&src[8..], signed, out, 1);
+            for texel in out.iter_mut() {
+                texel[2] = 0;
+                texel[3] = one(signed);
+            }
+        },
+        BlockFormat::Bc7 => decode_bc7(src, out),
+    }
+}
+
+/// 1.0 as an unorm or snorm byte.
+fn one(signed: bool) -> u8 {
+    if signed { 127 } else { 255 }
+}
+
+fn read_u16(src: &[u8]) -> u16 {
+    src[0] as u16 | (src[1] as u16) << 8
+}
+
+fn unpack_565(color: u16) -> [u8; 4] {
+    let r = (color >> 11 & 0x1f) as u8;
+    let g = (color >> 5 & 0x3f) as u8;
+    let b = (color & 0x1f) as u8;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2, 255]
+}
+
+/// Decodes the 8 byte color block of BC1-BC3. Only BC1 has the three
+/// color mode with transparent black.
+fn decode_color(src: &[u8], allow_transparent: bool, out: &mut Block) {
+    let c0 = read_u16(src);
+    let c1 = read_u16(&src[2..]);
+    let e0 = unpack_565(c0);
+    let e1 = unpack_565(c1);
+
+    let mut palette = [e0, e1, [0; 4], [0; 4]];
+    let mix = |a: u8, b: u8, wa: u16, wb: u16| ((a as u16 * wa + b as u16 * wb) / (wa + wb)) as u8;
+    if c0 > c1 || !allow_transparent {
+        for c in 0..3 {
+            palette[2][c] = mix(e0[c], e1[c], 2, 1);
+            palette[3][c] = mix(e0[c], e1[c], 1, 2);
+        }
+        palette[2][3] = 255;
+        palette[3][3] = 255;
+    } else {
+        for c in 0..3 {
+            palette[2][c] = mix(e0[c], e1[c], 1, 1);
+        }
+        palette[2][3] = 255;
+        // palette[3] stays transparent black
+    }
+
+    for (i, texel) in out.iter_mut().enumerate() {
+        let index = (src[4 + i / 4] >> (i % 4 * 2)) & 0x3;
+        *texel = palette[index as usize];
+    }
+}
+
+/// Decodes a BC4 style 8 byte single channel block into `channel` of `out`.
+/// Signed values are stored as snorm bytes.
+fn decode_channel(src: &[u8], signed: bool, out: &mut Block, channel: usize) {
+    let (e0, e1) = if signed {
+        ((src[0] as i8).max(-127) as i32, (src[1] as i8).max(-127) as i32)
+    } else {
+        (src[0] as i32, src[1] as i32)
+    };
+    let (min, max) = if signed { (-127, 127) } else { (0, 255) };
+
+    let mut palette = [e0, e1, 0, 0, 0, 0, 0, 0];
+    if e0 > e1 {
+        for i in 1..7 {
+            palette[i + 1] = ((7 - i) as i32 * e0 + i as i32 * e1) / 7;
+        }
+    } else {
+        for i in 1..5 {
+            palette[i + 1] = ((5 - i) as i32 * e0 + i as i32 * e1) / 5;
+        }
+        palette[6] = min;
+        palette[7] = max;
+    }
+
+    let bits = src[2..8].iter().rev().fold(0u64, |bits, &byte| bits << 8 | byte as u64);
+    for (i, texel) in out.iter_mut().enumerate() {
+        let value = palette[(bits >> (i * 3) & 0x7) as usize];
+        texel[channel] = value as i8 as u8;
+    }
+}
+
+/// Reads bit fields of a 128 bit block, least significant bit first.
+struct BitReader<'a> {
+    src: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, count: usize) -> u8 {
+        let mut value = 0;
+        for i in 0..count {
+            let bit = self.src[self.position / 8] >> (self.position % 8) & 1;
+            value |= bit << i;
+            self.position += 1;
+        }
+        value
+    }
+}
+
+struct Bc7Mode {
+    subsets: usize,
+    partition_bits: usize,
+    rotation_bits: usize,
+    index_selection_bits: usize,
+    color_bits: usize,
+    alpha_bits: usize,
+    endpoint_p_bits: bool,
+    shared_p_bits: bool,
+    index_bits: usize,
+    index_bits_2: usize,
+}
+
+static BC7_MODES: [Bc7Mode; 8] = [
+    Bc7Mode { subsets: 3, partition_bits: 4, rotation_bits: 0, index_selection_bits: 0, color_bits: 4, alpha_bits: 0,
+              endpoint_p_bits: true, shared_p_bits: false, index_bits: 3, index_bits_2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 6, alpha_bits: 0,
+              endpoint_p_bits: false, shared_p_bits: true, index_bits: 3, index_bits_2: 0 },
+    Bc7Mode { subsets: 3, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 0,
+              endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, index_bits_2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 0,
+              endpoint_p_bits: true, shared_p_bits: false, index_bits: 2, index_bits_2: 0 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 1, color_bits: 5, alpha_bits: 6,
+              endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, index_bits_2: 3 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 0, color_bits: 7, alpha_bits: 8,
+              endpoint_p_bits: false, shared_p_bits: false, index_bits: 2, index_bits_2: 2 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 7,
+              endpoint_p_bits: true, shared_p_bits: false, index_bits: 4, index_bits_2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 5,
+              endpoint_p_bits: true, shared_p_bits: false, index_bits: 2, index_bits_2: 0 },
+];
+
+static BC7_WEIGHTS_2: [u16; 4] = [0, 21, 43, 64];
+static BC7_WEIGHTS_3: [u16; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+static BC7_WEIGHTS_4: [u16; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// Texels in subset 1 of each two subset partition, one bit per texel.
+static BC7_PARTITIONS_2: [u16; 64] = [
+    0xcccc, 0x8888, 0xeeee, 0xecc8, 0xc880, 0xfeec, 0xfec8, 0xec80,
+    0xc800, 0xffec, 0xfe80, 0xe800, 0xffe8, 0xff00, 0xfff0, 0xf000,
+    0xf710, 0x008e, 0x7100, 0x08ce, 0x008c, 0x7310, 0x3100, 0x8cce,
+    0x088c, 0x3110, 0x6666, 0x366c, 0x17e8, 0x0ff0, 0x718e, 0x399c,
+    0xaaaa, 0xf0f0, 0x5a5a, 0x33cc, 0x3c3c, 0x55aa, 0x9696, 0xa55a,
+    0x73ce, 0x13c8, 0x324c, 0x3bdc, 0x6996, 0xc33c, 0x9966, 0x0660,
+    0x0272, 0x04e4, 0x4e40, 0x2720, 0xc936, 0x936c, 0x39c6, 0x639c,
+    0x9336, 0x9cc6, 0x817e, 0xe718, 0xccf0, 0x0fcc, 0x7744, 0xee22,
+];
+
+/// The subset of every texel in each three subset partition.
+static BC7_PARTITIONS_3: [[u8; 16]; 64] = [
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 1, 2, 2, 2, 2],
+    [0, 0, 0, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 2, 0, 0, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+    [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 1, 0, 1, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2],
+    [0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 2, 2],
+    [0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1],
+    [0, 0, 1, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+    [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2],
+    [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2],
+    [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2],
+    [0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2],
+    [0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2],
+    [0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2, 1, 2, 2, 2],
+    [0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0, 2, 2, 2, 0],
+    [0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2],
+    [0, 1, 1, 1, 0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0],
+    [0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2],
+    [0, 0, 2, 2, 0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2, 0, 2, 2, 2],
+    [0, 0, 0, 1, 0, 0, 0, 1, 2, 2, 2, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2],
+    [0, 0, 0, 0, 1, 1, 0, 0, 2, 2, 1, 0, 2, 2, 1, 0],
+    [0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1, 0, 0, 0, 0],
+    [0, 0, 1, 2, 0, 0, 1, 2, 1, 1, 2, 2, 2, 2, 2, 2],
+    [0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1, 0, 1, 1, 0],
+    [0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1],
+    [0, 0, 2, 2, 1, 1, 0, 2, 1, 1, 0, 2, 0, 0, 2, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 2, 0, 0, 2, 2, 2, 2, 2],
+    [0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1],
+    [0, 0, 0, 0, 2, 0, 0, 0, 2, 2, 1, 1, 2, 2, 2, 1],
+    [0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 2, 2, 2],
+    [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 2, 0, 0, 1, 1],
+    [0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 2, 2, 0, 2, 2, 2],
+    [0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0],
+    [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0],
+    [0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0],
+    [0, 1, 2, 0, 2, 0, 1, 2, 1, 2, 0, 1, 0, 1, 2, 0],
+    [0, 0, 1, 1, 2, 2, 0, 0, 1, 1, 2, 2, 0, 0, 1, 1],
+    [0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0, 1, 1],
+    [0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1],
+    [0, 0, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2, 1, 1, 2, 2],
+    [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 1, 1],
+    [0, 2, 2, 0, 1, 2, 2, 1, 0, 2, 2, 0, 1, 2, 2, 1],
+    [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 0, 1, 0, 1],
+    [0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1],
+    [0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2],
+    [0, 2, 2, 2, 0, 1, 1, 1, 0, 2, 2, 2, 0, 1, 1, 1],
+    [0, 0, 0, 2, 1, 1, 1, 2, 0, 0, 0, 2, 1, 1, 1, 2],
+    [0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2],
+    [0, 2, 2, 2, 0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2],
+    [0, 0, 0, 2, 1, 1, 1, 2, 1, 1, 1, 2, 0, 0, 0, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2],
+    [0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2],
+    [0, 0, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2],
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2],
+    [0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1],
+    [0, 2, 2, 2, 1, 2, 2, 2, 0, 2, 2, 2, 1, 2, 2, 2],
+    [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2],
+    [0, 1, 1, 1, 2, 0, 1, 1, 2, 2, 0, 1, 2, 2, 2, 0],
+];
+
+/// Anchor texel of the second subset of each two subset partition.
+static BC7_ANCHORS_2: [u8; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+    15, 2, 8, 2, 2, 8, 8, 15, 2, 8, 2, 2, 8, 8, 2, 2,
+    15, 15, 6, 8, 2, 8, 15, 15, 2, 8, 2, 2, 2, 15, 15, 6,
+    6, 2, 6, 8, 15, 15, 2, 2, 15, 15, 15, 15, 15, 2, 2, 15,
+];
+
+/// Anchor texels of the second and third subset of each three subset
+/// partition.
+static BC7_ANCHORS_3: [[u8; 64]; 2] = [
+    [
+        3, 3, 15, 15, 8, 3, 15, 15, 8, 8, 6, 6, 6, 5, 3, 3,
+        3, 3, 8, 15, 3, 3, 6, 10, 5, 8, 8, 6, 8, 5, 15, 15,
+        8, 15, 3, 5, 6, 10, 8, 15, 15, 3, 15, 5, 15, 15, 15, 15,
+        3, 15, 5, 5, 5, 8, 5, 10, 5, 10, 8, 13, 15, 12, 3, 3,
+    ],
+    [
+        15, 8, 8, 3, 15, 15, 3, 8, 15, 15, 15, 15, 15, 15, 15, 8,
+        15, 8, 15, 3, 15, 8, 15, 8, 3, 15, 6, 10, 15, 15, 10, 8,
+        15, 3, 15, 10, 10, 8, 9, 10, 6, 15, 8, 15, 3, 6, 6, 8,
+        15, 3, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 3, 15, 15, 8,
+    ],
+];
+
+fn bc7_weights(bits: usize) -> &'static [u16] {
+    match bits {
+        2 => &BC7_WEIGHTS_2,
+        3 => &BC7_WEIGHTS_3,
+        _ => &BC7_WEIGHTS_4,
+    }
+}
+
+fn bc7_interpolate(e0: u8, e1: u8, weight: u16) -> u8 {
+    (((64 - weight) * e0 as u16 + weight * e1 as u16 + 32) >> 6) as u8
+}
+
+/// Expands a `bits` wide value to 8 bits by replicating its high bits.
+fn bc7_expand(value: u8, bits: usize) -> u8 {
+    if bits >= 8 {
+        return value;
+    }
+    let value = value << (8 - bits);
+    value | value >> bits
+}
+
+fn decode_bc7(src: &[u8], out: &mut Block) {
+    let mode_index = src[0].trailing_zeros() as usize;
+    if mode_index >= BC7_MODES.len() {
+        // Reserved modes decode to transparent black
+        *out = [[0; 4]; 16];
+        return;
+    }
+    let mode = &BC7_MODES[mode_index];
+    let mut bits = BitReader { src, position: mode_index + 1 };
+
+    let partition = bits.read(mode.partition_bits) as usize;
+    let rotation = bits.read(mode.rotation_bits);
+    let index_selection = bits.read(mode.index_selection_bits);
+
+    let endpoint_count = mode.subsets * 2;
+    let mut endpoints = [[0u8; 4]; 6];
+    for channel in 0..3 {
+        for endpoint in endpoints.iter_mut().take(endpoint_count) {
+            endpoint[channel] = bits.read(mode.color_bits);
+        }
+    }
+    for endpoint in endpoints.iter_mut().take(endpoint_count) {
+        endpoint[3] = if mode.alpha_bits > 0 { bits.read(mode.alpha_bits) } else { 255 };
+    }
+
+    let mut color_bits = mode.color_bits;
+    let mut alpha_bits = mode.alpha_bits;
+    if mode.endpoint_p_bits || mode.shared_p_bits {
+        let mut p_bits = [0u8; 6];
+        if mode.endpoint_p_bits {
+            for p_bit in p_bits.iter_mut().take(endpoint_count) {
+                *p_bit = bits.read(1);
+            }
+        } else {
+            for subset in 0..mode.subsets {
+                let p_bit = bits.read(1);
+                p_bits[subset * 2] = p_bit;
+                p_bits[subset * 2 + 1] = p_bit;
+            }
+        }
+        for (endpoint, &p_bit) in endpoints.iter_mut().zip(p_bits.iter()).take(endpoint_count) {
+            for channel in 0..3 {
+                endpoint[channel] = endpoint[channel] << 1 | p_bit;
+            }
+            if mode.alpha_bits > 0 {
+                endpoint[3] = endpoint[3] << 1 | p_bit;
+            }
+        }
+        color_bits += 1;
+        if alpha_bits > 0 {
+            alpha_bits += 1;
+        }
+    }
+    for endpoint in endpoints.iter_mut().take(endpoint_count) {
+        for channel in 0..3 {
+            endpoint[channel] = bc7_expand(endpoint[channel], color_bits);
+        }
+        if alpha_bits > 0 {
+            endpoint[3] = bc7_expand(endpoint[3], alpha_bits);
+        }
+    }
+
+    let subset_of = |texel: usize| -> usize {
+        match mode.subsets {
+            1 => 0,
+            2 => (BC7_PARTITIONS_2[partition] >> texel & 1) as usize,
+            _ => BC7_PARTITIONS_3[partition][texel] as usize,
+        }
+    };
+    let is_anchor = |texel: usize| -> bool {
+        texel == 0 || match mode.subsets {
+            1 => false,
+            2 => texel == BC7_ANCHORS_2[partition] as usize,
+            _ => texel == BC7_ANCHORS_3[0][partition] as usize ||
+                texel == BC7_ANCHORS_3[1][partition] as usize,
+        }
+    };
+
+    // Anchor texels drop the most significant index bit, which is zero
+    let mut indices = [0u8; 16];
+    for (texel, index) in indices.iter_mut().enumerate() {
+        let count = if is_anchor(texel) { mode.index_bits - 1 } else { mode.index_bits };
+        *index = bits.read(count);
+    }
+    let mut indices_2 = [0u8; 16];
+    if mode.index_bits_2 > 0 {
+        for (texel, index) in indices_2.iter_mut().enumerate() {
+            let count = if texel == 0 { mode.index_bits_2 - 1 } else { mode.index_bits_2 };
+            *index = bits.read(count);
+        }
+    }
+
+    for (texel, out) in out.iter_mut().enumerate() {
+        let subset = subset_of(texel);
+        let e0 = endpoints[subset * 2];
+        let e1 = endpoints[subset * 2 + 1];
+
+        let (color_weight, alpha_weight) = if mode.index_bits_2 == 0 {
+            let weight = bc7_weights(mode.index_bits)[indices[texel] as usize];
+            (weight, weight)
+        } else if index_selection == 0 {
+            (bc7_weights(mode.index_bits)[indices[texel] as usize],
+             bc7_weights(mode.index_bits_2)[indices_2[texel] as usize])
+        } else {
+            (bc7_weights(mode.index_bits_2)[indices_2[texel] as usize],
+             bc7_weights(mode.index_bits)[indices[texel] as usize])
+        };
+
+        let mut color = [0u8; 4];
+        for channel in 0..3 {
+            color[channel] = bc7_interpolate(e0[channel], e1[channel], color_weight);
+        }
+        color[3] = bc7_interpolate(e0[3], e1[3], alpha_weight);
+
+        match rotation {
+            1 => color.swap(0, 3),
+            2 => color.swap(1, 3),
+            3 => color.swap(2, 3),
+            _ => {},
+        }
+        *out = color;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packs `(value, bit count)` fields into a 128 bit block, least
+    /// significant bit first.
+    fn pack_bits(fields: &[(u32, usize)]) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        let mut position = 0;
+        for &(value, count) in fields {
+            for i in 0..count {
+                block[position / 8] |= ((value >> i & 1) as u8) << (position % 8);
+                position += 1;
+            }
+        }
+        assert_eq!(position, 128);
+        block
+    }
+
+    #[test]
+    fn bc1_solid_color() {
+        // Both endpoints pure red, every index 0
+        let block = [0x00, 0xf8, 0x00, 0xf8, 0, 0, 0, 0];
+        let pixels = decompress(BlockFormat::Bc1, 4, 4, &block).unwrap();
+        assert_eq!(pixels.len(), 64);
+        for texel in pixels.chunks(4) {
+            assert_eq!(texel, [255, 0, 0, 255]);
+        }
+    }
+
+    #[test]
+    fn bc1_transparent_texels() {
+        // color0 <= color1 selects the three color mode, index 3 is
+        // transparent black
+        let block = [0x00, 0x00, 0x00, 0xf8, 0xff, 0xff, 0xff, 0xff];
+        let pixels = decompress(BlockFormat::Bc1, 4, 4, &block).unwrap();
+        for texel in pixels.chunks(4) {
+            assert_eq!(texel, [0, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn bc7_mode_6() {
+        // Endpoint 0 black with p-bit 0, endpoint 1 white with p-bit 1, and
+        // texel i using index i
+        let mut fields = vec![(1 << 6, 7)];
+        for _ in 0..4 {
+            fields.push((0, 7));
+            fields.push((0x7f, 7));
+        }
+        fields.push((0, 1));
+        fields.push((1, 1));
+        fields.push((0, 3));
+        for i in 1..16 {
+            fields.push((i, 4));
+        }
+        let block = pack_bits(&fields);
+
+        let pixels = decompress(BlockFormat::Bc7, 4, 4, &block).unwrap();
+        for (i, texel) in pixels.chunks(4).enumerate() {
+            let value = bc7_interpolate(0, 255, BC7_WEIGHTS_4[i]);
+            assert_eq!(texel, [value; 4], "texel {}", i);
+        }
+        assert_eq!(&pixels[..4], [0; 4]);
+        assert_eq!(&pixels[32..36], [135; 4]);
+        assert_eq!(&pixels[60..], [255; 4]);
+    }
+
+    #[test]
+    fn partial_blocks_are_cropped() {
+        let block = [0x00, 0xf8, 0x00, 0xf8, 0, 0, 0, 0];
+        let pixels = decompress(BlockFormat::Bc1, 2, 3, &block).unwrap();
+        assert_eq!(pixels.len(), 2 * 3 * 4);
+    }
+
+    #[test]
+    fn truncated_data() {
+        // An 8x8 image takes four blocks
+        assert!(decompress(BlockFormat::Bc1, 8, 8, &[0; 24]).is_none());
+        assert!(decompress(BlockFormat::Bc7, 4, 4, &[0x40; 15]).is_none());
+        assert!(decompress(BlockFormat::Bc1, 8, 8, &[0; 32]).is_some());
+    }
+}
@@ -0,0 +1,403 @@
+/*
+ * culling.rs
+ * Frustum culling on the GPU: a compute shader tests the draws
+ * of a draw list and writes their indirect draw commands.
+ */
+use std::mem;
+
+use cgmath::{Matrix4, Vector4};
+use voodoo::{Result as VdResult, Device, Buffer, CommandBuffer, ComputePipeline, PipelineCache,
+             PipelineLayout, DescriptorSetLayout, DescriptorPool, DescriptorSet, DescriptorType,
+             ShaderStageFlags, BufferUsageFlags, MemoryPropertyFlags, PipelineBindPoint,
+             PipelineStageFlags, AccessFlags, DependencyFlags, BufferMemoryBarrier};
+
+use vulkan::{self, Vertex, DRAW_INDEXED_INDIRECT_COMMAND_SIZE};
+use memory::{Allocator, Allocation};
+use reflect::{self, PipelineInterface};
+use scene::{DrawList, Scene};
+use error::{Result, ResultExt};
+
+/// Invocations per work group, `local_size_x` in cull.comp.
+const WORK_GROUP_SIZE: u32 = 64;
+
+/// A sphere enclosing a mesh or object, in its own coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: [f32; 3],
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    /// Encloses the positions of `vertices`, centered on their bounding box.
+    /// Not the smallest sphere, but close for most meshes.
+    pub fn from_vertices(vertices: &[Vertex]) -> BoundingSphere {
+        if vertices.is_empty() {
+            return BoundingSphere { center: [0.0; 3], radius: 0.0 };
+        }
+        let mut min = vertices[0].pos;
+        let mut max = vertices[0].pos;
+        for vertex in vertices {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(vertex.pos[axis]);
+                max[axis] = max[axis].max(vertex.pos[axis]);
+            }
+        }
+        let center = [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0, (min[2] + max[2]) / 2.0];
+        let radius = vertices.iter().map(|vertex| {
+            let d = [vertex.pos[0] - center[0], vertex.pos[1] - center[1],
+                     vertex.pos[2] - center[2]];
+            (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt()
+        }).fold(0.0f32, f32::max);
+        BoundingSphere { center, radius }
+    }
+}
+
+/// A draw as the culling shader reads it, placed by the draw data at the
+/// same index. Must match `CullObject` in cull.comp.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CullObject {
+    /// Center in xyz and radius in w, in object space.
+    pub sphere: [f32; 4],
+    pub index_count: u32,
+    pub first_index: u32,
+    pub first_instance: u32,
+    pub instance_count: u32,
+}
+
+/// Must match the `Cull` push constant block in cull.comp.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CullPushConstants {
+    /// Left, right, bottom, top, near and far, as `(normal, distance)` with
+    /// the normal pointing inwards.
+    pub planes: [[f32; 4]; 6],
+    pub object_count: u32,
+}
+
+/// The planes of the frustum `view_proj` projects into clip space, for
+/// Vulkan's depth range of 0 to 1.
+pub fn frustum_planes(view_proj: &Matrix4<f32>) -> [[f32; 4]; 6] {
+    let row = |i: usize| {
+        Vector4::new(view_proj.x[i], view_proj.y[i], view_proj.z[i], view_proj.w[i])
+    };
+    let planes = [
+        row(3) + row(0),
+        row(3) - row(0),
+        row(3) + row(1),
+        row(3) - row(1),
+        row(2),
+        row(3) - row(2),
+    ];
+    let mut normalized = [[0.0; 4]; 6];
+    for (plane, out) in planes.iter().zip(normalized.iter_mut()) {
+        let length = (plane.x * plane.x + plane.y * plane.y + plane.z * plane.z).sqrt();
+        *out = (plane / length).into();
+    }
+    normalized
+}
+
+/// The culling shader's view of each draw of `draw_list`, in order.
+pub fn cull_objects(draw_list: &DrawList, scene: &Scene) -> Vec<CullObject> {
+    draw_list.draws.iter().map(|draw| {
+        let sub_mesh = &scene.mesh(draw.mesh).mesh.sub_meshes()[draw.sub_mesh];
+        CullObject {
+            sphere: [draw.bounds.center[0], draw.bounds.center[1], draw.bounds.center[2],
+                     draw.bounds.radius],
+            index_count: sub_mesh.index_count,
+            first_index: sub_mesh.first_index,
+            first_instance: draw.first_instance,
+            instance_count: draw.instance_count,
+        }
+    }).collect()
+}
+
+/// Checks the bindings and push constants `Culler` provides against the
+/// shader.
+fn check_shader_interface(interface: &PipelineInterface) -> Result<()> {
+    for binding in 0..3 {
+        match interface.binding(0, binding) {
+            Some(b) if b.descriptor_type == DescriptorType::StorageBuffer && b.count == 1 => {},
+            _ => return Err(format!("culling shader doesn't use a storage buffer at binding {}",
+                binding).into()),
+        }
+    }
+    if interface.bindings.len() != 3 {
+        return Err("culling shader uses descriptors besides its three buffers".into());
+    }
+    let push_size = mem::size_of::<CullPushConstants>() as u32;
+    let has_push_constants = interface.push_constants.iter().any(|&(_, (offset, size))| {
+        offset == 0 && size >= push_size
+    });
+    if !has_push_constants {
+        return Err("culling shader doesn't take the frustum as push constants".into());
+    }
+    Ok(())
+}
+
+/// The buffers one frame in flight culls with.
+pub struct CullBuffers {
+    /// Written by the host whenever the draw list changes.
+    objects: Option<(Buffer, Allocation)>,
+    /// Written by the culling shader, read by the indirect draws.
+    commands: Option<(Buffer, Allocation)>,
+    capacity: usize,
+    descriptor_set: DescriptorSet,
+    object_count: usize,
+}
+
+impl CullBuffers {
+    /// The indirect draw commands, one per draw of the last written list.
+    pub fn commands(&self) -> Option<&Buffer> {
+        self.commands.as_ref().map(|&(ref buffer, _)| buffer)
+    }
+}
+
+/// The compute pipeline culling draws against the view frustum.
+pub struct Culler {
+    device: Device,
+    pipeline: ComputePipeline,
+    pipeline_layout: PipelineLayout,
+    descriptor_set_layout: DescriptorSetLayout,
+    descriptor_pool: DescriptorPool,
+}
+
+impl Culler {
+    /// Creates the pipeline from the SPIR-V `shader_code`, with descriptor
+    /// sets for `frame_count` frames in flight.
+    pub fn new(device: &Device,
+               pipeline_cache: &PipelineCache,
+               shader_code: &[u32],
+               frame_count: usize) -> Result<Culler> {
+        let reflection = reflect::reflect(shader_code).context("culling shader")?;
+        let interface = PipelineInterface::new(&[&reflection])?;
+        check_shader_interface(&interface)?;
+        let set_bindings = interface.set_bindings(0);
+
+        let descriptor_pool = vulkan::create_descriptor_pool(device.clone(),
+                                                             frame_count as u32,
+                                                             &set_bindings)
+            .context("creating culling descriptor pool")?;
+        let descriptor_set_layout = vulkan::create_descriptor_set_layout(device.clone(),
+                                                                         &set_bindings)
+            .context("creating culling descriptor set layout")?;
+        let pipeline_layout = vulkan::create_pipeline_layout(device.clone(),
+                                                             Some(&descriptor_set_layout),
+                                                             &interface.push_constant_ranges())
+            .context("creating culling pipeline layout")?;
+        let pipeline = vulkan::create_compute_pipeline(device.clone(),
+                                                       Some(pipeline_cache),
+                                                       &pipeline_layout,
+                                                       shader_code)
+            .context("creating culling pipeline")?;
+
+        Ok(Culler {
+            device: device.clone(),
+            pipeline,
+            pipeline_layout,
+            descriptor_set_layout,
+            descriptor_pool,
+        })
+    }
+
+    /// Creates the buffers of a frame in flight. They are allocated when
+    /// first written, the draw data has to be bound before recording.
+    pub fn create_buffers(&self) -> VdResult<CullBuffers> {
+        let descriptor_sets = self.descriptor_pool
+            .allocate_descriptor_sets(&[self.descriptor_set_layout.handle()])?;
+        Ok(CullBuffers {
+            objects: None,
+            commands: None,
+            capacity: 0,
+            descriptor_set: descriptor_sets[0].clone(),
+            object_count: 0,
+        })
+    }
+
+    /// Writes `objects` to `buffers`, growing them if needed. The frame
+    /// using `buffers` must have finished on the GPU.
+    pub fn write(&self,
+                 allocator: &Allocator,
+                 buffers: &mut CullBuffers,
+                 objects: &[CullObject]) -> VdResult<()> {
+        buffers.object_count = objects.len();
+        if objects.is_empty() {
+            return Ok(());
+        }
+        if objects.len() > buffers.capacity {
+            let capacity = objects.len().next_power_of_two();
+            let objects_size = (capacity * mem::size_of::<CullObject>()) as u64;
+            let commands_size = capacity as u64 * DRAW_INDEXED_INDIRECT_COMMAND_SIZE as u64;
+            let object_buffer = vulkan::create_buffer(&self.device,
+                allocator,
+                objects_size,
+                BufferUsageFlags::STORAGE_BUFFER,
+                MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT)?;
+            let command_buffer = vulkan::create_buffer(&self.device,
+                allocator,
+                commands_size,
+                BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::INDIRECT_BUFFER,
+                MemoryPropertyFlags::DEVICE_LOCAL)?;
+
+            vulkan::update_storage_buffer_descriptor(&self.descriptor_pool,
+                &buffers.descriptor_set, 0, &object_buffer.0, objects_size);
+            vulkan::update_storage_buffer_descriptor(&self.descriptor_pool,
+                &buffers.descriptor_set, 1, &command_buffer.0, commands_size);
+            buffers.objects = Some(object_buffer);
+            buffers.commands = Some(command_buffer);
+            buffers.capacity = capacity;
+        }
+
+        let memory = &buffers.objects.as_ref().unwrap().1;
+        let mut data = unsafe {
+            memory.map((objects.len() * mem::size_of::<CullObject>()) as u64)?
+        };
+        data.copy_from_slice(objects);
+        memory.unmap(data);
+        Ok(())
+    }
+
+    /// Points the culling shader of `buffers` at the first `size` bytes of
+    /// `draw_data`, the draw data of the objects written. The frame using
+    /// `buffers` must have finished on the GPU.
+    pub fn bind_draw_data(&self, buffers: &CullBuffers, draw_data: &Buffer, size: u64) {
+        vulkan::update_storage_buffer_descriptor(&self.descriptor_pool,
+            &buffers.descriptor_set, 2, draw_data, size);
+    }
+
+    /// Records culling the objects last written to `buffers` against
+    /// `planes`, followed by a barrier making the commands visible to
+    /// indirect draws. Has to be called outside of a render pass.
+    pub fn record(&self,
+                  cmd_buf: &CommandBuffer,
+                  buffers: &CullBuffers,
+                  planes: [[f32; 4]; 6]) {
+        let commands = match buffers.commands {
+            Some((ref commands, _)) if buffers.object_count > 0 => commands,
+            _ => return,
+        };
+
+        cmd_buf.bind_pipeline(PipelineBindPoint::Compute, &self.pipeline);
+        cmd_buf.bind_descriptor_sets(PipelineBindPoint::Compute, &self.pipeline_layout,
+            0, &[&buffers.descriptor_set], &[]);
+        vulkan::push_constants(cmd_buf, &self.pipeline_layout, ShaderStageFlags::COMPUTE, 0,
+            &CullPushConstants { planes, object_count: buffers.object_count as u32 });
+        let group_count = (buffers.object_count as u32 + WORK_GROUP_SIZE - 1) / WORK_GROUP_SIZE;
+        cmd_buf.dispatch(group_count, 1, 1);
+
+        let barrier = BufferMemoryBarrier::builder()
+            .src_access_mask(AccessFlags::SHADER_WRITE)
+            .dst_access_mask(AccessFlags::INDIRECT_COMMAND_READ)
+            .src_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
+            .buffer(commands)
+            .offset(0)
+            .size(buffers.object_count as u64 * DRAW_INDEXED_INDIRECT_COMMAND_SIZE as u64)
+            .build();
+        cmd_buf.pipeline_barrier(PipelineStageFlags::COMPUTE_SHADER,
+            PipelineStageFlags::DRAW_INDIRECT, DependencyFlags::empty(), &[], &[barrier], &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_planes_eq(actual: &[[f32; 4]; 6], expected: &[[f32; 4]; 6]) {
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            for i in 0..4 {
+                assert!((a[i] - e[i]).abs() < 1e-5, "planes {:?}, expected {:?}", actual, expected);
+            }
+        }
+    }
+
+    /// Whether `point` is on the inner side of every plane, as cull.comp
+    /// tests a sphere of radius 0.
+    fn inside(planes: &[[f32; 4]; 6], point: [f32; 3]) -> bool {
+        planes.iter().all(|p| p[0] * point[0] + p[1] * point[1] + p[2] * point[2] + p[3] >= 0.0)
+    }
+
+    /// A 90 degree, square perspective looking down -z, with the near plane
+    /// at 1 and the far plane at 10 mapped to depths 0 and 1.
+    fn perspective() -> Matrix4<f32> {
+        let a = 10.0 / -9.0;
+        Matrix4::new(1.0, 0.0, 0.0, 0.0,
+                     0.0, 1.0, 0.0, 0.0,
+                     0.0, 0.0, a, -1.0,
+                     0.0, 0.0, a, 0.0)
+    }
+
+    /// An orthographic projection of the box from (-2, -3, -1) to (2, 3, -5),
+    /// looking down -z.
+    fn ortho() -> Matrix4<f32> {
+        Matrix4::new(0.5, 0.0, 0.0, 0.0,
+                     0.0, 1.0 / 3.0, 0.0, 0.0,
+                     0.0, 0.0, -0.25, 0.0,
+                     0.0, 0.0, -0.25, 1.0)
+    }
+
+    #[test]
+    fn perspective_planes() {
+        let s = 0.5f32.sqrt();
+        assert_planes_eq(&frustum_planes(&perspective()), &[
+            [s, 0.0, -s, 0.0],
+            [-s, 0.0, -s, 0.0],
+            [0.0, s, -s, 0.0],
+            [0.0, -s, -s, 0.0],
+            [0.0, 0.0, -1.0, -1.0],
+            [0.0, 0.0, 1.0, 10.0],
+        ]);
+    }
+
+    #[test]
+    fn ortho_planes() {
+        assert_planes_eq(&frustum_planes(&ortho()), &[
+            [1.0, 0.0, 0.0, 2.0],
+            [-1.0, 0.0, 0.0, 2.0],
+            [0.0, 1.0, 0.0, 3.0],
+            [0.0, -1.0, 0.0, 3.0],
+            [0.0, 0.0, -1.0, -1.0],
+            [0.0, 0.0, 1.0, 5.0],
+        ]);
+    }
+
+    #[test]
+    fn classifies_points() {
+        let planes = frustum_planes(&perspective());
+        assert!(inside(&planes, [0.0, 0.0, -5.0]));
+        assert!(inside(&planes, [4.0, -4.0, -5.0]));
+        assert!(!inside(&planes, [6.0, 0.0, -5.0]));
+        assert!(!inside(&planes, [0.0, -6.0, -5.0]));
+        assert!(!inside(&planes, [0.0, 0.0, -0.5]));
+        assert!(!inside(&planes, [0.0, 0.0, -11.0]));
+        assert!(!inside(&planes, [0.0, 0.0, 5.0]));
+
+        let planes = frustum_planes(&ortho());
+        assert!(inside(&planes, [1.5, 2.5, -3.0]));
+        assert!(!inside(&planes, [2.5, 0.0, -3.0]));
+        assert!(!inside(&planes, [0.0, -3.5, -3.0]));
+        assert!(!inside(&planes, [0.0, 0.0, 0.0]));
+        assert!(!inside(&planes, [0.0, 0.0, -6.0]));
+    }
+
+    #[test]
+    fn unit_cube_sphere() {
+        let mut vertices = Vec::new();
+        for &x in &[0.0, 1.0] {
+            for &y in &[0.0, 1.0] {
+                for &z in &[0.0, 1.0] {
+                    vertices.push(Vertex { pos: [x, y, z], color: [1.0; 3], tex_coord: [0.0; 2] });
+                }
+            }
+        }
+        let sphere = BoundingSphere::from_vertices(&vertices);
+        assert_eq!(sphere.center, [0.5, 0.5, 0.5]);
+        assert!((sphere.radius - 0.75f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn empty_sphere() {
+        assert_eq!(BoundingSphere::from_vertices(&[]),
+                   BoundingSphere { center: [0.0; 3], radius: 0.0 });
+    }
+}
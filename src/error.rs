@@ -0,0 +1,106 @@
+/*
+ * error.rs
+ * The error type shared by the whole crate.
+ */
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::result;
+
+use voodoo;
+use image::ImageError;
+use tobj::LoadError;
+use notify;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Vulkan(voodoo::Error),
+    Image(ImageError),
+    Model(LoadError),
+    Io(io::Error),
+    Watch(notify::Error),
+    Message(String),
+    /// `source` occurred while performing `step`.
+    Context {
+        step: String,
+        source: Box<Error>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Vulkan(ref err) => write!(f, "vulkan error: {}", err),
+            Error::Image(ref err) => write!(f, "image error: {}", err),
+            Error::Model(ref err) => write!(f, "model error: {}", err),
+            Error::Io(ref err) => write!(f, "io error: {}", err),
+            Error::Watch(ref err) => write!(f, "watch error: {}", err),
+            Error::Message(ref msg) => write!(f, "{}", msg),
+            Error::Context { ref step, ref source } => write!(f, "{}: {}", step, source),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        "rust_game error"
+    }
+}
+
+impl From<voodoo::Error> for Error {
+    fn from(err: voodoo::Error) -> Error {
+        Error::Vulkan(err)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(err: ImageError) -> Error {
+        Error::Image(err)
+    }
+}
+
+impl From<LoadError> for Error {
+    fn from(err: LoadError) -> Error {
+        Error::Model(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(err: notify::Error) -> Error {
+        Error::Watch(err)
+    }
+}
+
+impl<'s> From<&'s str> for Error {
+    fn from(msg: &'s str) -> Error {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Error {
+        Error::Message(msg)
+    }
+}
+
+/// Adds a description of the failed step to errors.
+pub trait ResultExt<T> {
+    fn context<S: Into<String>>(self, step: S) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for result::Result<T, E> {
+    fn context<S: Into<String>>(self, step: S) -> Result<T> {
+        self.map_err(|err| Error::Context {
+            step: step.into(),
+            source: Box::new(err.into()),
+        })
+    }
+}
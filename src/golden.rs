@@ -0,0 +1,256 @@
+/*
+ * golden.rs
+ * Golden-image regression checks for rendered frames.
+ * Renders a fixed scene offscreen and compares the frames
+ * against reference PNGs.
+ */
+use app::App;
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use error::{Result, ResultExt};
+
+use image::{self, RgbaImage, Rgba};
+use voodoo::Extent2d;
+
+pub static GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"),
+    "/tests/golden");
+
+/// Size of the frames rendered for comparison.
+pub const GOLDEN_EXTENT: (u32, u32) = (256, 256);
+
+/// The scene is rendered at these offsets (in seconds) from the start time,
+/// each compared against `<name>.png`.
+pub static GOLDEN_FRAMES: &[(&str, f32)] = &[
+    ("quads_t0", 0.0),
+    ("quads_t1", 1.0),
+    ("quads_t2_5", 2.5),
+];
+
+pub struct Config {
+    /// Directory holding the reference images.
+    pub reference_dir: PathBuf,
+    /// Directory receiving actual and diff images of failed comparisons.
+    pub output_dir: PathBuf,
+    /// Largest per-channel difference still counted as a matching pixel.
+    pub tolerance: u8,
+    /// Overwrite the reference images with the rendered frames.
+    pub bless: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            reference_dir: PathBuf::from(GOLDEN_DIR),
+            output_dir: PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"),
+                "/target/golden")),
+            tolerance: 2,
+            bless: false,
+        }
+    }
+}
+
+/// The result of comparing a frame against its reference image.
+pub struct Comparison {
+    pub mismatched_pixels: u32,
+    pub max_difference: u8,
+    /// Mismatching pixels in red over a dimmed copy of the reference.
+    pub diff: RgbaImage,
+}
+
+impl Comparison {
+    pub fn matches(&self) -> bool {
+        self.mismatched_pixels == 0
+    }
+}
+
+/// Compares two images of equal size pixel by pixel.
+pub fn compare(actual: &RgbaImage,
+               reference: &RgbaImage,
+               tolerance: u8) -> Comparison {
+    assert_eq!(actual.dimensions(), reference.dimensions());
+
+    let mut mismatched_pixels = 0;
+    let mut max_difference = 0;
+
+    let diff = RgbaImage::from_fn(actual.width(), actual.height(), |x, y| {
+        let a = actual.get_pixel(x, y).data;
+        let r = reference.get_pixel(x, y).data;
+
+        let difference = a.iter().zip(r.iter())
+            .map(|(&a, &r)| (a as i16 - r as i16).abs() as u8)
+            .max()
+            .unwrap();
+
+        if difference > max_difference {
+            max_difference = difference;
+        }
+        if difference > tolerance {
+            mismatched_pixels += 1;
+            Rgba { data: [255, 0, 0, 255] }
+        } else {
+            Rgba { data: [r[0] / 4, r[1] / 4, r[2] / 4, 255] }
+        }
+    });
+
+    Comparison {
+        mismatched_pixels,
+        max_difference,
+        diff,
+    }
+}
+
+/// Checks `actual` against the reference image `name`.
+///
+/// Returns a description of the mismatch on failure and writes the actual
+/// frame and a diff image into the output directory.
+pub fn check(config: &Config,
+             name: &str,
+             actual: &RgbaImage) -> io::Result<Option<String>> {
+    let reference_path = config.reference_dir.join(format!("{}.png", name));
+
+    if config.bless {
+        save(actual, &reference_path)?;
+        println!("blessed {}", reference_path.display());
+        return Ok(None);
+    }
+
+    let reference = match image::open(&reference_path) {
+        Ok(reference) => reference.to_rgba(),
+        Err(err) => return Ok(Some(format!("{}: unable to load reference image {} ({}), \
+            run with --bless to create it", name, reference_path.display(), err))),
+    };
+
+    if reference.dimensions() != actual.dimensions() {
+        save(actual, &config.output_dir.join(format!("{}.actual.png", name)))?;
+        return Ok(Some(format!("{}: size {:?} differs from reference size {:?}",
+            name, actual.dimensions(), reference.dimensions())));
+    }
+
+    let comparison = compare(actual, &reference, config.tolerance);
+    if comparison.matches() {
+        return Ok(None);
+    }
+
+    let diff_path = config.output_dir.join(format!("{}.diff.png", name));
+    save(actual, &config.output_dir.join(format!("{}.actual.png", name)))?;
+    save(&comparison.diff, &diff_path)?;
+
+    Ok(Some(format!("{}: {} pixels differ by more than {} (max difference {}), diff written to {}",
+        name, comparison.mismatched_pixels, config.tolerance, comparison.max_difference,
+        diff_path.display())))
+}
+
+fn save(image: &RgbaImage, path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        ::std::fs::create_dir_all(parent)?;
+    }
+    image.save(path)
+}
+
+/// Renders every frame in `GOLDEN_FRAMES` offscreen and checks it.
+/// Returns the descriptions of all failed checks.
+pub fn run(config: &Config) -> Result<Vec<String>> {
+    let extent = Extent2d::builder()
+        .width(GOLDEN_EXTENT.0)
+        .height(GOLDEN_EXTENT.1)
+        .build();
+    let mut app = App::headless(extent)?;
+
+    let mut failures = Vec::new();
+    for &(name, time) in GOLDEN_FRAMES {
+        let frame = app.render_offscreen(time)
+            .context(format!("rendering {}", name))?;
+        if let Some(failure) = check(config, name, &frame)
+                .context(format!("checking {}", name))? {
+            failures.push(failure);
+        }
+    }
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    fn solid(width: u32, height: u32, value: u8) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, Rgba { data: [value, value, value, 255] })
+    }
+
+    /// A config reading and writing in an empty directory of its own.
+    fn config(name: &str) -> Config {
+        let dir = env::temp_dir().join(format!("rust_game_golden_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        Config {
+            reference_dir: dir.join("reference"),
+            output_dir: dir.join("output"),
+            tolerance: 2,
+            bless: false,
+        }
+    }
+
+    #[test]
+    fn identical_images_match() {
+        let comparison = compare(&solid(4, 4, 100), &solid(4, 4, 100), 0);
+        assert!(comparison.matches());
+        assert_eq!(comparison.max_difference, 0);
+    }
+
+    #[test]
+    fn differences_within_tolerance_match() {
+        let comparison = compare(&solid(4, 4, 102), &solid(4, 4, 100), 2);
+        assert!(comparison.matches());
+        assert_eq!(comparison.max_difference, 2);
+        assert_eq!(comparison.diff.get_pixel(0, 0).data, [25, 25, 25, 255]);
+    }
+
+    #[test]
+    fn differences_beyond_tolerance_mismatch() {
+        let mut actual = solid(4, 4, 100);
+        actual.put_pixel(1, 2, Rgba { data: [100, 104, 100, 255] });
+        let comparison = compare(&actual, &solid(4, 4, 100), 3);
+        assert!(!comparison.matches());
+        assert_eq!(comparison.mismatched_pixels, 1);
+        assert_eq!(comparison.max_difference, 4);
+        assert_eq!(comparison.diff.get_pixel(1, 2).data, [255, 0, 0, 255]);
+        assert_eq!(comparison.diff.get_pixel(0, 0).data, [25, 25, 25, 255]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn compared_images_must_have_equal_sizes() {
+        compare(&solid(4, 4, 0), &solid(4, 2, 0), 0);
+    }
+
+    #[test]
+    fn missing_references_fail() {
+        let config = config("missing");
+        assert!(check(&config, "frame", &solid(4, 4, 0)).unwrap().is_some());
+    }
+
+    #[test]
+    fn blessed_frames_match() {
+        let mut config = config("blessed");
+        config.bless = true;
+        assert!(check(&config, "frame", &solid(4, 4, 50)).unwrap().is_none());
+        config.bless = false;
+        assert!(check(&config, "frame", &solid(4, 4, 51)).unwrap().is_none());
+        assert!(check(&config, "frame", &solid(4, 4, 60)).unwrap().is_some());
+        assert!(config.output_dir.join("frame.diff.png").exists());
+    }
+
+    #[test]
+    fn size_mismatches_fail() {
+        let mut config = config("size");
+        config.bless = true;
+        check(&config, "frame", &solid(4, 4, 0)).unwrap();
+        config.bless = false;
+        let failure = check(&config, "frame", &solid(8, 4, 0)).unwrap().unwrap();
+        assert!(failure.contains("size"));
+        assert!(config.output_dir.join("frame.actual.png").exists());
+    }
+}
@@ -2,13 +2,28 @@
  * input.rs
  */
 use voodoo_winit::winit::{
-    ControlFlow, ElementState, Event, KeyboardInput, ModifiersState, VirtualKeyCode, WindowEvent,
+    ElementState, Event, KeyboardInput, ModifiersState, VirtualKeyCode, WindowEvent,
 };
 
-pub fn update(event: Event) -> ControlFlow {
+/// What the application should do in response to an event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Continue,
+    Exit,
+    CaptureFrame,
+    CyclePresentPolicy,
+    CyclePipeline,
+    ToggleSplitScreen,
+    AddObject,
+    RemoveObject,
+    /// The window was resized to the contained width and height.
+    Resize(u32, u32),
+}
+
+pub fn update(event: Event) -> Action {
     match event {
         Event::WindowEvent { event, .. } => handle_window_event(event),
-        _ => ControlFlow::Continue,
+        _ => Action::Continue,
     }
 }
 
@@ -18,24 +33,54 @@ struct Key {
     pub modifiers: ModifiersState,
 }
 
-fn handle_key(key: Key) -> ControlFlow {
+fn handle_key(key: Key) -> Action {
     println!("{:?} {:?}", key.state, key.id);
     match key {
         Key {
             id: VirtualKeyCode::Escape,
             state: ElementState::Pressed,
             ..
-        } => ControlFlow::Break,
-        _ => ControlFlow::Continue,
+        } => Action::Exit,
+        Key {
+            id: VirtualKeyCode::F12,
+            state: ElementState::Pressed,
+            ..
+        } => Action::CaptureFrame,
+        Key {
+            id: VirtualKeyCode::V,
+            state: ElementState::Pressed,
+            ..
+        } => Action::CyclePresentPolicy,
+        Key {
+            id: VirtualKeyCode::F,
+            state: ElementState::Pressed,
+            ..
+        } => Action::CyclePipeline,
+        Key {
+            id: VirtualKeyCode::S,
+            state: ElementState::Pressed,
+            ..
+        } => Action::ToggleSplitScreen,
+        Key {
+            id: VirtualKeyCode::A,
+            state: ElementState::Pressed,
+            ..
+        } => Action::AddObject,
+        Key {
+            id: VirtualKeyCode::R,
+            state: ElementState::Pressed,
+            ..
+        } => Action::RemoveObject,
+        _ => Action::Continue,
     }
 }
 
-fn handle_keyboard_input(input: KeyboardInput) -> ControlFlow {
+fn handle_keyboard_input(input: KeyboardInput) -> Action {
     match input {
         KeyboardInput {
             virtual_keycode: None,
             ..
-        } => ControlFlow::Continue,
+        } => Action::Continue,
         KeyboardInput {
             virtual_keycode: Some(keycode),
             state,
@@ -49,10 +94,11 @@ fn handle_keyboard_input(input: KeyboardInput) -> ControlFlow {
     }
 }
 
-fn handle_window_event(event: WindowEvent) -> ControlFlow {
+fn handle_window_event(event: WindowEvent) -> Action {
     match event {
-        WindowEvent::Closed => ControlFlow::Break,
+        WindowEvent::Closed => Action::Exit,
+        WindowEvent::Resized(width, height) => Action::Resize(width, height),
         WindowEvent::KeyboardInput { input, .. } => handle_keyboard_input(input),
-        _ => ControlFlow::Continue,
+        _ => Action::Continue,
     }
 }
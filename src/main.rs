@@ -9,6 +9,9 @@ extern crate smallvec;
 extern crate ordered_float;
 extern crate cgmath;
 extern crate tobj;
+extern crate glsl_to_spirv;
+extern crate notify;
+extern crate dirs;
 
 #[macro_use]
 extern crate lazy_static;
@@ -20,8 +23,90 @@ mod app;
 mod input;
 mod vulkan;
 mod presenter;
+mod mesh;
+mod memory;
+mod upload;
+mod texture;
+mod texture_file;
+mod bcn;
+mod shader;
+mod reflect;
+mod pipeline;
+mod pipeline_cache;
+mod render_graph;
+mod scene;
+mod culling;
+mod golden;
+mod error;
+
+use std::env;
+use std::process;
+
+fn usage() -> ! {
+    eprintln!("usage: rust_game [MESH.obj...]\n       \
+        rust_game --golden [--bless] [--tolerance N] [--reference-dir DIR]");
+    process::exit(2);
+}
+
+/// Runs the golden-image regression checks and exits with their result.
+fn run_golden<I: Iterator<Item = String>>(mut args: I) -> ! {
+    let mut config = golden::Config::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bless" => config.bless = true,
+            "--tolerance" => {
+                config.tolerance = args.next()
+                    .and_then(|t| t.parse().ok())
+                    .unwrap_or_else(|| usage());
+            },
+            "--reference-dir" => {
+                config.reference_dir = args.next()
+                    .unwrap_or_else(|| usage())
+                    .into();
+            },
+            _ => usage(),
+        }
+    }
+
+    let failures = match golden::run(&config) {
+        Ok(failures) => failures,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    };
+    for failure in &failures {
+        eprintln!("FAILED {}", failure);
+    }
+    if failures.is_empty() {
+        let outcome = if config.bless { "blessed" } else { "matched" };
+        println!("{} golden images {}", golden::GOLDEN_FRAMES.len(), outcome);
+        process::exit(0);
+    }
+    process::exit(1);
+}
 
 fn main() {
-    app::App::new().unwrap()
-        .main_loop().unwrap();
+    let mut args = env::args().peekable();
+    args.next();
+    if args.peek().map(|arg| arg == "--golden").unwrap_or(false) {
+        args.next();
+        run_golden(args);
+    }
+
+    let mut config = presenter::PresenterConfig::default();
+    for arg in args {
+        if arg.starts_with("--") {
+            usage();
+        }
+        config.meshes.push(arg.into());
+    }
+
+    let result = app::App::new(config)
+        .and_then(|mut app| app.main_loop());
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
 }
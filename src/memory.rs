@@ -0,0 +1,413 @@
+/*
+ * memory.rs
+ * A block based sub-allocator for device memory.
+ * Large DeviceMemory blocks are allocated per memory type
+ * and handed out in aligned ranges, keeping the number of
+ * vkAllocateMemory calls far below maxMemoryAllocationCount.
+ */
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::fmt;
+
+use voodoo::{Result as VdResult, Device, DeviceMemory, DeviceSize, MemoryRequirements,
+            MemoryPropertyFlags, MemoryMapFlags, MemoryMapping, Buffer, Image};
+
+/// Size of newly allocated blocks. Larger requests get a block of their own.
+pub const DEFAULT_BLOCK_SIZE: DeviceSize = 64 << 20;
+
+/// Buffers and linear images must not share a page of
+/// `bufferImageGranularity` with optimally tiled images, so both are kept
+/// in separate blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tiling {
+    Linear,
+    Optimal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BlockKey {
+    memory_type_index: u32,
+    tiling: Tiling,
+}
+
+/// The unallocated ranges of a block.
+#[derive(Debug, Clone, PartialEq)]
+struct FreeList {
+    /// Unallocated `(offset, size)` ranges, sorted by offset and never adjacent.
+    free_ranges: Vec<(DeviceSize, DeviceSize)>,
+    allocation_count: usize,
+}
+
+impl FreeList {
+    /// The free list of a block of `size` bytes, all of them free.
+    fn new(size: DeviceSize) -> FreeList {
+        FreeList {
+            free_ranges: vec![(0, size)],
+            allocation_count: 0,
+        }
+    }
+
+    /// Carves an aligned range out of the first free range large enough.
+    fn allocate(&mut self, size: DeviceSize, alignment: DeviceSize) -> Option<DeviceSize> {
+        for i in 0..self.free_ranges.len() {
+            let (range_offset, range_size) = self.free_ranges[i];
+            let offset = align_up(range_offset, alignment);
+            let padding = offset - range_offset;
+            if padding + size > range_size {
+                continue;
+            }
+
+            let remaining = range_size - padding - size;
+            self.free_ranges.remove(i);
+            if remaining > 0 {
+                self.free_ranges.insert(i, (offset + size, remaining));
+            }
+            if padding > 0 {
+                self.free_ranges.insert(i, (range_offset, padding));
+            }
+            self.allocation_count += 1;
+            return Some(offset);
+        }
+        None
+    }
+
+    /// Returns a range to the free list, merging it with its neighbours.
+    fn free(&mut self, offset: DeviceSize, size: DeviceSize) {
+        let i = self.free_ranges.iter()
+            .position(|&(range_offset, _)| range_offset > offset)
+            .unwrap_or(self.free_ranges.len());
+        self.free_ranges.insert(i, (offset, size));
+
+        if i + 1 < self.free_ranges.len() {
+            let (next_offset, next_size) = self.free_ranges[i + 1];
+            if offset + size == next_offset {
+                self.free_ranges[i].1 += next_size;
+                self.free_ranges.remove(i + 1);
+            }
+        }
+        if i > 0 {
+            let (prev_offset, prev_size) = self.free_ranges[i - 1];
+            if prev_offset + prev_size == offset {
+                self.free_ranges[i - 1].1 += self.free_ranges[i].1;
+                self.free_ranges.remove(i);
+            }
+        }
+        self.allocation_count -= 1;
+    }
+
+    fn free_bytes(&self) -> DeviceSize {
+        self.free_ranges.iter().map(|&(_, size)| size).sum()
+    }
+}
+
+struct Block {
+    id: u64,
+    memory: DeviceMemory,
+    size: DeviceSize,
+    free_list: FreeList,
+}
+
+pub fn align_up(value: DeviceSize, alignment: DeviceSize) -> DeviceSize {
+    if alignment <= 1 {
+        value
+    } else {
+        (value + alignment - 1) / alignment * alignment
+    }
+}
+
+struct Inner {
+    device: Device,
+    block_size: DeviceSize,
+    next_block_id: u64,
+    blocks: HashMap<BlockKey, Vec<Block>>,
+}
+
+impl Inner {
+    fn free(&mut self, key: BlockKey, block_id: u64, offset: DeviceSize, size: DeviceSize) {
+        let block_size = self.block_size;
+        let blocks = self.blocks.get_mut(&key).expect("allocation of unknown memory type");
+        let index = blocks.iter().position(|block| block.id == block_id)
+            .expect("allocation of unknown block");
+        blocks[index].free_list.free(offset, size);
+        // Give empty blocks back to the driver, but keep one so allocating
+        // and freeing in turn doesn't allocate a block every time. Blocks of
+        // a single large allocation aren't worth keeping.
+        if blocks[index].free_list.allocation_count == 0 {
+            let spare_count = blocks.iter().filter(|block| {
+                block.free_list.allocation_count == 0 && block.size == block_size
+            }).count();
+            if blocks[index].size != block_size || spare_count > 1 {
+                blocks.remove(index);
+            }
+        }
+    }
+}
+
+/// Hands out sub-ranges of large `DeviceMemory` blocks.
+///
+/// Cloning an allocator yields another handle to the same blocks.
+#[derive(Clone)]
+pub struct Allocator {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Allocator {
+    pub fn new(device: Device) -> Allocator {
+        Allocator::with_block_size(device, DEFAULT_BLOCK_SIZE)
+    }
+
+    pub fn with_block_size(device: Device, block_size: DeviceSize) -> Allocator {
+        Allocator {
+            inner: Arc::new(Mutex::new(Inner {
+                device,
+                block_size,
+                next_block_id: 0,
+                blocks: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Allocates memory satisfying `requirements` from a memory type with
+    /// `properties`.
+    pub fn allocate(&self,
+                    requirements: &MemoryRequirements,
+                    properties: MemoryPropertyFlags,
+                    tiling: Tiling) -> VdResult<Allocation> {
+        let mut inner = self.inner.lock().unwrap();
+        let memory_type_index = inner.device.memory_type_index(
+            requirements.memory_type_bits(), properties)?;
+        let key = BlockKey { memory_type_index, tiling };
+        let size = requirements.size();
+        let alignment = requirements.alignment();
+
+        let found = inner.blocks.get_mut(&key).and_then(|blocks| {
+            blocks.iter_mut().filter_map(|block| {
+                block.free_list.allocate(size, alignment)
+                    .map(|offset| (block.id, block.memory.clone(), offset))
+            }).next()
+        });
+
+        let (block_id, memory, offset) = match found {
+            Some(found) => found,
+            None => {
+                let block_size = inner.block_size.max(size);
+                let memory = DeviceMemory::new(inner.device.clone(), block_size,
+                    memory_type_index)?;
+                let mut block = Block {
+                    id: inner.next_block_id,
+                    memory,
+                    size: block_size,
+                    free_list: FreeList::new(block_size),
+                };
+                inner.next_block_id += 1;
+
+                let offset = block.free_list.allocate(size, alignment)
+                    .expect("fresh block too small for allocation");
+                let found = (block.id, block.memory.clone(), offset);
+                inner.blocks.entry(key).or_insert_with(Vec::new).push(block);
+                found
+            }
+        };
+
+        Ok(Allocation {
+            allocator: self.clone(),
+            key,
+            block_id,
+            memory,
+            offset,
+            size,
+        })
+    }
+
+    /// Allocates memory for `buffer` and binds it.
+    pub fn allocate_buffer(&self,
+                           buffer: &Buffer,
+                           properties: MemoryPropertyFlags) -> VdResult<Allocation> {
+        let memory_requirements = buffer.memory_requirements().clone();
+        let allocation = self.allocate(&memory_requirements, properties, Tiling::Linear)?;
+        unsafe {
+            buffer.bind_memory(allocation.memory(), allocation.offset())?;
+        }
+        Ok(allocation)
+    }
+
+    /// Allocates memory for the optimally tiled `image` and binds it.
+    pub fn allocate_image(&self,
+                          image: &Image,
+                          properties: MemoryPropertyFlags) -> VdResult<Allocation> {
+        let memory_requirements = image.memory_requirements().clone();
+        let allocation = self.allocate(&memory_requirements, properties, Tiling::Optimal)?;
+        unsafe {
+            image.bind_memory(allocation.memory(), allocation.offset())?;
+        }
+        Ok(allocation)
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let inner = self.inner.lock().unwrap();
+        let mut stats = AllocatorStats::default();
+        for block in inner.blocks.values().flat_map(|blocks| blocks.iter()) {
+            let free_list = &block.free_list;
+            let free_bytes = free_list.free_bytes();
+            stats.block_count += 1;
+            stats.allocation_count += free_list.allocation_count;
+            stats.reserved_bytes += block.size;
+            stats.used_bytes += block.size - free_bytes;
+            stats.free_range_count += free_list.free_ranges.len();
+            stats.largest_free_range = free_list.free_ranges.iter()
+                .map(|&(_, size)| size)
+                .fold(stats.largest_free_range, DeviceSize::max);
+        }
+        stats
+    }
+}
+
+/// A range of device memory, returned to its allocator when dropped.
+///
+/// Only one allocation of a block may be mapped at a time.
+pub struct Allocation {
+    allocator: Allocator,
+    key: BlockKey,
+    block_id: u64,
+    memory: DeviceMemory,
+    offset: DeviceSize,
+    size: DeviceSize,
+}
+
+impl Allocation {
+    /// The block this allocation lies in.
+    pub fn memory(&self) -> &DeviceMemory {
+        &self.memory
+    }
+
+    /// Offset of the allocation within its block.
+    pub fn offset(&self) -> DeviceSize {
+        self.offset
+    }
+
+    pub fn size(&self) -> DeviceSize {
+        self.size
+    }
+
+    /// Maps `size` bytes from the start of the allocation.
+    pub unsafe fn map<T>(&self, size: DeviceSize) -> VdResult<MemoryMapping<T>> {
+        self.map_range(0, size)
+    }
+
+    /// Maps `size` bytes starting `offset` bytes into the allocation.
+    pub unsafe fn map_range<T>(&self,
+                               offset: DeviceSize,
+                               size: DeviceSize) -> VdResult<MemoryMapping<T>> {
+        self.memory.map(self.offset + offset, size, MemoryMapFlags::empty())
+    }
+
+    pub fn unmap<T>(&self, mapping: MemoryMapping<T>) {
+        self.memory.unmap(mapping);
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.allocator.inner.lock() {
+            inner.free(self.key, self.block_id, self.offset, self.size);
+        }
+    }
+}
+
+/// Usage and fragmentation figures of an `Allocator`.
+#[derive(Debug, Clone, Default)]
+pub struct AllocatorStats {
+    pub block_count: usize,
+    pub allocation_count: usize,
+    /// Bytes allocated from the driver.
+    pub reserved_bytes: DeviceSize,
+    /// Bytes handed out, including alignment padding.
+    pub used_bytes: DeviceSize,
+    pub free_range_count: usize,
+    pub largest_free_range: DeviceSize,
+}
+
+impl AllocatorStats {
+    /// The share of free memory not part of the largest free range, from 0
+    /// (one contiguous range) to nearly 1 (many small ranges).
+    pub fn fragmentation(&self) -> f32 {
+        let free_bytes = self.reserved_bytes - self.used_bytes;
+        if free_bytes == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free_range as f32 / free_bytes as f32
+        }
+    }
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} allocations in {} blocks, {} of {} KiB used, {} free ranges \
+            ({:.1}% fragmentation)", self.allocation_count, self.block_count,
+            self.used_bytes >> 10, self.reserved_bytes >> 10, self.free_range_count,
+            self.fragmentation() * 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_up_rounds_to_multiples() {
+        assert_eq!(align_up(0, 256), 0);
+        assert_eq!(align_up(1, 256), 256);
+        assert_eq!(align_up(256, 256), 256);
+        assert_eq!(align_up(257, 256), 512);
+        assert_eq!(align_up(13, 1), 13);
+        assert_eq!(align_up(13, 0), 13);
+    }
+
+    #[test]
+    fn allocations_are_aligned() {
+        let mut free_list = FreeList::new(1024);
+        assert_eq!(free_list.allocate(10, 1), Some(0));
+        assert_eq!(free_list.allocate(100, 64), Some(64));
+        // The padding stays free for smaller allocations
+        assert_eq!(free_list.free_ranges, vec![(10, 54), (164, 860)]);
+        assert_eq!(free_list.allocate(16, 16), Some(16));
+        assert_eq!(free_list.allocation_count, 3);
+    }
+
+    #[test]
+    fn allocations_fail_without_room() {
+        let mut free_list = FreeList::new(256);
+        assert_eq!(free_list.allocate(200, 1), Some(0));
+        assert_eq!(free_list.allocate(100, 1), None);
+        // Enough bytes are free, but not once aligned
+        assert_eq!(free_list.allocate(40, 128), None);
+        assert_eq!(free_list.allocation_count, 1);
+    }
+
+    #[test]
+    fn freed_ranges_merge_with_neighbours() {
+        let mut free_list = FreeList::new(300);
+        let a = free_list.allocate(100, 1).unwrap();
+        let b = free_list.allocate(100, 1).unwrap();
+        let c = free_list.allocate(100, 1).unwrap();
+        assert!(free_list.free_ranges.is_empty());
+
+        free_list.free(a, 100);
+        free_list.free(c, 100);
+        assert_eq!(free_list.free_ranges, vec![(0, 100), (200, 100)]);
+        // Merges with the ranges on both sides
+        free_list.free(b, 100);
+        assert_eq!(free_list, FreeList::new(300));
+    }
+
+    #[test]
+    fn freed_ranges_merge_with_the_next() {
+        let mut free_list = FreeList::new(300);
+        free_list.allocate(100, 1).unwrap();
+        let b = free_list.allocate(100, 1).unwrap();
+        free_list.free(b, 100);
+        assert_eq!(free_list.free_ranges, vec![(100, 200)]);
+        assert_eq!(free_list.free_bytes(), 200);
+        assert_eq!(free_list.allocation_count, 1);
+    }
+}
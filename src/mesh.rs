@@ -0,0 +1,140 @@
+/*
+ * mesh.rs
+ * Vertex and index buffers on the device, together with
+ * everything needed to draw them.
+ */
+use vulkan::{self, Vertex, Model, SubMesh};
+
+use memory::{Allocator, Allocation};
+use upload::UploadManager;
+
+use voodoo::{Result as VdResult, Device, CommandBuffer, Buffer, IndexType};
+
+use std::u16;
+
+/// Integer types which can be stored in an index buffer.
+pub trait Index: Copy + Into<u32> {
+    const INDEX_TYPE: IndexType;
+}
+
+impl Index for u16 {
+    const INDEX_TYPE: IndexType = IndexType::Uint16;
+}
+
+impl Index for u32 {
+    const INDEX_TYPE: IndexType = IndexType::Uint32;
+}
+
+/// A vertex and index buffer which knows its own counts and index type, so
+/// draw calls always match the uploaded data.
+pub struct Mesh {
+    vertex_buffer: Buffer,
+    vertex_buffer_memory: Allocation,
+    index_buffer: Buffer,
+    index_buffer_memory: Allocation,
+    vertex_count: u32,
+    index_count: u32,
+    index_type: IndexType,
+    sub_meshes: Vec<SubMesh>,
+}
+
+impl Mesh {
+    /// Queues uploading `vertices` and `indices`. The mesh can be drawn once
+    /// the uploads have completed.
+    ///
+    /// Fails if an index refers past the end of `vertices` or a sub-mesh
+    /// lies outside of `indices`.
+    pub fn new<I: Index>(device: &Device,
+                         allocator: &Allocator,
+                         uploads: &mut UploadManager,
+                         vertices: &[Vertex],
+                         indices: &[I],
+                         sub_meshes: Vec<SubMesh>) -> VdResult<Mesh> {
+        let vertex_count = vertices.len() as u32;
+        let index_count = indices.len() as u32;
+
+        if vertices.is_empty() || indices.is_empty() {
+            return Err("meshes need at least one vertex and index".into());
+        }
+        if let Some(&index) = indices.iter().find(|&&index| index.into() >= vertex_count) {
+            return Err(format!("index {} is out of bounds for {} vertices",
+                index.into(), vertex_count).into());
+        }
+        if let Some(sub_mesh) = sub_meshes.iter()
+                .find(|sub_mesh| sub_mesh.first_index + sub_mesh.index_count > index_count) {
+            return Err(format!("sub-mesh {:?} exceeds the {} indices of its mesh",
+                sub_mesh.name, index_count).into());
+        }
+
+        let (vertex_buffer, vertex_buffer_memory) =
+            vulkan::create_vertex_buffer(device, allocator, uploads, vertices)?;
+        let (index_buffer, index_buffer_memory) =
+            vulkan::create_index_buffer(device, allocator, uploads, indices)?;
+
+        Ok(Mesh {
+            vertex_buffer,
+            vertex_buffer_memory,
+            index_buffer,
+            index_buffer_memory,
+            vertex_count,
+            index_count,
+            index_type: I::INDEX_TYPE,
+            sub_meshes,
+        })
+    }
+
+    /// Queues uploading `model`, using 16 bit indices when its vertex count allows.
+    pub fn from_model(device: &Device,
+                      allocator: &Allocator,
+                      uploads: &mut UploadManager,
+                      model: &Model) -> VdResult<Mesh> {
+        if model.vertices.len() <= u16::MAX as usize + 1 {
+            let indices: Vec<u16> = model.indices.iter().map(|&i| i as u16).collect();
+            Mesh::new(device, allocator, uploads, &model.vertices, &indices,
+                model.sub_meshes.clone())
+        } else {
+            Mesh::new(device, allocator, uploads, &model.vertices, &model.indices,
+                model.sub_meshes.clone())
+        }
+    }
+
+    pub fn vertex_count(&self) -> u32 {
+        self.vertex_count
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.index_count
+    }
+
+    pub fn index_type(&self) -> IndexType {
+        self.index_type
+    }
+
+    pub fn sub_meshes(&self) -> &[SubMesh] {
+        &self.sub_meshes
+    }
+
+    /// Records binding the buffers and drawing all indices of the mesh.
+    pub fn draw(&self, command_buffer: &CommandBuffer) {
+        self.bind(command_buffer);
+        command_buffer.draw_indexed(self.index_count, 1, 0, 0, 0);
+    }
+
+    /// Records drawing `instance_count` instances of the indices of
+    /// `sub_mesh`, starting at instance `first_instance`. The buffers have
+    /// to be bound already.
+    pub fn draw_sub_mesh(&self,
+                         command_buffer: &CommandBuffer,
+                         sub_mesh: &SubMesh,
+                         first_instance: u32,
+                         instance_count: u32) {
+        command_buffer.draw_indexed(sub_mesh.index_count, instance_count, sub_mesh.first_index,
+            0, first_instance);
+    }
+
+    /// Records binding the vertex and index buffer.
+    pub fn bind(&self, command_buffer: &CommandBuffer) {
+        command_buffer.bind_vertex_buffers(0, &[&self.vertex_buffer], &[0]);
+        command_buffer.bind_index_buffer(&self.index_buffer, 0, self.index_type);
+    }
+}
@@ -0,0 +1,281 @@
+/*
+ * pipeline.rs
+ * Descriptions of the fixed-function state of graphics
+ * pipelines, and a cache creating each described pipeline
+ * only once.
+ */
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use voodoo::{Result as VdResult, Device, GraphicsPipeline, PipelineLayout, RenderPass, PipelineCache,
+            PrimitiveTopology, PolygonMode, CullModeFlags, FrontFace, CompareOp};
+
+use vulkan;
+
+/// How fragments are combined with the color already in the attachment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    /// Fragments replace the attachment color.
+    Opaque,
+    /// `src * src_alpha + dst * (1 - src_alpha)`
+    Alpha,
+    /// `src * src_alpha + dst`
+    Additive,
+}
+
+/// The fixed-function state of a graphics pipeline.
+///
+/// The viewport and scissor aren't part of it, every pipeline leaves them to
+/// be set while recording so it works for any framebuffer size.
+///
+/// Start from one of the presets and adjust it with the setters, or by
+/// replacing fields:
+///
+/// ```ignore
+/// let desc = PipelineDesc {
+///     cull_mode: CullModeFlags::BACK,
+///     ..PipelineDesc::alpha_blended()
+/// };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipelineDesc {
+    pub topology: PrimitiveTopology,
+    pub polygon_mode: PolygonMode,
+    pub cull_mode: CullModeFlags,
+    pub front_face: FrontFace,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare: CompareOp,
+    pub blend: BlendMode,
+}
+
+impl PipelineDesc {
+    /// Depth tested and written triangles without blending.
+    pub fn opaque() -> PipelineDesc {
+        PipelineDesc {
+            topology: PrimitiveTopology::TriangleList,
+            polygon_mode: PolygonMode::Fill,
+            cull_mode: CullModeFlags::NONE,
+            front_face: FrontFace::CounterClockwise,
+            depth_test: true,
+            depth_write: true,
+            depth_compare: CompareOp::Less,
+            blend: BlendMode::Opaque,
+        }
+    }
+
+    /// Alpha blended triangles, depth tested against but not written, so
+    /// they are drawn after opaque geometry.
+    pub fn alpha_blended() -> PipelineDesc {
+        PipelineDesc::opaque()
+            .blend(BlendMode::Alpha)
+            .depth_write(false)
+    }
+
+    /// Triangles adding their color to what is behind them, e.g. for glows.
+    pub fn additive() -> PipelineDesc {
+        PipelineDesc::opaque()
+            .blend(BlendMode::Additive)
+            .depth_write(false)
+    }
+
+    /// Triangle edges only. Needs the `fillModeNonSolid` device feature.
+    pub fn wireframe() -> PipelineDesc {
+        PipelineDesc::opaque()
+            .polygon_mode(PolygonMode::Line)
+    }
+
+    /// A line between each pair of vertices.
+    pub fn lines() -> PipelineDesc {
+        PipelineDesc::opaque()
+            .topology(PrimitiveTopology::LineList)
+    }
+
+    pub fn topology(mut self, topology: PrimitiveTopology) -> PipelineDesc {
+        self.topology = topology;
+        self
+    }
+
+    pub fn polygon_mode(mut self, polygon_mode: PolygonMode) -> PipelineDesc {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn depth_write(mut self, depth_write: bool) -> PipelineDesc {
+        self.depth_write = depth_write;
+        self
+    }
+
+    pub fn blend(mut self, blend: BlendMode) -> PipelineDesc {
+        self.blend = blend;
+        self
+    }
+}
+
+impl Default for PipelineDesc {
+    fn default() -> PipelineDesc {
+        PipelineDesc::opaque()
+    }
+}
+
+impl Eq for PipelineDesc {}
+
+impl Hash for PipelineDesc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.topology as i32).hash(state);
+        (self.polygon_mode as i32).hash(state);
+        self.cull_mode.bits().hash(state);
+        (self.front_face as i32).hash(state);
+        self.depth_test.hash(state);
+        self.depth_write.hash(state);
+        (self.depth_compare as i32).hash(state);
+        self.blend.hash(state);
+    }
+}
+
+/// Creates graphics pipelines on first use and keeps them by description.
+///
+/// All pipelines share a layout, shaders and render pass. Replacing the
+/// shaders or render pass drops the pipelines made for the old ones.
+/// Creation goes through a driver pipeline cache, so recreating them is
+/// cheaper than the first time.
+pub struct Pipelines {
+    device: Device,
+    cache: PipelineCache,
+    layout: PipelineLayout,
+    vert_shader_code: Vec<u32>,
+    frag_shader_code: Vec<u32>,
+    render_pass: Option<RenderPass>,
+    pipelines: HashMap<PipelineDesc, GraphicsPipeline>,
+}
+
+impl Pipelines {
+    pub fn new(device: &Device,
+               cache: &PipelineCache,
+               layout: &PipelineLayout,
+               vert_shader_code: Vec<u32>,
+               frag_shader_code: Vec<u32>) -> Pipelines {
+        Pipelines {
+            device: device.clone(),
+            cache: cache.clone(),
+            layout: layout.clone(),
+            vert_shader_code,
+            frag_shader_code,
+            render_pass: None,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    /// The pipeline described by `desc`, created if it isn't cached yet.
+    pub fn get(&mut self, desc: &PipelineDesc) -> VdResult<GraphicsPipeline> {
+        if let Some(pipeline) = self.pipelines.get(desc) {
+            return Ok(pipeline.clone());
+        }
+        let pipeline = self.create(desc, &self.vert_shader_code, &self.frag_shader_code)?;
+        self.pipelines.insert(*desc, pipeline.clone());
+        Ok(pipeline)
+    }
+
+    fn create(&self,
+              desc: &PipelineDesc,
+              vert_shader_code: &[u32],
+              frag_shader_code: &[u32]) -> VdResult<GraphicsPipeline> {
+        let render_pass = self.render_pass.as_ref()
+            .ok_or("no render pass to create pipelines for")?;
+        vulkan::create_graphics_pipeline(self.device.clone(),
+                                         Some(&self.cache),
+                                         &self.layout,
+                                         render_pass,
+                                         vert_shader_code,
+                                         frag_shader_code,
+                                         desc)
+    }
+
+    /// Makes future pipelines draw in `render_pass`. The pipelines made for
+    /// the previous render pass must no longer be in use.
+    ///
+    /// Pipelines don't depend on the framebuffer size, so this is only
+    /// needed when the attachment formats change, not on resize.
+    pub fn set_render_pass(&mut self, render_pass: &RenderPass) {
+        self.pipelines.clear();
+        self.render_pass = Some(render_pass.clone());
+    }
+
+    /// Switches to new shaders, recreating every cached pipeline with them.
+    /// If any pipeline fails, the old shaders and pipelines are kept.
+    ///
+    /// Waits for the device to be idle before destroying the old pipelines.
+    pub fn set_shaders(&mut self,
+                       vert_shader_code: Vec<u32>,
+                       frag_shader_code: Vec<u32>) -> VdResult<()> {
+        let mut pipelines = HashMap::with_capacity(self.pipelines.len());
+        for desc in self.pipelines.keys() {
+            let pipeline = self.create(desc, &vert_shader_code, &frag_shader_code)?;
+            pipelines.insert(*desc, pipeline);
+        }
+
+        self.device.wait_idle();
+        self.pipelines = pipelines;
+        self.vert_shader_code = vert_shader_code;
+        self.frag_shader_code = frag_shader_code;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash(desc: &PipelineDesc) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        desc.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn equal_descriptions_hash_equally() {
+        let a = PipelineDesc::opaque().blend(BlendMode::Alpha).depth_write(false);
+        let b = PipelineDesc::alpha_blended();
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_eq!(PipelineDesc::default(), PipelineDesc::opaque());
+    }
+
+    #[test]
+    fn presets_differ() {
+        let presets = [
+            PipelineDesc::opaque(),
+            PipelineDesc::alpha_blended(),
+            PipelineDesc::additive(),
+            PipelineDesc::wireframe(),
+            PipelineDesc::lines(),
+        ];
+        for (i, a) in presets.iter().enumerate() {
+            for b in &presets[i + 1..] {
+                assert_ne!(a, b);
+                assert_ne!(hash(a), hash(b));
+            }
+        }
+    }
+
+    #[test]
+    fn every_field_is_hashed() {
+        let opaque = PipelineDesc::opaque();
+        let variants = [
+            PipelineDesc { topology: PrimitiveTopology::TriangleStrip, ..opaque },
+            PipelineDesc { polygon_mode: PolygonMode::Point, ..opaque },
+            PipelineDesc { cull_mode: CullModeFlags::BACK, ..opaque },
+            PipelineDesc { front_face: FrontFace::Clockwise, ..opaque },
+            PipelineDesc { depth_test: false, ..opaque },
+            PipelineDesc { depth_write: false, ..opaque },
+            PipelineDesc { depth_compare: CompareOp::LessOrEqual, ..opaque },
+            PipelineDesc { blend: BlendMode::Additive, ..opaque },
+        ];
+        for variant in &variants {
+            assert_ne!(*variant, opaque);
+            assert_ne!(hash(variant), hash(&opaque));
+        }
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * pipeline_cache.rs
+ * A Vulkan pipeline cache kept on disk between runs, so
+ * pipelines compiled once are cheap to create again.
+ */
+use std::fs;
+use std::path::PathBuf;
+use std::ptr;
+use std::os::raw::c_void;
+
+use voodoo::{Result as VdResult, Device, PipelineCache, PhysicalDevice, ErrorKind,
+             CallResult};
+use dirs;
+
+use error::{Result, ResultExt};
+
+/// Name of the directory in the user cache directory the cache is kept in.
+static CACHE_DIR_NAME: &str = "rust_game";
+static CACHE_FILE_NAME: &str = "pipeline_cache.bin";
+
+/// Starts our header in front of the data from the driver.
+const MAGIC: &[u8; 4] = b"RGPC";
+const FORMAT_VERSION: u32 = 1;
+/// Magic, format version, vendor, device and driver version, cache UUID.
+const HEADER_LEN: usize = 4 + 4 * 4 + 16;
+
+/// `VK_PIPELINE_CACHE_HEADER_VERSION_ONE`, the layout of the header the
+/// driver puts in front of its data.
+const VK_HEADER_VERSION_ONE: u32 = 1;
+const VK_HEADER_LEN: usize = 4 * 4 + 16;
+
+/// Where the cache is kept, if the platform has a user cache directory.
+pub fn default_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join(CACHE_DIR_NAME).join(CACHE_FILE_NAME))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset] as u32 | (bytes[offset + 1] as u32) << 8 |
+        (bytes[offset + 2] as u32) << 16 | (bytes[offset + 3] as u32) << 24
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&[value as u8, (value >> 8) as u8,
+                              (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+/// Our header describing the device the data was created on.
+fn header(physical_device: &PhysicalDevice) -> Vec<u8> {
+    let properties = physical_device.properties();
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    write_u32(&mut header, FORMAT_VERSION);
+    write_u32(&mut header, properties.vendor_id());
+    write_u32(&mut header, properties.device_id());
+    write_u32(&mut header, properties.driver_version());
+    header.extend_from_slice(&properties.pipeline_cache_uuid()[..]);
+    header
+}
+
+/// Returns the driver's data in `file` if it was written for
+/// `physical_device` with the current driver, otherwise why it can't be
+/// used.
+fn validate<'f>(physical_device: &PhysicalDevice, file: &'f [u8]) -> Result<&'f [u8]> {
+    if file.len() < HEADER_LEN || &file[..4] != MAGIC {
+        return Err("not a pipeline cache file".into());
+    }
+    if read_u32(file, 4) != FORMAT_VERSION {
+        return Err(format!("unsupported format version {}", read_u32(file, 4)).into());
+    }
+    if file[..HEADER_LEN] != header(physical_device)[..] {
+        return Err("written for a different device or driver version".into());
+    }
+
+    // The driver checks its own header too, but not all drivers do it well
+    let data = &file[HEADER_LEN..];
+    let properties = physical_device.properties();
+    if data.len() < VK_HEADER_LEN || (read_u32(data, 0) as usize) < VK_HEADER_LEN ||
+        read_u32(data, 4) != VK_HEADER_VERSION_ONE
+    {
+        return Err("invalid driver header".into());
+    }
+    if read_u32(data, 8) != properties.vendor_id() ||
+        read_u32(data, 12) != properties.device_id() ||
+        data[16..32] != properties.pipeline_cache_uuid()[..]
+    {
+        return Err("driver header doesn't match the device".into());
+    }
+    Ok(data)
+}
+
+fn is_incomplete(err: &voodoo::Error) -> bool {
+    match err.kind {
+        ErrorKind::ApiCall(call_res, _) => call_res == CallResult::Incomplete,
+        _ => false,
+    }
+}
+
+/// Copies the contents of `cache` out of the driver.
+fn cache_data(device: &Device, cache: &PipelineCache) -> VdResult<Vec<u8>> {
+    // Pipelines created on other threads can grow the cache between the
+    // calls, so retry until the data fits and its size stays the same
+    loop {
+        let mut size = 0usize;
+        unsafe {
+            device.get_pipeline_cache_data(cache.handle(), &mut size, ptr::null_mut())?;
+        }
+        let mut data = vec![0u8; size];
+        let mut written = size;
+        let complete = unsafe {
+            device.get_pipeline_cache_data(cache.handle(), &mut written,
+                data.as_mut_ptr() as *mut c_void)
+        };
+        match complete {
+            Ok(()) => {},
+            // VK_INCOMPLETE, only part of the data fit
+            Err(ref err) if is_incomplete(err) => continue,
+            Err(err) => return Err(err),
+        }
+
+        let mut current_size = 0usize;
+        unsafe {
+            device.get_pipeline_cache_data(cache.handle(), &mut current_size,
+                ptr::null_mut())?;
+        }
+        if current_size == written {
+            data.truncate(written);
+            return Ok(data);
+        }
+    }
+}
+
+/// A pipeline cache loaded from a file, written back when dropped.
+pub struct PipelineCacheFile {
+    device: Device,
+    cache: PipelineCache,
+    path: Option<PathBuf>,
+}
+
+impl PipelineCacheFile {
+    /// Creates a cache from the file at `path`. A missing, corrupt or stale
+    /// file gives an empty cache, it is overwritten on save. Without a path
+    /// the cache only lasts as long as the process.
+    pub fn open(device: &Device, path: Option<PathBuf>) -> VdResult<PipelineCacheFile> {
+        let file = path.as_ref().and_then(|path| {
+            match fs::read(path) {
+                Ok(file) => Some(file),
+                Err(_) => {
+                    println!("No pipeline cache at {}", path.display());
+                    None
+                },
+            }
+        });
+        let initial_data = match file {
+            Some(ref file) => match validate(device.physical_device(), file) {
+                Ok(data) => data,
+                Err(err) => {
+                    println!("Ignoring pipeline cache {}: {}",
+                             path.as_ref().unwrap().display(), err);
+                    &[]
+                },
+            },
+            None => &[],
+        };
+
+        let cache = PipelineCache::builder()
+            .initial_data(initial_data)
+            .build(device.clone())?;
+
+        Ok(PipelineCacheFile {
+            device: device.clone(),
+            cache,
+            path,
+        })
+    }
+
+    pub fn cache(&self) -> &PipelineCache {
+        &self.cache
+    }
+
+    /// Writes the cache to its file, creating the directory if needed.
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let data = cache_data(&self.device, &self.cache)
+            .context("reading pipeline cache data")?;
+        let mut file = header(self.device.physical_device());
+        file.extend_from_slice(&data);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write then rename, so a crash can't leave a truncated cache behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &file)
+            .context(format!("writing {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path)
+            .context(format!("replacing {}", path.display()))?;
+        Ok(())
+    }
+}
+
+impl Drop for PipelineCacheFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.save() {
+            eprintln!("Unable to save pipeline cache: {}", err);
+        }
+    }
+}
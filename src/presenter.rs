@@ -9,13 +9,33 @@ use vulkan;
 use voodoo_winit::winit::{Window, EventsLoop};
 use voodoo::{Result as VdResult, ApplicationInfo, Instance, SurfaceKhr, Extent2d, Device,
             DescriptorSetLayout, DescriptorSet, PipelineLayout, CommandPool, DescriptorPool, ErrorKind,
-            SwapchainKhr, Sampler, CommandBuffer, Buffer, DeviceMemory, PipelineStageFlags, SubmitInfo,
-            PresentInfoKhr, CommandBufferHandle, Image, ImageView, CallResult, Semaphore, SemaphoreCreateFlags,
-            RenderPass, GraphicsPipeline, Framebuffer};
+            SwapchainKhr, DescriptorImageInfo, CommandBuffer, Buffer, PipelineStageFlags, SubmitInfo,
+            PresentInfoKhr, ImageHandle, ImageView, CallResult, Semaphore,
+            SemaphoreCreateFlags, GraphicsPipeline, Format, ImageLayout, Fence, FenceCreateFlags,
+            DescriptorType, ShaderStageFlags, AttachmentLoadOp, CommandBufferLevel,
+            CommandBufferUsageFlags, CommandPoolResetFlags, BufferUsageFlags,
+            MemoryPropertyFlags};
 
-use smallvec::SmallVec;
+use image::RgbaImage;
+use cgmath::Matrix4;
 
-use vulkan::{Vertex};
+use vulkan::{Vertex, UniformBufferObject, DeviceSelector, PresentPolicy, InstanceVertex, DrawData,
+             ViewportRect};
+use mesh::Mesh;
+use memory::{Allocator, Allocation};
+use upload::{self, UploadManager};
+use texture::{TextureManager, MAX_TEXTURES};
+use shader::{self, ShaderWatcher};
+use reflect::{self, PipelineInterface, DescriptorBinding};
+use pipeline::{Pipelines, PipelineDesc};
+use pipeline_cache::{self, PipelineCacheFile};
+use render_graph::{RenderGraph, PassId, PassDesc, ImageSize};
+use scene::{Scene, SceneMesh, MeshId, ObjectId, Object, DrawList};
+use culling::{self, Culler, CullBuffers, BoundingSphere};
+use error::{Result, ResultExt};
+
+use std::mem;
+use std::path::{Path, PathBuf};
 
 // RESOURCE DATA
 const VERTICES: [Vertex; 8] =  [
@@ -34,346 +54,1213 @@ const INDICES: [u32; 12] = [
 ];
 
 // Resource Paths
-// static MODEL_PATH: &str = "/src/shared_assets/models/chalet.obj";
 
-static VERT_SHADER_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"),
-    "/shaders/vert.spv");
-static FRAG_SHADER_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"),
-    "/shaders/frag.spv");
+static SHADER_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"),
+    "/shaders");
+static VERT_SHADER_SOURCE: &str = concat!(env!("CARGO_MANIFEST_DIR"),
+    "/shaders/shader.vert");
+static FRAG_SHADER_SOURCE: &str = concat!(env!("CARGO_MANIFEST_DIR"),
+    "/shaders/shader.frag");
+static CULL_SHADER_SOURCE: &str = concat!(env!("CARGO_MANIFEST_DIR"),
+    "/shaders/cull.comp");
 static TEXTURE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"),
     "/images/hello.jpg");
 
+/// Returns true if `err` reports that the swapchain no longer matches the
+/// surface and has to be recreated.
+fn is_out_of_date(err: &voodoo::Error) -> bool {
+    match err.kind {
+        ErrorKind::ApiCall(call_res, _) => call_res == CallResult::ErrorOutOfDateKhr,
+        _ => false,
+    }
+}
+
+/// Merges the interfaces of the vertex and fragment shader.
+fn reflect_shaders(vert_shader_code: &[u32], frag_shader_code: &[u32]) -> Result<PipelineInterface> {
+    let vert = reflect::reflect(vert_shader_code).context("vertex shader")?;
+    let frag = reflect::reflect(frag_shader_code).context("fragment shader")?;
+    PipelineInterface::new(&[&vert, &frag])
+}
+
+/// Where the shaders read the resources of a frame, all in descriptor set 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SceneBindings {
+    uniform_buffer: u32,
+    /// An array of `MAX_TEXTURES` combined image samplers.
+    textures: u32,
+    /// The `DrawData` of each draw, read by the vertex shader.
+    draw_data: u32,
+}
+
+/// The only binding of `descriptor_type` in `interface`.
+fn find_binding(interface: &PipelineInterface,
+                descriptor_type: DescriptorType,
+                what: &str) -> Result<DescriptorBinding> {
+    let mut bindings = interface.bindings.iter()
+        .filter(|binding| binding.descriptor_type == descriptor_type);
+    match (bindings.next(), bindings.next()) {
+        (Some(binding), None) => Ok(binding.clone()),
+        (None, _) => Err(format!("shaders don't read {}", what).into()),
+        (Some(_), Some(_)) => Err(format!("shaders read several {:?} bindings, expected one \
+            for {}", descriptor_type, what).into()),
+    }
+}
+
+/// Finds the bindings `Frame` writes descriptors for in the shaders.
+fn scene_bindings(interface: &PipelineInterface) -> Result<SceneBindings> {
+    let uniform_buffer = find_binding(interface, DescriptorType::UniformBuffer,
+        "the uniform buffer")?;
+    let textures = find_binding(interface, DescriptorType::CombinedImageSampler,
+        "the textures")?;
+    if textures.count != MAX_TEXTURES {
+        return Err(format!("shaders sample {} textures at binding {}, expected an array of {}",
+            textures.count, textures.binding, MAX_TEXTURES).into());
+    }
+    let draw_data = find_binding(interface, DescriptorType::StorageBuffer, "the draw data")?;
+    if !draw_data.stage_flags.contains(ShaderStageFlags::VERTEX) {
+        return Err(format!("the draw data at binding {} isn't read by the vertex shader",
+            draw_data.binding).into());
+    }
+
+    let bindings = [&uniform_buffer, &textures, &draw_data];
+    if bindings.iter().any(|binding| binding.set != 0) {
+        return Err("shaders read descriptors outside of set 0".into());
+    }
+    if interface.bindings.len() != bindings.len() {
+        return Err("shaders use descriptors besides the uniform buffer, textures and draw \
+            data".into());
+    }
+    Ok(SceneBindings {
+        uniform_buffer: uniform_buffer.binding,
+        textures: textures.binding,
+        draw_data: draw_data.binding,
+    })
+}
+
 fn window_extent(window: &Window) -> Extent2d {
-        let dims = window.get_inner_size().unwrap();
+        // A window which no longer exists has nothing to render to
+        let dims = window.get_inner_size().unwrap_or((0, 0));
         Extent2d::builder()
             .height(dims.1 as u32)
             .width(dims.0 as u32)
             .build()
 }
 
-pub struct SwapchainComponents {
-    pub image_views: Vec<ImageView>,
-    pub render_pass: RenderPass,
-    pub graphics_pipeline: GraphicsPipeline,
-    pub framebuffers: Vec<Framebuffer>,
+/// Creates the render graph drawing the scene into an output of
+/// `output_format`, left in `output_final_layout` for presenting or reading
+/// back. Returns the graph and the pass drawing the scene.
+fn create_render_graph(device: &Device,
+                       output_format: Format,
+                       output_final_layout: ImageLayout) -> VdResult<(RenderGraph, PassId)> {
+    let mut graph = RenderGraph::new();
+    let output = graph.add_output("output", output_format, output_final_layout)?;
+    let depth = graph.add_image("depth", vulkan::find_depth_format(device)?, ImageSize::Output);
+    let scene_pass = graph.add_pass("scene", PassDesc::new()
+        .color(output, AttachmentLoadOp::Clear)
+        .depth(depth, AttachmentLoadOp::Clear));
+    graph.compile(device)?;
+    Ok((graph, scene_pass))
 }
 
-pub fn create_swapchain_components(device: &Device,
-                                   swapchain: &SwapchainKhr,
-                                   pipeline_layout: &PipelineLayout,
-                                   depth_image_view: &ImageView,
-                                   vert_shader_code: &Vec<u32>,
-                                   frag_shader_code: &Vec<u32>,
-                                   extent: Extent2d) -> SwapchainComponents {
+/// The images and views of the swapchain, which the render graph draws to.
+fn swapchain_output(swapchain: &SwapchainKhr) -> VdResult<Vec<(ImageHandle, ImageView)>> {
+    let image_views = vulkan::create_image_views(swapchain)?;
+    Ok(swapchain.images().iter().map(|image| image.handle()).zip(image_views).collect())
+}
 
+/// Queues uploading the buffers of `model` and the textures of its
+/// materials. The mesh can be drawn once `uploads` has finished.
+fn upload_model(device: &Device,
+                allocator: &Allocator,
+                uploads: &mut UploadManager,
+                textures: &mut TextureManager,
+                model: &vulkan::Model) -> Result<SceneMesh> {
+    let material_textures = model.material_textures.iter().map(|path| {
+        match *path {
+            Some(ref path) => textures.load(uploads, path),
+            None => Ok(textures.default_texture()),
+        }
+    }).collect::<Result<Vec<_>>>()?;
 
-    let image_views = vulkan::create_image_views(swapchain).unwrap();
+    let mesh = Mesh::from_model(device,
+                                allocator,
+                                uploads,
+                                model)
+        .context("creating mesh buffers")?;
 
-    let render_pass = vulkan::create_render_pass(device.clone(),
-                                                 swapchain.image_format()).unwrap();
+    let texture_indices = mesh.sub_meshes().iter().map(|sub_mesh| {
+        sub_mesh.material_id
+            .and_then(|id| material_textures.get(id))
+            .unwrap_or(&textures.default_texture())
+            .index()
+    }).collect::<Vec<_>>();
 
-    let framebuffers = vulkan::create_framebuffers(&device,
-                                                   &render_pass,
-                                                   &image_views,
-                                                   depth_image_view,
-                                                   extent.clone()).unwrap();
+    Ok(SceneMesh {
+        mesh,
+        texture_indices,
+        bounds: BoundingSphere::from_vertices(&model.vertices),
+    })
+}
 
-    let graphics_pipeline =
-        vulkan::create_graphics_pipeline(device.clone(),
-                                         pipeline_layout,
-                                         &render_pass,
-                                         extent.clone(),
-                                         vert_shader_code,
-                                         frag_shader_code).unwrap();
+/// Creates the frustum culling pipeline, or returns why draws can't be
+/// culled on this device.
+fn create_culler(device: &Device,
+                 pipeline_cache: &PipelineCacheFile,
+                 frame_count: usize) -> Result<Culler> {
+    // Indirect draws of instanced objects start past the first instance
+    if !device.physical_device().features().draw_indirect_first_instance() {
+        return Err("the device doesn't support drawIndirectFirstInstance".into());
+    }
+    let shader_code = shader::compile_glsl(CULL_SHADER_SOURCE)?;
+    Culler::new(device, pipeline_cache.cache(), &shader_code, frame_count)
+}
 
-    SwapchainComponents {
-        image_views,
-        render_pass,
-        graphics_pipeline,
-        framebuffers,
+/// Settings the presenter is created with.
+#[derive(Debug, Clone)]
+pub struct PresenterConfig {
+    /// How many frames the CPU may record ahead of the GPU.
+    pub frames_in_flight: usize,
+    /// Overrides the physical device picked by score. Defaults to the
+    /// selector in the `RUST_GAME_GPU` environment variable.
+    pub physical_device: Option<DeviceSelector>,
+    pub present_policy: PresentPolicy,
+    /// OBJ files to draw. The built-in quads are drawn if this is empty.
+    pub meshes: Vec<PathBuf>,
+}
+
+impl Default for PresenterConfig {
+    fn default() -> PresenterConfig {
+        PresenterConfig {
+            frames_in_flight: 2,
+            physical_device: DeviceSelector::from_env(),
+            present_policy: PresentPolicy::default(),
+            meshes: Vec::new(),
+        }
+    }
+}
+
+/// The resources used by one of the frames in flight. A frame is reused
+/// once its fence signals that the GPU is done with it.
+pub struct Frame {
+    image_available_semaphore: Semaphore,
+    render_finished_semaphore: Semaphore,
+    in_flight_fence: Fence,
+    /// Reset every frame, which frees the commands of the last one at once.
+    command_pool: CommandPool,
+    command_buffer: CommandBuffer,
+    pub uniform_buffer: Buffer,
+    pub uniform_buffer_memory: Allocation,
+    pub descriptor_set: DescriptorSet,
+    bindings: SceneBindings,
+    /// Grown to fit the instances of the frame's draw list, created on
+    /// first use.
+    instance_buffer: Option<(Buffer, Allocation)>,
+    instance_capacity: usize,
+    /// The draw data of the frame's draw list, grown to fit.
+    draw_data_buffer: (Buffer, Allocation),
+    draw_data_capacity: usize,
+    /// Only with a culler.
+    cull_buffers: Option<CullBuffers>,
+    /// The revisions of the scene the buffers were last written for.
+    scene_revision: Option<u64>,
+    transform_revision: Option<u64>,
+    /// Receives a copy of the output image when the frame is captured,
+    /// grown to fit.
+    readback_buffer: Option<(Buffer, Allocation)>,
+    readback_size: u64,
+    /// Set when the next or last submission of this frame copies its
+    /// output to the readback buffer.
+    capture: Option<FrameCapture>,
+}
+
+/// The output image a frame copies to its readback buffer.
+struct FrameCapture {
+    image: ImageHandle,
+    extent: Extent2d,
+    format: Format,
+}
+
+impl Frame {
+    fn new(device: &Device,
+           surface: Option<&SurfaceKhr>,
+           allocator: &Allocator,
+           command_pool: &CommandPool,
+           descriptor_set_layout: &DescriptorSetLayout,
+           descriptor_pool: &DescriptorPool,
+           bindings: SceneBindings,
+           texture_image_infos: &[DescriptorImageInfo],
+           culler: Option<&Culler>,
+           extent: Extent2d) -> VdResult<Frame> {
+        let (uniform_buffer, uniform_buffer_memory) =
+            vulkan::create_uniform_buffer(device,
+                                          allocator,
+                                          command_pool,
+                                          extent)?;
+
+        let descriptor_sets =
+            vulkan::create_descriptor_sets(descriptor_set_layout,
+                                           descriptor_pool,
+                                           bindings.uniform_buffer,
+                                           &uniform_buffer,
+                                           bindings.textures,
+                                           texture_image_infos)?;
+
+        let frame_command_pool = vulkan::create_frame_command_pool(device.clone(), surface)?;
+        let command_buffer =
+            frame_command_pool.allocate_command_buffer(CommandBufferLevel::Primary)?;
+
+        let draw_data_buffer = Frame::create_draw_data_buffer(device, allocator, 1)?;
+        vulkan::update_storage_buffer_descriptor(descriptor_pool, &descriptor_sets[0],
+            bindings.draw_data, &draw_data_buffer.0, mem::size_of::<DrawData>() as u64);
+
+        let cull_buffers = match culler {
+            Some(culler) => {
+                let cull_buffers = culler.create_buffers()?;
+                culler.bind_draw_data(&cull_buffers, &draw_data_buffer.0,
+                    mem::size_of::<DrawData>() as u64);
+                Some(cull_buffers)
+            },
+            None => None,
+        };
+
+        Ok(Frame {
+            image_available_semaphore: Semaphore::new(device.clone(),
+                                                      SemaphoreCreateFlags::empty())?,
+            render_finished_semaphore: Semaphore::new(device.clone(),
+                                                      SemaphoreCreateFlags::empty())?,
+            // Signaled, so waiting on a frame which was never submitted returns
+            in_flight_fence: Fence::new(device.clone(), FenceCreateFlags::SIGNALED)?,
+            command_pool: frame_command_pool,
+            command_buffer,
+            uniform_buffer,
+            uniform_buffer_memory,
+            descriptor_set: descriptor_sets[0].clone(),
+            bindings,
+            instance_buffer: None,
+            instance_capacity: 0,
+            draw_data_buffer,
+            draw_data_capacity: 1,
+            cull_buffers,
+            scene_revision: None,
+            transform_revision: None,
+            readback_buffer: None,
+            readback_size: 0,
+            capture: None,
+        })
+    }
+
+    fn create_draw_data_buffer(device: &Device,
+                               allocator: &Allocator,
+                               capacity: usize) -> VdResult<(Buffer, Allocation)> {
+        vulkan::create_buffer(device,
+                              allocator,
+                              (capacity * mem::size_of::<DrawData>()) as u64,
+                              BufferUsageFlags::STORAGE_BUFFER,
+                              MemoryPropertyFlags::HOST_VISIBLE |
+                                  MemoryPropertyFlags::HOST_COHERENT)
+    }
+
+    /// Blocks until the GPU has finished the last submission of this frame.
+    fn wait(&self, device: &Device) -> VdResult<()> {
+        device.wait_for_fences(&[self.in_flight_fence.handle()], true, u64::max_value())
+    }
+
+    fn write_uniform_buffer(&self, ubo: &UniformBufferObject) -> VdResult<()> {
+        let mut data = unsafe {
+            self.uniform_buffer_memory.map(mem::size_of::<UniformBufferObject>() as u64)?
+        };
+        data.copy_from_slice(&[*ubo]);
+        self.uniform_buffer_memory.unmap(data);
+        Ok(())
+    }
+
+    /// Writes `instances` to the instance buffer, growing it if needed. The
+    /// frame must have finished on the GPU.
+    fn write_instances(&mut self,
+                       device: &Device,
+                       allocator: &Allocator,
+                       instances: &[InstanceVertex]) -> VdResult<()> {
+        if instances.is_empty() {
+            return Ok(());
+        }
+        if instances.len() > self.instance_capacity {
+            let capacity = instances.len().next_power_of_two();
+            self.instance_buffer = Some(vulkan::create_instance_buffer(device, allocator,
+                                                                       capacity)?);
+            self.instance_capacity = capacity;
+        }
+
+        let memory = &self.instance_buffer.as_ref().unwrap().1;
+        let mut data = unsafe {
+            memory.map((instances.len() * mem::size_of::<InstanceVertex>()) as u64)?
+        };
+        data.copy_from_slice(instances);
+        memory.unmap(data);
+        Ok(())
+    }
+
+    /// Writes `draw_data` to the draw data buffer, growing it if needed and
+    /// pointing the descriptors at the new one. The frame must have finished
+    /// on the GPU.
+    fn write_draw_data(&mut self,
+                       device: &Device,
+                       allocator: &Allocator,
+                       descriptor_pool: &DescriptorPool,
+                       culler: Option<&Culler>,
+                       draw_data: &[DrawData]) -> VdResult<()> {
+        if draw_data.is_empty() {
+            return Ok(());
+        }
+        if draw_data.len() > self.draw_data_capacity {
+            let capacity = draw_data.len().next_power_of_two();
+            self.draw_data_buffer = Frame::create_draw_data_buffer(device, allocator, capacity)?;
+            self.draw_data_capacity = capacity;
+
+            let size = (capacity * mem::size_of::<DrawData>()) as u64;
+            vulkan::update_storage_buffer_descriptor(descriptor_pool, &self.descriptor_set,
+                self.bindings.draw_data, &self.draw_data_buffer.0, size);
+            if let (Some(culler), Some(cull_buffers)) = (culler, self.cull_buffers.as_ref()) {
+                culler.bind_draw_data(cull_buffers, &self.draw_data_buffer.0, size);
+            }
+        }
+
+        let memory = &self.draw_data_buffer.1;
+        let mut data = unsafe {
+            memory.map((draw_data.len() * mem::size_of::<DrawData>()) as u64)?
+        };
+        data.copy_from_slice(draw_data);
+        memory.unmap(data);
+        Ok(())
+    }
+
+    /// Brings the instances, draw data and cull objects of this frame up to
+    /// date with `draw_list`, the draw list of `scene` at its current
+    /// revision. Only what changed since this frame was last written is
+    /// uploaded. The frame must have finished on the GPU.
+    fn write_scene(&mut self,
+                   device: &Device,
+                   allocator: &Allocator,
+                   descriptor_pool: &DescriptorPool,
+                   culler: Option<&Culler>,
+                   scene: &Scene,
+                   draw_list: &DrawList) -> VdResult<()> {
+        let draws_changed = self.scene_revision != Some(scene.revision());
+        if draws_changed {
+            self.write_instances(device, allocator, &draw_list.instances)?;
+            if let (Some(culler), Some(cull_buffers)) = (culler, self.cull_buffers.as_mut()) {
+                culler.write(allocator, cull_buffers, &culling::cull_objects(draw_list, scene))?;
+            }
+        }
+        if draws_changed || self.transform_revision != Some(scene.transform_revision()) {
+            self.write_draw_data(device, allocator, descriptor_pool, culler,
+                                 &scene.draw_data(draw_list))?;
+        }
+        self.scene_revision = Some(scene.revision());
+        self.transform_revision = Some(scene.transform_revision());
+        Ok(())
+    }
+
+    /// Records the command buffer of this frame anew, drawing `draw_list`
+    /// in `scene_pass` into the `output_index`th output of `render_graph`.
+    /// The frame must have finished on the GPU and `draw_list` has to be
+    /// written with `write_scene` already.
+    fn record(&self,
+              culler: Option<&Culler>,
+              frustum_planes: [[f32; 4]; 6],
+              max_indirect_draws: u32,
+              render_graph: &RenderGraph,
+              scene_pass: PassId,
+              output_index: usize,
+              draw_list: &DrawList,
+              pipelines: &[GraphicsPipeline],
+              scene: &Scene,
+              viewports: &[ViewportRect],
+              pipeline_layout: &PipelineLayout) -> VdResult<()> {
+        self.command_pool.reset(CommandPoolResetFlags::empty())?;
+        self.command_buffer.begin(CommandBufferUsageFlags::ONE_TIME_SUBMIT)?;
+        let draw_commands = match (culler, self.cull_buffers.as_ref()) {
+            (Some(culler), Some(cull_buffers)) => {
+                culler.record(&self.command_buffer, cull_buffers, frustum_planes);
+                cull_buffers.commands().filter(|_| !draw_list.is_empty())
+            },
+            _ => None,
+        };
+        render_graph.record(&self.command_buffer, output_index, |pass, cmd_buf, extent| {
+            if pass == scene_pass {
+                let instance_buffer = self.instance_buffer.as_ref()
+                    .filter(|_| !draw_list.instances.is_empty())
+                    .map(|&(ref buffer, _)| buffer);
+                vulkan::record_draw_list(cmd_buf, draw_list, pipelines, instance_buffer,
+                    draw_commands, max_indirect_draws, scene, extent, viewports,
+                    pipeline_layout, &self.descriptor_set);
+            }
+            Ok(())
+        })?;
+        // The output is ready to be presented, copy it before it is
+        if let Some(ref capture) = self.capture {
+            let &(ref readback_buffer, _) = self.readback_buffer.as_ref().unwrap();
+            vulkan::record_image_readback(&self.command_buffer, capture.image,
+                ImageLayout::PresentSrcKhr, readback_buffer, &capture.extent);
+        }
+        self.command_buffer.end()
+    }
+
+    /// Makes the next recording of this frame copy `image`, its output, to
+    /// the readback buffer, growing it if needed. The frame must have
+    /// finished on the GPU.
+    fn capture(&mut self,
+               device: &Device,
+               allocator: &Allocator,
+               image: ImageHandle,
+               extent: Extent2d,
+               format: Format) -> VdResult<()> {
+        let size = extent.width() as u64 * extent.height() as u64 * 4;
+        if size > self.readback_size {
+            self.readback_buffer = Some(vulkan::create_buffer(device,
+                allocator,
+                size,
+                BufferUsageFlags::TRANSFER_DST,
+                MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT)?);
+            self.readback_size = size;
+        }
+        self.capture = Some(FrameCapture { image, extent, format });
+        Ok(())
+    }
+
+    /// Waits for the last submission of this frame and returns the output
+    /// it captured, if it captured one.
+    fn take_capture(&mut self, device: &Device) -> VdResult<Option<RgbaImage>> {
+        let capture = match self.capture.take() {
+            Some(capture) => capture,
+            None => return Ok(None),
+        };
+        self.wait(device)?;
+
+        let (width, height) = (capture.extent.width(), capture.extent.height());
+        let memory = &self.readback_buffer.as_ref().unwrap().1;
+        let data = unsafe {
+            memory.map::<u8>(width as u64 * height as u64 * 4)?
+        };
+        let mut pixels = data.to_vec();
+        memory.unmap(data);
+
+        if vulkan::rgba8_swizzle(capture.format) == Some(true) {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        Ok(RgbaImage::from_raw(width, height, pixels))
+    }
+
+    /// Replaces the cull buffers of this frame with ones of `culler`, which
+    /// are filled when the frame is next written. The frame must have
+    /// finished on the GPU.
+    fn set_culler(&mut self, culler: &Culler) -> VdResult<()> {
+        let cull_buffers = culler.create_buffers()?;
+        culler.bind_draw_data(&cull_buffers, &self.draw_data_buffer.0,
+            (self.draw_data_capacity * mem::size_of::<DrawData>()) as u64);
+        self.cull_buffers = Some(cull_buffers);
+        self.scene_revision = None;
+        Ok(())
+    }
+
+    /// Points the texture array of this frame at `image_infos`. The frame
+    /// must have finished on the GPU.
+    fn set_textures(&self, descriptor_pool: &DescriptorPool, image_infos: &[DescriptorImageInfo]) {
+        vulkan::update_texture_descriptors(descriptor_pool, &self.descriptor_set,
+            self.bindings.textures, image_infos);
     }
 }
 
 pub struct Presenter {
     pub instance: Instance,
-    pub events_loop: EventsLoop,
-    pub window: Window,
-    pub surface: SurfaceKhr,
+    pub events_loop: Option<EventsLoop>,
+    pub window: Option<Window>,
+    pub surface: Option<SurfaceKhr>,
+    pub offscreen: Option<vulkan::OffscreenTarget>,
     pub device: Device,
+    pub allocator: Allocator,
+    pub uploads: UploadManager,
     pub descriptor_set_layout: DescriptorSetLayout,
-    pub descriptor_sets: SmallVec<[DescriptorSet; 8]>,
     pub pipeline_layout: PipelineLayout,
     pub command_pool: CommandPool,
     pub descriptor_pool: DescriptorPool,
-    pub texture_sampler: Sampler,
-    pub uniform_buffer: Buffer,
-    pub uniform_buffer_memory: DeviceMemory,
+    pub frames: Vec<Frame>,
+    current_frame: usize,
     pub swapchain: Option<SwapchainKhr>,
-    pub swapchain_components: Option<SwapchainComponents>,
-    pub command_buffers: Option<SmallVec<[CommandBuffer; 16]>>,
-    pub command_buffer_handles: Option<SmallVec<[CommandBufferHandle; 16]>>,
-    vert_shader_code: Vec<u32>,
-    frag_shader_code: Vec<u32>,
-    pub texture_image: Image,
-    pub texture_image_memory: DeviceMemory,
-    pub texture_image_view: ImageView,
-    vertices: Vec<vulkan::Vertex>,
-    indices: Vec<u32>,
-    vertex_buffer: Buffer,
-    pub vertex_buffer_memory: DeviceMemory,
-    index_buffer: Buffer,
-    pub index_buffer_memory: DeviceMemory,
-    image_available_semaphore: Semaphore,
-    render_finished_semaphore: Semaphore,
-    pub depth_image: Image,
-    pub depth_image_memory: DeviceMemory,
-    pub depth_image_view: ImageView,
+    /// The passes of a frame. Kept across resizes, recreated only if the
+    /// output format changes.
+    pub render_graph: RenderGraph,
+    scene_pass: PassId,
+    output_format: Format,
+    /// The parts of the framebuffer the scene is drawn into.
+    viewports: Vec<ViewportRect>,
+    /// Pipelines for the current shaders and render pass.
+    pub pipelines: Pipelines,
+    /// Shared by the pipelines and the culler, saved to disk when the
+    /// presenter is dropped.
+    pipeline_cache: PipelineCacheFile,
+    /// Culls draws on the GPU, if the device and shader allow it.
+    culler: Option<Culler>,
+    /// How many culled draws a single indirect draw call may take.
+    max_indirect_draws: u32,
+    /// The state of the pipeline objects are drawn with.
+    pipeline_desc: PipelineDesc,
+    /// The resources the shaders use, which the pipeline layout is made for.
+    shader_interface: PipelineInterface,
+    /// Reports edits to the shader sources, windowed presenters only.
+    shader_watcher: Option<ShaderWatcher>,
+    pub textures: TextureManager,
+    /// What is drawn, read anew every frame.
+    pub scene: Scene,
+    /// The draw list of `scene` at `draw_list_revision`, rebuilt only when
+    /// the scene changes.
+    draw_list: DrawList,
+    draw_list_revision: Option<u64>,
+    /// The mesh of the configured OBJ files, or of the built-in quads.
+    pub model_mesh: MeshId,
+    pub materials: Vec<tobj::Material>,
+    /// Reading back swapchain images is supported by the surface.
+    readback_supported: bool,
+    /// The next frame drawn is captured.
+    capture_requested: bool,
+    swapchain_outdated: bool,
+    present_policy: PresentPolicy,
 }
 
 impl Presenter {
-    pub fn new(info: ApplicationInfo<'static>) -> Presenter {
-
-        // Begin Initialization
-        //
+    /// Opens a window and presents to its surface.
+    pub fn new(info: ApplicationInfo<'static>, config: PresenterConfig) -> Result<Presenter> {
         let window_name = info.application_name()
-            .to_str().unwrap().to_string();
+            .to_str().map_err(|_| "the application name isn't valid UTF-8")?
+            .to_string();
         // Window EventsLoop
         let events_loop = EventsLoop::new();
         let window = vulkan::init_window(window_name,
-                                         &events_loop).unwrap();
+                                         &events_loop)
+            .context("creating window")?;
         let extent = window_extent(&window);
 
+        Self::init(info, config, Some((events_loop, window)), extent)
+    }
+
+    /// Renders into an offscreen image of size `extent` instead of a window.
+    ///
+    /// No display server is needed, so this also works on software drivers
+    /// such as lavapipe. Frames are read back with `read_offscreen_frame`.
+    pub fn headless(info: ApplicationInfo<'static>,
+                    config: PresenterConfig,
+                    extent: Extent2d) -> Result<Presenter> {
+        Self::init(info, config, None, extent)
+    }
+
+    fn init(info: ApplicationInfo<'static>,
+            config: PresenterConfig,
+            window: Option<(EventsLoop, Window)>,
+            extent: Extent2d) -> Result<Presenter> {
+
         // Vulkan instance object
-        let instance = vulkan::init_instance(&info).unwrap();
+        let instance = vulkan::init_instance(&info)
+            .context("creating vulkan instance")?;
 
         // Window Surface
-        let surface = voodoo_winit::create_surface(instance.clone(),
-                                                   &window).unwrap();
+        let (events_loop, window, surface) = match window {
+            Some((events_loop, window)) => {
+                let surface = voodoo_winit::create_surface(instance.clone(),
+                                                           &window)
+                    .context("creating window surface")?;
+                (Some(events_loop), Some(window), Some(surface))
+            },
+            None => (None, None, None),
+        };
 
-        // A physical Device (first detected)
+        // The best scoring physical Device, unless one is selected explicitly
         let physical_device = vulkan::choose_physical_device(&instance,
-                                                             &surface).unwrap();
+                                                             surface.as_ref(),
+                                                             config.physical_device.as_ref())
+            .context("choosing physical device")?;
+        println!("Using Device: {} (type: {:?})",
+                 vulkan::physical_device_name(&physical_device),
+                 physical_device.properties().device_type());
         // virtual Device
-        let device = vulkan::create_device(&surface,
-                                           physical_device).unwrap();
+        let device = vulkan::create_device(surface.as_ref(),
+                                           physical_device)
+            .context("creating logical device")?;
+
+        let allocator = Allocator::new(device.clone());
 
         let command_pool = vulkan::create_command_pool(device.clone(),
-                                                       &surface).unwrap();
+                                                       surface.as_ref())
+            .context("creating command pool")?;
 
-        let descriptor_pool = vulkan::create_descriptor_pool(device.clone()).unwrap();
+        let frames_in_flight = config.frames_in_flight.max(1);
 
-        let descriptor_set_layout = vulkan::create_descriptor_set_layout(device.clone()).unwrap();
+        let vert_shader_code = shader::compile_glsl(VERT_SHADER_SOURCE)
+            .context("loading vertex shader")?;
+        let frag_shader_code = shader::compile_glsl(FRAG_SHADER_SOURCE)
+            .context("loading fragment shader")?;
+
+        // The layouts are derived from what the shaders declare
+        let shader_interface = reflect_shaders(&vert_shader_code, &frag_shader_code)
+            .context("reflecting shaders")?;
+        let bindings = scene_bindings(&shader_interface)?;
+        let set_bindings = shader_interface.set_bindings(0);
+
+        let descriptor_pool = vulkan::create_descriptor_pool(device.clone(),
+                                                             frames_in_flight as u32,
+                                                             &set_bindings)
+            .context("creating descriptor pool")?;
+
+        let descriptor_set_layout = vulkan::create_descriptor_set_layout(device.clone(),
+                                                                         &set_bindings)
+            .context("creating descriptor set layout")?;
 
         let pipeline_layout = vulkan::create_pipeline_layout(device.clone(),
-                                                             Some(&descriptor_set_layout)).unwrap();
-        let texture_sampler = vulkan::create_texture_sampler(device.clone()).unwrap();
+                                                             Some(&descriptor_set_layout),
+                                                             &shader_interface
+                                                                 .push_constant_ranges())
+            .context("creating pipeline layout")?;
 
-        let (depth_image, depth_image_memory, depth_image_view) =
-            vulkan::create_depth_resources(&device,
-                                           &command_pool,
-                                           extent.clone()).unwrap();
+        let pipeline_cache = PipelineCacheFile::open(&device, pipeline_cache::default_path())
+            .context("creating pipeline cache")?;
 
-        let (uniform_buffer, uniform_buffer_memory) =
-            vulkan::create_uniform_buffer(&device,
-                                          &command_pool,
-                                          extent.clone()).unwrap();
+        let mut pipelines = Pipelines::new(&device,
+                                           pipeline_cache.cache(),
+                                           &pipeline_layout,
+                                           vert_shader_code,
+                                           frag_shader_code);
+        let pipeline_desc = PipelineDesc::default();
 
-        // Surface swapchain
-        let swapchain = vulkan::create_swapchain(surface.clone(),
-                                                 device.clone(),
-                                                 Some(extent.clone()),
-                                                 None).unwrap();
+        // Surface swapchain, or an offscreen image when running headless
+        let (swapchain, offscreen) = match surface {
+            Some(ref surface) => {
+                let swapchain = vulkan::create_swapchain(surface.clone(),
+                                                         device.clone(),
+                                                         Some(extent.clone()),
+                                                         config.present_policy,
+                                                         None)
+                    .context("creating swapchain")?;
+                (Some(swapchain), None)
+            },
+            None => {
+                let offscreen = vulkan::create_offscreen_target(&device,
+                                                                &allocator,
+                                                                extent.clone())
+                    .context("creating offscreen render target")?;
+                (None, Some(offscreen))
+            },
+        };
 
         // RESOURCES
 
-        let vert_shader_code = voodoo::util::read_spir_v_file(VERT_SHADER_PATH).unwrap();
-        let frag_shader_code = voodoo::util::read_spir_v_file(FRAG_SHADER_PATH).unwrap();
+        // Headless runs render fixed frames, only windows reload shaders
+        let shader_watcher = if window.is_some() {
+            ShaderWatcher::new(SHADER_DIR)
+                .map_err(|err| eprintln!("not watching {} for changes: {}", SHADER_DIR, err))
+                .ok()
+        } else {
+            None
+        };
 
-        // let (vertices, indices) = load_model(&device, &Path::new(MODEL_PATH)).unwrap();
-        let vertices = VERTICES[..].to_owned();
+        let model = if config.meshes.is_empty() {
+            vulkan::Model::new(VERTICES[..].to_owned(), INDICES[..].to_owned())
+        } else {
+            vulkan::load_models(&config.meshes)
+                .context("loading meshes")?
+        };
 
-        let indices = INDICES[..].to_owned();
+        let mut uploads = UploadManager::new(&device,
+                                             &allocator,
+                                             &command_pool,
+                                             upload::DEFAULT_STAGING_SIZE)
+            .context("creating upload manager")?;
 
-        let (texture_image, texture_image_memory) =
-            vulkan::create_texture_image(&device,
-                                         &command_pool,
-                                         TEXTURE_PATH).unwrap();
+        // The first texture is drawn on anything without a texture of its own
+        let mut textures = TextureManager::new(&device, &allocator);
+        textures.load(&mut uploads, TEXTURE_PATH)?;
 
-        let (vertex_buffer, vertex_buffer_memory) =
-            vulkan::create_vertex_buffer(&device,
-                                         &command_pool,
-                                         &vertices).unwrap();
+        let mut scene = Scene::new();
+        let scene_mesh = upload_model(&device, &allocator, &mut uploads, &mut textures, &model)?;
+        let model_mesh = scene.add_mesh(scene_mesh);
 
-        let (index_buffer, index_buffer_memory) =
-            vulkan::create_index_buffer(&device,
-                                        &command_pool,
-                                        &indices).unwrap();
+        uploads.finish()
+            .context("uploading resources")?;
         // -- End Resources
 
-        let swapchain_components =
-            create_swapchain_components(&device,
-                                        &swapchain,
-                                        &pipeline_layout,
-                                        &depth_image_view,
-                                        &vert_shader_code,
-                                        &frag_shader_code,
-                                        extent.clone());
+        let (output, output_format, output_final_layout) = match swapchain {
+            Some(ref swapchain) => {
+                let output = swapchain_output(swapchain)
+                    .context("creating swapchain image views")?;
+                (output, swapchain.image_format(), ImageLayout::PresentSrcKhr)
+            },
+            None => {
+                let offscreen = offscreen.as_ref().unwrap();
+                (vec![(offscreen.color_image.handle(), offscreen.color_image_view.clone())],
+                 vulkan::OFFSCREEN_COLOR_FORMAT,
+                 ImageLayout::TransferSrcOptimal)
+            },
+        };
 
-        let texture_image_view =
-            vulkan::create_texture_image_view(device.clone(),
-            &texture_image).unwrap();
+        let (mut render_graph, scene_pass) =
+            create_render_graph(&device, output_format, output_final_layout)
+            .context("creating render graph")?;
+        render_graph.resize(&device, &allocator, output, extent.clone())
+            .context("allocating render graph images")?;
 
-        let descriptor_sets =
-            vulkan::create_descriptor_sets(&descriptor_set_layout,
-                                           &descriptor_pool,
-                                           &uniform_buffer,
-                                           &texture_image_view,
-                                           &texture_sampler).unwrap();
-
-        let command_buffers =
-            vulkan::create_command_buffers(&device,
-                                           &command_pool,
-                                           &swapchain_components.render_pass,
-                                           &swapchain_components.graphics_pipeline,
-                                           &swapchain_components.framebuffers,
-                                           &extent,
-                                           &vertex_buffer,
-                                           &index_buffer,
-                                           vertices.len() as u32,
-                                           vertices.len() as u32,
-                                           &pipeline_layout,
-                                           descriptor_sets[0].clone()).unwrap();
+        pipelines.set_render_pass(render_graph.render_pass(scene_pass));
+        pipelines.get(&pipeline_desc)
+            .context("creating graphics pipeline")?;
 
-        let command_buffer_handles: SmallVec<[CommandBufferHandle; 16]> =
-            command_buffers.iter().map(|cb| cb.handle()).collect();
+        let viewports = vec![ViewportRect::full()];
 
+        let texture_image_infos = textures.descriptor_image_infos();
 
-        let image_available_semaphore = Semaphore::new(device.clone(),
-                                                       SemaphoreCreateFlags::empty()).unwrap();
+        let readback_supported = match surface {
+            Some(ref surface) => vulkan::surface_supports_readback(surface,
+                                                                   device.physical_device())
+                .context("querying surface capabilities")?,
+            None => false,
+        };
 
-        let render_finished_semaphore = Semaphore::new(device.clone(),
-                                                       SemaphoreCreateFlags::empty()).unwrap();
+        let culler = match create_culler(&device, &pipeline_cache, frames_in_flight) {
+            Ok(culler) => Some(culler),
+            Err(err) => {
+                println!("GPU culling disabled: {}", err);
+                None
+            },
+        };
 
-        Presenter {
+        // Without multiDrawIndirect each culled draw takes its own indirect
+        // draw call
+        let max_indirect_draws = if device.physical_device().features().multi_draw_indirect() {
+            device.physical_device().properties().limits().max_draw_indirect_count()
+        } else {
+            1
+        };
+
+        let frames = (0..frames_in_flight).map(|_| {
+            Frame::new(&device,
+                       surface.as_ref(),
+                       &allocator,
+                       &command_pool,
+                       &descriptor_set_layout,
+                       &descriptor_pool,
+                       bindings,
+                       &texture_image_infos,
+                       culler.as_ref(),
+                       extent.clone())
+        }).collect::<VdResult<Vec<_>>>()
+            .context("creating frame resources")?;
+
+        println!("Device memory: {}", allocator.stats());
+
+        Ok(Presenter {
             instance,
             window,
             events_loop,
             surface,
+            offscreen,
             device,
+            allocator,
+            uploads,
             descriptor_set_layout,
-            descriptor_sets,
             pipeline_layout,
             command_pool,
             descriptor_pool,
-            texture_sampler,
-            uniform_buffer,
-            uniform_buffer_memory,
-            command_buffer_handles: Some(command_buffer_handles),
-            swapchain: Some(swapchain),
-            swapchain_components: Some(swapchain_components),
-            command_buffers: Some(command_buffers),
-            vert_shader_code,
-            frag_shader_code,
-            texture_image,
-            texture_image_memory,
-            texture_image_view,
-            vertices,
-            indices,
-            vertex_buffer,
-            vertex_buffer_memory,
-            index_buffer,
-            index_buffer_memory,
-            image_available_semaphore,
-            render_finished_semaphore,
-            depth_image,
-            depth_image_memory,
-            depth_image_view,
-        }
+            frames,
+            current_frame: 0,
+            swapchain,
+            render_graph,
+            scene_pass,
+            output_format,
+            viewports,
+            pipelines,
+            pipeline_cache,
+            culler,
+            max_indirect_draws,
+            pipeline_desc,
+            shader_interface,
+            shader_watcher,
+            textures,
+            scene,
+            draw_list: DrawList::default(),
+            draw_list_revision: None,
+            model_mesh,
+            materials: model.materials,
+            readback_supported,
+            capture_requested: false,
+            swapchain_outdated: false,
+            present_policy: config.present_policy,
+        })
     }
     pub fn extent(&self) -> Extent2d {
-        window_extent(&self.window)
+        match self.window {
+            Some(ref window) => window_extent(window),
+            None => self.offscreen.as_ref().unwrap().extent.clone(),
+        }
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.offscreen.is_some()
+    }
+
+    /// Reads back the last frame rendered into the offscreen target.
+    pub fn read_offscreen_frame(&self) -> VdResult<RgbaImage> {
+        let offscreen = match self.offscreen {
+            Some(ref offscreen) => offscreen,
+            None => return Err("windowed frames are captured with request_capture".into()),
+        };
+        let pixels = vulkan::read_offscreen_target(&self.device,
+                                                   &self.allocator,
+                                                   &self.command_pool,
+                                                   offscreen)?;
+        RgbaImage::from_raw(offscreen.extent.width(),
+                            offscreen.extent.height(),
+                            pixels)
+            .ok_or_else(|| "the offscreen target read back fewer pixels than it holds".into())
+    }
+
+    /// Captures the next frame drawn, which `take_captured_frame` returns
+    /// once the GPU has finished it. Windowed presenters only, headless
+    /// frames are read with `read_offscreen_frame`.
+    pub fn request_capture(&mut self) -> VdResult<()> {
+        let format = match self.swapchain {
+            Some(ref swapchain) => swapchain.image_format(),
+            None => return Err("headless frames are read with read_offscreen_frame".into()),
+        };
+        if !self.readback_supported {
+            return Err("the surface doesn't support reading back swapchain images".into());
+        }
+        if vulkan::rgba8_swizzle(format).is_none() {
+            return Err("unable to read back swapchain images of this format".into());
+        }
+        self.capture_requested = true;
+        Ok(())
+    }
+
+    /// Returns the frame captured after `request_capture` as an RGBA image,
+    /// or None if it hasn't been drawn yet. Blocks until the GPU has
+    /// finished the frame.
+    pub fn take_captured_frame(&mut self) -> VdResult<Option<RgbaImage>> {
+        for frame in &mut self.frames {
+            if let Some(image) = frame.take_capture(&self.device)? {
+                return Ok(Some(image));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Draws a frame and returns it as an RGBA image, reading back the
+    /// offscreen target when headless and the swapchain image otherwise.
+    /// Blocks until the GPU has finished the frame; use `request_capture`
+    /// to capture a windowed frame without stalling.
+    pub fn capture_frame(&mut self, ubo: &UniformBufferObject) -> VdResult<RgbaImage> {
+        if self.is_headless() {
+            self.draw_frame(ubo)?;
+            return self.read_offscreen_frame();
+        }
+
+        self.request_capture()?;
+        let drawn = self.draw_frame(ubo);
+        // A frame skipped while paused or recreating the swapchain leaves
+        // the request pending, which would capture a later frame instead
+        self.capture_requested = false;
+        drawn?;
+        match self.take_captured_frame()? {
+            Some(image) => Ok(image),
+            None => Err("no frame was drawn to capture".into()),
+        }
     }
 
     fn cleanup_swapchain(&mut self) {
         self.swapchain = None;
-        self.swapchain_components = None;
-        self.command_buffers = None;
+    }
+
+    /// Notifies the presenter that the window was resized. The swapchain is
+    /// recreated before the next frame is drawn.
+    pub fn resize(&mut self) {
+        self.swapchain_outdated = true;
+    }
+
+    pub fn present_policy(&self) -> PresentPolicy {
+        self.present_policy
+    }
+
+    /// Switches to `policy`, recreating the swapchain if it changed.
+    pub fn set_present_policy(&mut self, policy: PresentPolicy) -> VdResult<()> {
+        if policy == self.present_policy {
+            return Ok(());
+        }
+        self.present_policy = policy;
+        if self.is_headless() {
+            return Ok(());
+        }
+        if self.is_paused() {
+            self.swapchain_outdated = true;
+            return Ok(());
+        }
+        self.recreate_swapchain()
+    }
+
+    /// Returns true while the window is minimized. Nothing can be presented
+    /// to a surface with a zero-sized extent.
+    pub fn is_paused(&self) -> bool {
+        let extent = self.extent();
+        extent.width() == 0 || extent.height() == 0
     }
 
     fn recreate_swapchain(&mut self) -> VdResult<()> {
         self.device.wait_idle();
+        self.swapchain_outdated = false;
 
-        let extent = self.extent();
-        let swapchain = vulkan::create_swapchain(self.surface.clone(),
+        let swapchain = vulkan::create_swapchain(self.surface.as_ref().unwrap().clone(),
                                                  self.device.clone(),
-                                                 Some(extent.clone()),
-                                                 self.swapchain.as_ref().take()).unwrap();
+                                                 Some(self.extent()),
+                                                 self.present_policy,
+                                                 self.swapchain.as_ref().take())?;
+        // The surface may dictate an extent differing from the window size
+        let extent = swapchain.extent().clone();
 
         self.cleanup_swapchain();
 
-        let swapchain_components =
-            create_swapchain_components(&self.device,
-                                        &swapchain,
-                                        &self.pipeline_layout,
-                                        &self.depth_image_view,
-                                        &self.vert_shader_code,
-                                        &self.frag_shader_code,
-                                        extent.clone());
-
-
-        let command_buffers =
-            vulkan::create_command_buffers(&self.device,
-                                           &self.command_pool,
-                                           &swapchain_components.render_pass,
-                                           &swapchain_components.graphics_pipeline,
-                                           &swapchain_components.framebuffers,
-                                           &extent,
-                                           &self.vertex_buffer,
-                                           &self.index_buffer,
-                                           self.vertices.len() as u32,
-                                           self.indices.len() as u32,
-                                           &self.pipeline_layout,
-                                           self.descriptor_sets[0].clone()).unwrap();
-
-        let command_buffer_handles = command_buffers.iter().map(|cb| cb.handle()).collect();
+        // A resize keeps the image format, and with it the render passes and
+        // the pipelines
+        if swapchain.image_format() != self.output_format {
+            let (render_graph, scene_pass) =
+                create_render_graph(&self.device,
+                                    swapchain.image_format(),
+                                    ImageLayout::PresentSrcKhr)?;
+            self.render_graph = render_graph;
+            self.scene_pass = scene_pass;
+            self.output_format = swapchain.image_format();
+            self.pipelines.set_render_pass(self.render_graph.render_pass(self.scene_pass));
+        }
+
+        self.render_graph.resize(&self.device,
+                                 &self.allocator,
+                                 swapchain_output(&swapchain)?,
+                                 extent.clone())?;
 
         self.swapchain = Some(swapchain);
-        self.swapchain_components = Some(swapchain_components);
-        self.command_buffers = Some(command_buffers);
-        self.command_buffer_handles = Some(command_buffer_handles);
 
         Ok(())
     }
 
-    pub fn draw_frame(&mut self) -> VdResult<()> {
+    /// Recompiles the shaders and rebuilds the graphics pipeline if a shader
+    /// source changed. Compile errors are reported and the last working
+    /// pipeline is kept.
+    fn reload_changed_shaders(&mut self) -> VdResult<()> {
+        let changed = match self.shader_watcher {
+            Some(ref watcher) => watcher.poll_changed(),
+            None => Vec::new(),
+        };
+        let is_changed = |source: &str| {
+            changed.iter().any(|path| path.file_name() == Path::new(source).file_name())
+        };
+        if is_changed(CULL_SHADER_SOURCE) {
+            self.reload_culler()?;
+        }
+        if !is_changed(VERT_SHADER_SOURCE) && !is_changed(FRAG_SHADER_SOURCE) {
+            return Ok(());
+        }
+
+        let compiled = shader::compile_glsl(VERT_SHADER_SOURCE).and_then(|vert| {
+            shader::compile_glsl(FRAG_SHADER_SOURCE).map(|frag| (vert, frag))
+        }).and_then(|(vert, frag)| {
+            // The pipeline layout stays, so the shaders have to fit it
+            if reflect_shaders(&vert, &frag)? != self.shader_interface {
+                return Err("the shaders' descriptors or push constants changed, \
+                    restart to apply".into());
+            }
+            Ok((vert, frag))
+        });
+        let (vert_shader_code, frag_shader_code) = match compiled {
+            Ok(code) => code,
+            Err(err) => {
+                eprintln!("shader reload failed, keeping the last pipeline: {}", err);
+                return Ok(());
+            },
+        };
+
+        if let Err(err) = self.pipelines.set_shaders(vert_shader_code, frag_shader_code) {
+            eprintln!("shader reload failed, keeping the last pipeline: {}", err);
+            return Ok(());
+        }
+        println!("Reloaded shaders");
+        Ok(())
+    }
+
+    /// Recompiles the culling shader and replaces the culler and the cull
+    /// buffers of all frames. Compile errors are reported and the last
+    /// culler stays in use.
+    fn reload_culler(&mut self) -> VdResult<()> {
+        let culler = match create_culler(&self.device, &self.pipeline_cache, self.frames.len()) {
+            Ok(culler) => culler,
+            Err(err) => {
+                eprintln!("culling shader reload failed, keeping the last pipeline: {}", err);
+                return Ok(());
+            },
+        };
+        // The frames in flight may still cull with the old pipeline
+        self.device.wait_idle();
+        for frame in &mut self.frames {
+            frame.set_culler(&culler)?;
+        }
+        self.culler = Some(culler);
+        println!("Reloaded culling shader");
+        Ok(())
+    }
+
+    pub fn pipeline_desc(&self) -> PipelineDesc {
+        self.pipeline_desc
+    }
+
+    /// Draws all objects with a pipeline of the state `desc` from the next
+    /// frame on. Creates the pipeline right away, so an unsupported state
+    /// is reported here.
+    pub fn set_pipeline_desc(&mut self, desc: PipelineDesc) -> VdResult<()> {
+        self.pipelines.get(&desc)?;
+        self.pipeline_desc = desc;
+        for object in self.scene.objects_mut() {
+            object.pipeline = desc;
+        }
+        Ok(())
+    }
+
+    /// Uploads `model` and adds it to the scene as a mesh objects can draw.
+    /// Blocks until the upload has finished.
+    pub fn add_mesh(&mut self, model: &vulkan::Model) -> Result<MeshId> {
+        let texture_count = self.textures.len();
+        let scene_mesh = upload_model(&self.device, &self.allocator, &mut self.uploads,
+                                      &mut self.textures, model)?;
+        self.uploads.finish()
+            .context("uploading mesh")?;
+
+        // The frames in flight sample the texture array, so wait before
+        // pointing it at the new textures
+        if self.textures.len() != texture_count {
+            self.device.wait_idle();
+            let image_infos = self.textures.descriptor_image_infos();
+            for frame in &self.frames {
+                frame.set_textures(&self.descriptor_pool, &image_infos);
+            }
+        }
+        Ok(self.scene.add_mesh(scene_mesh))
+    }
+
+    /// Adds an object drawing `mesh` with the current pipeline state.
+    pub fn add_object(&mut self, mesh: MeshId) -> ObjectId {
+        self.scene.add_object(Object::new(mesh, self.pipeline_desc))
+    }
+
+    /// The size in pixels of the first viewport, which the projection
+    /// should match.
+    pub fn viewport_extent(&self) -> Extent2d {
+        self.viewports[0].extent(&self.extent())
+    }
+
+    pub fn viewports(&self) -> &[ViewportRect] {
+        &self.viewports
+    }
+
+    /// Draws the scene into each of `viewports` from now on, e.g.
+    /// `ViewportRect::columns(2)` for split screen.
+    pub fn set_viewports(&mut self, viewports: Vec<ViewportRect>) -> VdResult<()> {
+        if viewports.is_empty() {
+            return Err("at least one viewport is needed".into());
+        }
+        self.viewports = viewports;
+        Ok(())
+    }
+
+    /// Records the command buffer of the `frame_index`th frame, drawing the
+    /// scene as it is now into the `output_index`th output image as seen
+    /// through `ubo`.
+    fn record_frame(&mut self,
+                    frame_index: usize,
+                    output_index: usize,
+                    ubo: &UniformBufferObject) -> VdResult<()> {
+        if self.draw_list_revision != Some(self.scene.revision()) {
+            self.draw_list = self.scene.draw_list();
+            self.draw_list_revision = Some(self.scene.revision());
+        }
+        let pipelines = self.draw_list.pipelines.iter()
+            .map(|desc| self.pipelines.get(desc))
+            .collect::<VdResult<Vec<_>>>()?;
+        self.frames[frame_index].write_scene(&self.device,
+                                             &self.allocator,
+                                             &self.descriptor_pool,
+                                             self.culler.as_ref(),
+                                             &self.scene,
+                                             &self.draw_list)?;
+        let view_proj = Matrix4::from(ubo.proj) * Matrix4::from(ubo.view);
+        self.frames[frame_index].record(self.culler.as_ref(),
+                                        culling::frustum_planes(&view_proj),
+                                        self.max_indirect_draws,
+                                        &self.render_graph,
+                                        self.scene_pass,
+                                        output_index,
+                                        &self.draw_list,
+                                        &pipelines,
+                                        &self.scene,
+                                        &self.viewports,
+                                        &self.pipeline_layout)
+    }
+
+    /// Draws a frame showing `ubo`.
+    ///
+    /// Waits only until the frame in flight which is reused has finished on
+    /// the GPU, so recording the next frame overlaps with rendering this one.
+    pub fn draw_frame(&mut self, ubo: &UniformBufferObject) -> VdResult<()> {
+        self.reload_changed_shaders()?;
+        if self.is_headless() {
+            return self.draw_offscreen_frame(ubo);
+        }
+        if self.is_paused() {
+            return Ok(());
+        }
+        if self.swapchain_outdated {
+            self.recreate_swapchain()?;
+        }
+
+        let frame_index = self.current_frame;
+        self.frames[frame_index].wait(&self.device)?;
+
         let acquire_result =
             self.swapchain.as_ref().unwrap()
                           .acquire_next_image_khr(u64::max_value(),
-                                                  Some(&self.image_available_semaphore),
+                                                  Some(&self.frames[frame_index]
+                                                            .image_available_semaphore),
                                                   None);
         let image_index = match acquire_result {
             Ok(idx) => idx,
-            Err(res) => {
-                if let ErrorKind::ApiCall(call_res, _fn_name) = res.kind {
-                    if call_res == CallResult::ErrorOutOfDateKhr {
-                        self.recreate_swapchain().unwrap();
-                        return Ok(());
-                    } else {
-                        panic!("Unable to present swap chain image");
-                    }
-                } else {
-                    panic!("Unable to present swap chain image");
-                }
-            }
+            Err(ref res) if is_out_of_date(res) => {
+                self.recreate_swapchain()?;
+                return Ok(());
+            },
+            Err(res) => return Err(res),
         };
 
-        let wait_semaphores = [self.image_available_semaphore.handle()];
+        if self.capture_requested {
+            self.capture_requested = false;
+            let swapchain = self.swapchain.as_ref().unwrap();
+            self.frames[frame_index].capture(&self.device,
+                                             &self.allocator,
+                                             swapchain.images()[image_index as usize].handle(),
+                                             swapchain.extent().clone(),
+                                             swapchain.image_format())?;
+        } else {
+            self.frames[frame_index].capture = None;
+        }
+        self.record_frame(frame_index, image_index as usize, ubo)?;
+
+        let frame = &self.frames[frame_index];
+        frame.write_uniform_buffer(ubo)?;
+
+        let wait_semaphores = [frame.image_available_semaphore.handle()];
         let wait_stages = PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
-        let signal_semaphores = [self.render_finished_semaphore.handle()];
-        let command_buffer_handles =
-            [self.command_buffer_handles.as_ref().unwrap()
-                                        .get(image_index as usize).unwrap()
-                                        .clone()];
+        let signal_semaphores = [frame.render_finished_semaphore.handle()];
+        let command_buffer_handles = [frame.command_buffer.handle()];
 
         let submit_info =
             SubmitInfo::builder()
@@ -383,8 +1270,10 @@ impl Presenter {
                 .signal_semaphores(&signal_semaphores[..])
                 .build();
 
-        let queue = self.device.queue(0).unwrap();
-        queue.submit(&[submit_info], None).unwrap();
+        self.device.reset_fences(&[frame.in_flight_fence.handle()])?;
+
+        let queue = self.device.queue(0).ok_or("the device has no queue")?;
+        queue.submit(&[submit_info], Some(frame.in_flight_fence.handle()))?;
 
         let swapchains = [self.swapchain.as_ref().unwrap().handle()];
         let image_indices = [image_index];
@@ -396,8 +1285,39 @@ impl Presenter {
                 .image_indices(&image_indices)
                 .build();
 
-        queue.present_khr(&present_info).unwrap();
-        queue.wait_idle();
+        self.current_frame = (frame_index + 1) % self.frames.len();
+
+        match queue.present_khr(&present_info) {
+            Ok(_) => (),
+            Err(ref res) if is_out_of_date(res) => self.recreate_swapchain()?,
+            Err(res) => return Err(res),
+        }
+
+        Ok(())
+    }
+
+    /// Renders one frame into the offscreen target and waits for it to
+    /// finish, so it can be read back right away.
+    fn draw_offscreen_frame(&mut self, ubo: &UniformBufferObject) -> VdResult<()> {
+        let frame_index = self.current_frame;
+        self.frames[frame_index].wait(&self.device)?;
+        self.record_frame(frame_index, 0, ubo)?;
+
+        let frame = &self.frames[frame_index];
+        frame.write_uniform_buffer(ubo)?;
+
+        let command_buffer_handles = [frame.command_buffer.handle()];
+
+        let submit_info =
+            SubmitInfo::builder()
+                .command_buffers(&command_buffer_handles[..])
+                .build();
+
+        self.device.reset_fences(&[frame.in_flight_fence.handle()])?;
+
+        let queue = self.device.queue(0).ok_or("the device has no queue")?;
+        queue.submit(&[submit_info], Some(frame.in_flight_fence.handle()))?;
+        frame.wait(&self.device)?;
 
         Ok(())
     }
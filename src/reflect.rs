@@ -0,0 +1,682 @@
+/*
+ * reflect.rs
+ * Reads the interface of SPIR-V shader modules: descriptor
+ * bindings, push constant blocks and vertex inputs, so the
+ * pipeline layout can be derived from the shaders.
+ */
+use std::collections::HashMap;
+
+use voodoo::{DescriptorType, ShaderStageFlags, Format, VertexInputAttributeDescription,
+            PushConstantRange};
+
+use error::Result;
+
+const SPIR_V_MAGIC: u32 = 0x0723_0203;
+const HEADER_WORDS: usize = 5;
+
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_SPEC_CONSTANT: u32 = 50;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+/// A descriptor binding used by one or more shader stages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: DescriptorType,
+    /// Number of array elements, 1 for bindings which aren't arrays.
+    pub count: u32,
+    pub stage_flags: ShaderStageFlags,
+}
+
+/// A push constant block as `(offset, size)` in bytes.
+pub type PushConstantBlock = (u32, u32);
+
+/// A vertex shader input and the format it expects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: Format,
+}
+
+/// The interface of a single shader module.
+#[derive(Debug, Clone)]
+pub struct ShaderReflection {
+    pub stage: ShaderStageFlags,
+    pub bindings: Vec<DescriptorBinding>,
+    pub push_constants: Option<PushConstantBlock>,
+    /// Inputs of vertex shaders, empty for other stages.
+    pub inputs: Vec<VertexInput>,
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Bool,
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray,
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+/// The instructions of a module reflection needs.
+#[derive(Default)]
+struct Module {
+    stage: Option<ShaderStageFlags>,
+    types: HashMap<u32, Type>,
+    /// Array length constant ids and their values.
+    constants: HashMap<u32, u32>,
+    /// `(id, pointer type, storage class)` of each global variable.
+    variables: Vec<(u32, u32, u32)>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+}
+
+impl Module {
+    fn parse(code: &[u32]) -> Result<Module> {
+        if code.len() < HEADER_WORDS || code[0] != SPIR_V_MAGIC {
+            return Err("not a SPIR-V module".into());
+        }
+
+        let mut module = Module::default();
+        let mut position = HEADER_WORDS;
+        while position < code.len() {
+            let word_count = (code[position] >> 16) as usize;
+            let opcode = code[position] & 0xffff;
+            if word_count == 0 || position + word_count > code.len() {
+                return Err(format!("malformed SPIR-V instruction at word {}", position).into());
+            }
+            let operands = &code[position + 1..position + word_count];
+            module.parse_instruction(opcode, operands)?;
+            position += word_count;
+        }
+        Ok(module)
+    }
+
+    fn parse_instruction(&mut self, opcode: u32, operands: &[u32]) -> Result<()> {
+        if operands.is_empty() {
+            return Ok(());
+        }
+        // Missing operands of malformed instructions read as zero
+        let operand = |i: usize| operands.get(i).cloned().unwrap_or(0);
+        match opcode {
+            OP_ENTRY_POINT => {
+                if self.stage.is_some() {
+                    return Err("modules with several entry points are not supported".into());
+                }
+                self.stage = Some(match operands[0] {
+                    0 => ShaderStageFlags::VERTEX,
+                    1 => ShaderStageFlags::TESSELLATION_CONTROL,
+                    2 => ShaderStageFlags::TESSELLATION_EVALUATION,
+                    3 => ShaderStageFlags::GEOMETRY,
+                    4 => ShaderStageFlags::FRAGMENT,
+                    5 => ShaderStageFlags::COMPUTE,
+                    model => return Err(format!("unsupported execution model {}", model).into()),
+                });
+            },
+            OP_TYPE_BOOL => {
+                self.types.insert(operands[0], Type::Bool);
+            },
+            OP_TYPE_INT => {
+                self.types.insert(operands[0], Type::Int { width: operand(1), signed: operand(2) != 0 });
+            },
+            OP_TYPE_FLOAT => {
+                self.types.insert(operands[0], Type::Float { width: operand(1) });
+            },
+            OP_TYPE_VECTOR => {
+                self.types.insert(operands[0], Type::Vector { component: operand(1), count: operand(2) });
+            },
+            OP_TYPE_MATRIX => {
+                self.types.insert(operands[0], Type::Matrix { column: operand(1), count: operand(2) });
+            },
+            OP_TYPE_IMAGE => {
+                self.types.insert(operands[0], Type::Image { dim: operand(2), sampled: operand(6) });
+            },
+            OP_TYPE_SAMPLER => {
+                self.types.insert(operands[0], Type::Sampler);
+            },
+            OP_TYPE_SAMPLED_IMAGE => {
+                self.types.insert(operands[0], Type::SampledImage);
+            },
+            OP_TYPE_ARRAY => {
+                let length = *self.constants.get(&operand(2))
+                    .ok_or("array length is not a constant")?;
+                self.types.insert(operands[0], Type::Array { element: operand(1), length });
+            },
+            OP_TYPE_RUNTIME_ARRAY => {
+                self.types.insert(operands[0], Type::RuntimeArray);
+            },
+            OP_TYPE_STRUCT => {
+                self.types.insert(operands[0], Type::Struct { members: operands[1..].to_vec() });
+            },
+            OP_TYPE_POINTER => {
+                self.types.insert(operands[0], Type::Pointer { pointee: operand(2) });
+            },
+            // Operands are the result type, the result id and the value
+            OP_CONSTANT | OP_SPEC_CONSTANT => {
+                self.constants.insert(operand(1), operand(2));
+            },
+            OP_VARIABLE => {
+                self.variables.push((operand(1), operands[0], operand(2)));
+            },
+            OP_DECORATE => {
+                self.decorations.insert((operands[0], operand(1)), operand(2));
+            },
+            OP_MEMBER_DECORATE => {
+                self.member_decorations.insert((operands[0], operand(1), operand(2)), operand(3));
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn get_type(&self, id: u32) -> Result<&Type> {
+        self.types.get(&id).ok_or_else(|| format!("unknown SPIR-V type %{}", id).into())
+    }
+
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).cloned()
+    }
+
+    /// Size in bytes of values of type `id` in a buffer block.
+    fn size_of(&self, id: u32) -> Result<u32> {
+        Ok(match *self.get_type(id)? {
+            Type::Bool => 4,
+            Type::Int { width, .. } | Type::Float { width } => width / 8,
+            Type::Vector { component, count } => self.size_of(component)? * count,
+            Type::Matrix { column, count } => self.size_of(column)? * count,
+            Type::Array { element, length } => {
+                let stride = match self.decoration(id, DECORATION_ARRAY_STRIDE) {
+                    Some(stride) => stride,
+                    None => self.size_of(element)?,
+                };
+                stride * length
+            },
+            Type::Struct { ref members } => {
+                let mut size = 0;
+                for (member, &member_type) in members.iter().enumerate() {
+                    let (offset, member_size) = self.member_layout(id, member as u32, member_type)?;
+                    size = size.max(offset + member_size);
+                }
+                size
+            },
+            ref ty => return Err(format!("{:?} has no size in a buffer", ty).into()),
+        })
+    }
+
+    /// The offset and size of member `member` of struct `id`.
+    fn member_layout(&self, id: u32, member: u32, member_type: u32) -> Result<(u32, u32)> {
+        let offset = self.member_decorations.get(&(id, member, DECORATION_OFFSET)).cloned()
+            .ok_or_else(|| format!("member {} of struct %{} has no offset", member, id))?;
+        // Matrix columns may be padded, which only the member records
+        let size = match *self.get_type(member_type)? {
+            Type::Matrix { count, .. } => {
+                match self.member_decorations.get(&(id, member, DECORATION_MATRIX_STRIDE)) {
+                    Some(&stride) => stride * count,
+                    None => self.size_of(member_type)?,
+                }
+            },
+            _ => self.size_of(member_type)?,
+        };
+        Ok((offset, size))
+    }
+
+    /// The extent of the push constant block `id` as `(offset, size)`.
+    fn push_constant_block(&self, id: u32) -> Result<PushConstantBlock> {
+        let members = match *self.get_type(id)? {
+            Type::Struct { ref members } => members,
+            _ => return Err("push constant block is not a struct".into()),
+        };
+        let mut start = u32::max_value();
+        let mut end = 0;
+        for (member, &member_type) in members.iter().enumerate() {
+            let (offset, size) = self.member_layout(id, member as u32, member_type)?;
+            start = start.min(offset);
+            end = end.max(offset + size);
+        }
+        if start > end {
+            return Err("empty push constant block".into());
+        }
+        Ok((start, end - start))
+    }
+
+    /// The descriptor type and array length of a resource of type `id`.
+    fn descriptor(&self, id: u32, storage_class: u32) -> Result<(DescriptorType, u32)> {
+        let (element, count) = match *self.get_type(id)? {
+            Type::Array { element, length } => (element, length),
+            Type::RuntimeArray => return Err("unsized descriptor arrays are not supported".into()),
+            _ => (id, 1),
+        };
+
+        let descriptor_type = match (storage_class, self.get_type(element)?) {
+            (STORAGE_UNIFORM, &Type::Struct { .. }) => {
+                if self.decoration(element, DECORATION_BUFFER_BLOCK).is_some() {
+                    DescriptorType::StorageBuffer
+                } else {
+                    DescriptorType::UniformBuffer
+                }
+            },
+            (STORAGE_STORAGE_BUFFER, &Type::Struct { .. }) => DescriptorType::StorageBuffer,
+            (STORAGE_UNIFORM_CONSTANT, &Type::SampledImage) => DescriptorType::CombinedImageSampler,
+            (STORAGE_UNIFORM_CONSTANT, &Type::Sampler) => DescriptorType::Sampler,
+            (STORAGE_UNIFORM_CONSTANT, &Type::Image { dim, sampled }) => {
+                match (dim, sampled) {
+                    (DIM_SUBPASS_DATA, _) => DescriptorType::InputAttachment,
+                    (DIM_BUFFER, 2) => DescriptorType::StorageTexelBuffer,
+                    (DIM_BUFFER, _) => DescriptorType::UniformTexelBuffer,
+                    (_, 2) => DescriptorType::StorageImage,
+                    _ => DescriptorType::SampledImage,
+                }
+            },
+            (_, ty) => return Err(format!("unsupported resource type {:?}", ty).into()),
+        };
+        Ok((descriptor_type, count))
+    }
+
+    /// The vertex attribute format matching an input of type `id`.
+    fn input_format(&self, id: u32) -> Result<Format> {
+        let (component, count) = match *self.get_type(id)? {
+            Type::Vector { component, count } => (component, count),
+            _ => (id, 1),
+        };
+        let format = match (self.get_type(component)?, count) {
+            (&Type::Float { width: 32 }, 1) => Format::R32Sfloat,
+            (&Type::Float { width: 32 }, 2) => Format::R32G32Sfloat,
+            (&Type::Float { width: 32 }, 3) => Format::R32G32B32Sfloat,
+            (&Type::Float { width: 32 }, 4) => Format::R32G32B32A32Sfloat,
+            (&Type::Int { width: 32, signed: true }, 1) => Format::R32Sint,
+            (&Type::Int { width: 32, signed: true }, 2) => Format::R32G32Sint,
+            (&Type::Int { width: 32, signed: true }, 3) => Format::R32G32B32Sint,
+            (&Type::Int { width: 32, signed: true }, 4) => Format::R32G32B32A32Sint,
+            (&Type::Int { width: 32, signed: false }, 1) => Format::R32Uint,
+            (&Type::Int { width: 32, signed: false }, 2) => Format::R32G32Uint,
+            (&Type::Int { width: 32, signed: false }, 3) => Format::R32G32B32Uint,
+            (&Type::Int { width: 32, signed: false }, 4) => Format::R32G32B32A32Uint,
+            (ty, _) => return Err(format!("unsupported vertex input type {:?}", ty).into()),
+        };
+        Ok(format)
+    }
+}
+
+/// Reads the interface of the SPIR-V module `code`.
+pub fn reflect(code: &[u32]) -> Result<ShaderReflection> {
+    let module = Module::parse(code)?;
+    let stage = module.stage.ok_or("SPIR-V module has no entry point")?;
+
+    let mut reflection = ShaderReflection {
+        stage,
+        bindings: Vec::new(),
+        push_constants: None,
+        inputs: Vec::new(),
+    };
+
+    for &(id, pointer_type, storage_class) in &module.variables {
+        let pointee = match *module.get_type(pointer_type)? {
+            Type::Pointer { pointee } => pointee,
+            _ => return Err(format!("variable %{} is not a pointer", id).into()),
+        };
+
+        match storage_class {
+            STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                let (descriptor_type, count) = module.descriptor(pointee, storage_class)?;
+                reflection.bindings.push(DescriptorBinding {
+                    set: module.decoration(id, DECORATION_DESCRIPTOR_SET).unwrap_or(0),
+                    binding: module.decoration(id, DECORATION_BINDING)
+                        .ok_or_else(|| format!("resource %{} has no binding", id))?,
+                    descriptor_type,
+                    count,
+                    stage_flags: stage,
+                });
+            },
+            STORAGE_PUSH_CONSTANT => {
+                reflection.push_constants = Some(module.push_constant_block(pointee)?);
+            },
+            STORAGE_INPUT if stage == ShaderStageFlags::VERTEX => {
+                // Built-ins such as gl_VertexIndex aren't vertex attributes
+                if module.decoration(id, DECORATION_BUILT_IN).is_some() {
+                    continue;
+                }
+                let location = module.decoration(id, DECORATION_LOCATION)
+                    .ok_or_else(|| format!("vertex input %{} has no location", id))?;
+                // Matrices take a location per column
+                let (column_type, columns) = match *module.get_type(pointee)? {
+                    Type::Matrix { column, count } => (column, count),
+                    _ => (pointee, 1),
+                };
+                let format = module.input_format(column_type)?;
+                for column in 0..columns {
+                    reflection.inputs.push(VertexInput {
+                        location: location + column,
+                        format,
+                    });
+                }
+            },
+            _ => {},
+        }
+    }
+
+    reflection.bindings.sort_by_key(|binding| (binding.set, binding.binding));
+    reflection.inputs.sort_by_key(|input| input.location);
+    Ok(reflection)
+}
+
+/// The resources used by all shader stages of a pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineInterface {
+    /// Bindings of all descriptor sets, sorted by set and binding.
+    pub bindings: Vec<DescriptorBinding>,
+    /// The push constant block of each stage using one.
+    pub push_constants: Vec<(ShaderStageFlags, PushConstantBlock)>,
+}
+
+impl PipelineInterface {
+    /// Merges the interfaces of `stages`. Bindings shared by several stages
+    /// have to agree on their type and array length.
+    pub fn new(stages: &[&ShaderReflection]) -> Result<PipelineInterface> {
+        let mut bindings: Vec<DescriptorBinding> = Vec::new();
+        let mut push_constants = Vec::new();
+
+        for stage in stages {
+            for binding in &stage.bindings {
+                let existing = bindings.iter_mut()
+                    .find(|existing| existing.set == binding.set &&
+                        existing.binding == binding.binding);
+                match existing {
+                    Some(existing) => {
+                        if existing.descriptor_type != binding.descriptor_type ||
+                            existing.count != binding.count {
+                            return Err(format!("stages disagree on set {} binding {}: \
+                                {} {:?} and {} {:?}", binding.set, binding.binding,
+                                existing.count, existing.descriptor_type,
+                                binding.count, binding.descriptor_type).into());
+                        }
+                        existing.stage_flags |= binding.stage_flags;
+                    },
+                    None => bindings.push(binding.clone()),
+                }
+            }
+            if let Some(block) = stage.push_constants {
+                push_constants.push((stage.stage, block));
+            }
+        }
+
+        bindings.sort_by_key(|binding| (binding.set, binding.binding));
+        Ok(PipelineInterface { bindings, push_constants })
+    }
+
+    /// The bindings of descriptor set `set`.
+    pub fn set_bindings(&self, set: u32) -> Vec<DescriptorBinding> {
+        self.bindings.iter().filter(|binding| binding.set == set).cloned().collect()
+    }
+
+    pub fn push_constant_ranges(&self) -> Vec<PushConstantRange> {
+        self.push_constants.iter().map(|&(stage_flags, (offset, size))| {
+            PushConstantRange::builder()
+                .stage_flags(stage_flags)
+                .offset(offset)
+                .size(size)
+                .build()
+        }).collect()
+    }
+
+    /// Finds the binding `binding` of descriptor set `set`.
+    pub fn binding(&self, set: u32, binding: u32) -> Option<&DescriptorBinding> {
+        self.bindings.iter().find(|b| b.set == set && b.binding == binding)
+    }
+}
+
+/// Checks that `attributes` feed every input of the vertex shader
+/// `shader` with the format it expects.
+pub fn validate_vertex_input(shader: &ShaderReflection,
+                             attributes: &[VertexInputAttributeDescription]) -> Result<()> {
+    for input in &shader.inputs {
+        let attribute = attributes.iter()
+            .find(|attribute| attribute.location() == input.location)
+            .ok_or_else(|| format!("vertex shader input at location {} has no \
+                matching vertex attribute", input.location))?;
+        if attribute.format() != input.format {
+            return Err(format!("vertex attribute at location {} is {:?}, the vertex \
+                shader expects {:?}", input.location, attribute.format(), input.format).into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OP_TYPE_VOID: u32 = 19;
+    const EXECUTION_MODEL_VERTEX: u32 = 0;
+    const EXECUTION_MODEL_FRAGMENT: u32 = 4;
+    const DECORATION_BLOCK: u32 = 2;
+    const BUILT_IN_VERTEX_INDEX: u32 = 42;
+    const DIM_2D: u32 = 1;
+
+    /// Assembles a module from `(opcode, operands)` instructions.
+    fn module(instructions: &[(u32, &[u32])]) -> Vec<u32> {
+        let mut code = vec![SPIR_V_MAGIC, 0x0001_0000, 0, 100, 0];
+        for &(opcode, operands) in instructions {
+            code.push(((operands.len() as u32 + 1) << 16) | opcode);
+            code.extend_from_slice(operands);
+        }
+        code
+    }
+
+    /// `layout(location = 0) in vec3; layout(location = 1) in mat4;
+    /// layout(location = 5) in uint;` with gl_VertexIndex, a uniform block
+    /// at binding 0, a buffer block at binding 2 and a vec4 push constant at
+    /// offset 16.
+    fn vertex_shader() -> Vec<u32> {
+        module(&[
+            (OP_ENTRY_POINT, &[EXECUTION_MODEL_VERTEX, 1, 0x6e69_616d, 0]),
+            (OP_DECORATE, &[10, DECORATION_LOCATION, 0]),
+            (OP_DECORATE, &[11, DECORATION_LOCATION, 1]),
+            (OP_DECORATE, &[12, DECORATION_LOCATION, 5]),
+            (OP_DECORATE, &[13, DECORATION_BUILT_IN, BUILT_IN_VERTEX_INDEX]),
+            (OP_MEMBER_DECORATE, &[30, 0, DECORATION_OFFSET, 0]),
+            (OP_MEMBER_DECORATE, &[30, 0, DECORATION_MATRIX_STRIDE, 16]),
+            (OP_MEMBER_DECORATE, &[30, 1, DECORATION_OFFSET, 64]),
+            (OP_DECORATE, &[30, DECORATION_BLOCK]),
+            (OP_DECORATE, &[31, DECORATION_DESCRIPTOR_SET, 0]),
+            (OP_DECORATE, &[31, DECORATION_BINDING, 0]),
+            (OP_MEMBER_DECORATE, &[40, 0, DECORATION_OFFSET, 0]),
+            (OP_DECORATE, &[40, DECORATION_BUFFER_BLOCK]),
+            (OP_DECORATE, &[41, DECORATION_DESCRIPTOR_SET, 0]),
+            (OP_DECORATE, &[41, DECORATION_BINDING, 2]),
+            (OP_MEMBER_DECORATE, &[50, 0, DECORATION_OFFSET, 16]),
+            (OP_DECORATE, &[50, DECORATION_BLOCK]),
+            (OP_TYPE_VOID, &[2]),
+            (OP_TYPE_FLOAT, &[3, 32]),
+            (OP_TYPE_INT, &[4, 32, 0]),
+            (OP_TYPE_VECTOR, &[5, 3, 3]),
+            (OP_TYPE_VECTOR, &[6, 3, 4]),
+            (OP_TYPE_MATRIX, &[7, 6, 4]),
+            (OP_TYPE_POINTER, &[20, STORAGE_INPUT, 5]),
+            (OP_TYPE_POINTER, &[21, STORAGE_INPUT, 7]),
+            (OP_TYPE_POINTER, &[22, STORAGE_INPUT, 4]),
+            (OP_VARIABLE, &[20, 10, STORAGE_INPUT]),
+            (OP_VARIABLE, &[21, 11, STORAGE_INPUT]),
+            (OP_VARIABLE, &[22, 12, STORAGE_INPUT]),
+            (OP_VARIABLE, &[22, 13, STORAGE_INPUT]),
+            (OP_TYPE_STRUCT, &[30, 7, 6]),
+            (OP_TYPE_POINTER, &[32, STORAGE_UNIFORM, 30]),
+            (OP_VARIABLE, &[32, 31, STORAGE_UNIFORM]),
+            (OP_TYPE_STRUCT, &[40, 6]),
+            (OP_TYPE_POINTER, &[42, STORAGE_UNIFORM, 40]),
+            (OP_VARIABLE, &[42, 41, STORAGE_UNIFORM]),
+            (OP_TYPE_STRUCT, &[50, 6]),
+            (OP_TYPE_POINTER, &[51, STORAGE_PUSH_CONSTANT, 50]),
+            (OP_VARIABLE, &[51, 52, STORAGE_PUSH_CONSTANT]),
+        ])
+    }
+
+    /// A fragment shader sampling an array of `texture_count` textures at
+    /// binding 1, with a uniform block at binding 0 of set `uniform_set`.
+    fn fragment_shader(texture_count: u32, uniform_set: u32) -> Vec<u32> {
+        module(&[
+            (OP_ENTRY_POINT, &[EXECUTION_MODEL_FRAGMENT, 1, 0x6e69_616d, 0]),
+            (OP_DECORATE, &[20, DECORATION_DESCRIPTOR_SET, 0]),
+            (OP_DECORATE, &[20, DECORATION_BINDING, 1]),
+            (OP_MEMBER_DECORATE, &[30, 0, DECORATION_OFFSET, 0]),
+            (OP_DECORATE, &[30, DECORATION_BLOCK]),
+            (OP_DECORATE, &[31, DECORATION_DESCRIPTOR_SET, uniform_set]),
+            (OP_DECORATE, &[31, DECORATION_BINDING, 0]),
+            (OP_TYPE_FLOAT, &[3, 32]),
+            (OP_TYPE_INT, &[4, 32, 0]),
+            (OP_TYPE_VECTOR, &[6, 3, 4]),
+            (OP_TYPE_IMAGE, &[7, 3, DIM_2D, 0, 0, 0, 1, 0]),
+            (OP_TYPE_SAMPLED_IMAGE, &[8, 7]),
+            (OP_CONSTANT, &[4, 9, texture_count]),
+            (OP_TYPE_ARRAY, &[10, 8, 9]),
+            (OP_TYPE_POINTER, &[11, STORAGE_UNIFORM_CONSTANT, 10]),
+            (OP_VARIABLE, &[11, 20, STORAGE_UNIFORM_CONSTANT]),
+            (OP_TYPE_STRUCT, &[30, 6]),
+            (OP_TYPE_POINTER, &[32, STORAGE_UNIFORM, 30]),
+            (OP_VARIABLE, &[32, 31, STORAGE_UNIFORM]),
+        ])
+    }
+
+    #[test]
+    fn reflects_vertex_inputs() {
+        let reflection = reflect(&vertex_shader()).unwrap();
+        assert_eq!(reflection.stage, ShaderStageFlags::VERTEX);
+        // A location per matrix column, without the built-in
+        let inputs = reflection.inputs.iter()
+            .map(|input| (input.location, input.format))
+            .collect::<Vec<_>>();
+        assert_eq!(inputs, vec![
+            (0, Format::R32G32B32Sfloat),
+            (1, Format::R32G32B32A32Sfloat),
+            (2, Format::R32G32B32A32Sfloat),
+            (3, Format::R32G32B32A32Sfloat),
+            (4, Format::R32G32B32A32Sfloat),
+            (5, Format::R32Uint),
+        ]);
+    }
+
+    #[test]
+    fn reflects_buffers_and_push_constants() {
+        let reflection = reflect(&vertex_shader()).unwrap();
+        assert_eq!(reflection.bindings, vec![
+            DescriptorBinding {
+                set: 0,
+                binding: 0,
+                descriptor_type: DescriptorType::UniformBuffer,
+                count: 1,
+                stage_flags: ShaderStageFlags::VERTEX,
+            },
+            DescriptorBinding {
+                set: 0,
+                binding: 2,
+                descriptor_type: DescriptorType::StorageBuffer,
+                count: 1,
+                stage_flags: ShaderStageFlags::VERTEX,
+            },
+        ]);
+        assert_eq!(reflection.push_constants, Some((16, 16)));
+    }
+
+    #[test]
+    fn reflects_sampler_arrays() {
+        let reflection = reflect(&fragment_shader(16, 0)).unwrap();
+        assert_eq!(reflection.stage, ShaderStageFlags::FRAGMENT);
+        assert!(reflection.inputs.is_empty());
+        assert_eq!(reflection.bindings[1], DescriptorBinding {
+            set: 0,
+            binding: 1,
+            descriptor_type: DescriptorType::CombinedImageSampler,
+            count: 16,
+            stage_flags: ShaderStageFlags::FRAGMENT,
+        });
+    }
+
+    #[test]
+    fn merges_shared_bindings() {
+        let vert = reflect(&vertex_shader()).unwrap();
+        let frag = reflect(&fragment_shader(16, 0)).unwrap();
+        let interface = PipelineInterface::new(&[&vert, &frag]).unwrap();
+        assert_eq!(interface.bindings.len(), 3);
+        assert_eq!(interface.binding(0, 0).unwrap().stage_flags,
+            ShaderStageFlags::VERTEX | ShaderStageFlags::FRAGMENT);
+        assert_eq!(interface.binding(0, 1).unwrap().stage_flags, ShaderStageFlags::FRAGMENT);
+        assert_eq!(interface.push_constants, vec![(ShaderStageFlags::VERTEX, (16, 16))]);
+        assert_eq!(interface.set_bindings(1), vec![]);
+    }
+
+    #[test]
+    fn conflicting_bindings_are_rejected() {
+        let frag = reflect(&fragment_shader(16, 0)).unwrap();
+        let other = reflect(&fragment_shader(8, 0)).unwrap();
+        assert!(PipelineInterface::new(&[&frag, &other]).is_err());
+        // Bindings of different sets don't conflict
+        let other_set = reflect(&fragment_shader(16, 1)).unwrap();
+        assert_eq!(PipelineInterface::new(&[&frag, &other_set]).unwrap().bindings.len(), 3);
+    }
+
+    #[test]
+    fn malformed_modules_are_rejected() {
+        let mut code = vertex_shader();
+        code[0] = 0;
+        assert!(reflect(&code).is_err());
+
+        let mut code = vertex_shader();
+        code.pop();
+        assert!(reflect(&code).is_err());
+
+        // No entry point
+        assert!(reflect(&module(&[(OP_TYPE_FLOAT, &[3, 32])])).is_err());
+    }
+
+    #[test]
+    fn resources_need_a_binding() {
+        let code = module(&[
+            (OP_ENTRY_POINT, &[EXECUTION_MODEL_FRAGMENT, 1, 0x6e69_616d, 0]),
+            (OP_TYPE_FLOAT, &[3, 32]),
+            (OP_TYPE_IMAGE, &[7, 3, DIM_2D, 0, 0, 0, 1, 0]),
+            (OP_TYPE_SAMPLED_IMAGE, &[8, 7]),
+            (OP_TYPE_POINTER, &[11, STORAGE_UNIFORM_CONSTANT, 8]),
+            (OP_VARIABLE, &[11, 20, STORAGE_UNIFORM_CONSTANT]),
+        ]);
+        assert!(reflect(&code).is_err());
+    }
+}
@@ -0,0 +1,692 @@
+/*
+ * render_graph.rs
+ * Passes declaring the images they draw to and sample. The
+ * graph orders the passes, allocates the images only they
+ * use and records the barriers between them.
+ */
+use voodoo::{Result as VdResult, Device, CommandBuffer, RenderPass, Framebuffer, Image,
+            ImageHandle, ImageView, Format, ImageLayout, ImageUsageFlags, Extent2d,
+            AttachmentLoadOp, AttachmentStoreOp, PipelineStageFlags, AccessFlags,
+            DependencyFlags, RenderPassBeginInfo, SubpassContents, Rect2d, Offset2d, ClearValue,
+            ClearColorValue};
+use voodoo::vks;
+
+use vulkan::{self, AttachmentInfo, ImageAccess};
+use memory::{Allocator, Allocation};
+
+/// An image of a render graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageId(usize);
+
+/// A pass of a render graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PassId(usize);
+
+/// The size of an image the graph allocates.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageSize {
+    /// The size of the output, following it on resize.
+    Output,
+}
+
+/// How a pass uses an image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Usage {
+    Color(AttachmentLoadOp),
+    Depth(AttachmentLoadOp),
+    /// Read in the fragment shader.
+    Sampled,
+}
+
+impl Usage {
+    fn access(&self) -> ImageAccess {
+        let layout = match *self {
+            Usage::Color(_) => ImageLayout::ColorAttachmentOptimal,
+            Usage::Depth(_) => ImageLayout::DepthStencilAttachmentOptimal,
+            Usage::Sampled => ImageLayout::ShaderReadOnlyOptimal,
+        };
+        ImageAccess::for_layout(layout).unwrap()
+    }
+
+    fn writes(&self) -> bool {
+        *self != Usage::Sampled
+    }
+}
+
+/// The images a pass draws to and samples.
+///
+/// ```ignore
+/// let desc = PassDesc::new()
+///     .color(hdr, AttachmentLoadOp::Clear)
+///     .depth(depth, AttachmentLoadOp::Clear)
+///     .sample(shadow_map);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PassDesc {
+    colors: Vec<(ImageId, AttachmentLoadOp)>,
+    depth: Option<(ImageId, AttachmentLoadOp)>,
+    sampled: Vec<ImageId>,
+}
+
+impl PassDesc {
+    pub fn new() -> PassDesc {
+        PassDesc::default()
+    }
+
+    /// Draws to `image` as the next color attachment. `load` tells what the
+    /// pass starts with: cleared to black, the previous contents or garbage.
+    pub fn color(mut self, image: ImageId, load: AttachmentLoadOp) -> PassDesc {
+        self.colors.push((image, load));
+        self
+    }
+
+    /// Depth tests against `image`, cleared to 1.0 with `Clear`.
+    pub fn depth(mut self, image: ImageId, load: AttachmentLoadOp) -> PassDesc {
+        self.depth = Some((image, load));
+        self
+    }
+
+    /// Samples `image` in the fragment shader. The passes drawing to it are
+    /// recorded first.
+    pub fn sample(mut self, image: ImageId) -> PassDesc {
+        self.sampled.push(image);
+        self
+    }
+
+    /// The images drawn to, colors first.
+    fn attachments(&self) -> Vec<ImageId> {
+        self.colors.iter().map(|&(image, _)| image)
+            .chain(self.depth.iter().map(|&(image, _)| image))
+            .collect()
+    }
+
+    fn uses<'d>(&'d self) -> Box<Iterator<Item=(ImageId, Usage)> + 'd> {
+        Box::new(self.colors.iter().map(|&(image, load)| (image, Usage::Color(load)))
+            .chain(self.depth.iter().map(|&(image, load)| (image, Usage::Depth(load))))
+            .chain(self.sampled.iter().map(|&image| (image, Usage::Sampled))))
+    }
+}
+
+/// A layout transition of an image before a pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Transition {
+    image: ImageId,
+    from: ImageAccess,
+    to: ImageAccess,
+}
+
+struct ImageDecl {
+    name: String,
+    format: Format,
+    size: ImageSize,
+}
+
+struct PassDecl {
+    name: String,
+    desc: PassDesc,
+}
+
+/// An image allocated by the graph.
+struct Transient {
+    image: Image,
+    // Freed when the graph is resized or dropped
+    _memory: Allocation,
+    view: ImageView,
+}
+
+/// The images supplied from outside the graph, one of which is drawn to
+/// per frame, e.g. the swapchain images.
+struct Output {
+    image: ImageId,
+    /// Where the output is left after the last pass.
+    final_access: ImageAccess,
+    images: Vec<(ImageHandle, ImageView)>,
+    extent: Extent2d,
+}
+
+/// A frame made of several passes.
+///
+/// Images and passes are declared first, then `compile` orders the passes
+/// and creates their render passes. `resize` allocates the images and
+/// framebuffers for an output size and has to be called before recording
+/// and whenever the output changes. The render passes stay the same, so
+/// pipelines made for them do too.
+pub struct RenderGraph {
+    images: Vec<ImageDecl>,
+    passes: Vec<PassDecl>,
+    output: Option<Output>,
+    /// Pass indices in the order they are recorded.
+    order: Vec<usize>,
+    render_passes: Vec<RenderPass>,
+    /// Indexed by image, `None` for the output.
+    transients: Vec<Option<Transient>>,
+    /// Indexed by pass, one per output image for passes drawing to the
+    /// output, otherwise a single one.
+    framebuffers: Vec<Vec<Framebuffer>>,
+}
+
+impl RenderGraph {
+    pub fn new() -> RenderGraph {
+        RenderGraph {
+            images: Vec::new(),
+            passes: Vec::new(),
+            output: None,
+            order: Vec::new(),
+            render_passes: Vec::new(),
+            transients: Vec::new(),
+            framebuffers: Vec::new(),
+        }
+    }
+
+    /// Declares an image the graph allocates.
+    pub fn add_image(&mut self, name: &str, format: Format, size: ImageSize) -> ImageId {
+        self.images.push(ImageDecl {
+            name: name.to_string(),
+            format,
+            size,
+        });
+        ImageId(self.images.len() - 1)
+    }
+
+    /// Declares the image the frame ends up in, left in `final_layout`
+    /// after the last pass. The images themselves are passed to `resize`.
+    pub fn add_output(&mut self, name: &str, format: Format, final_layout: ImageLayout)
+        -> VdResult<ImageId>
+    {
+        if self.output.is_some() {
+            return Err("a render graph has a single output".into());
+        }
+        let final_access = ImageAccess::for_layout(final_layout)
+            .ok_or_else(|| format!("unsupported output layout {:?}", final_layout))?;
+        let image = self.add_image(name, format, ImageSize::Output);
+        self.output = Some(Output {
+            image,
+            final_access,
+            images: Vec::new(),
+            extent: Extent2d::builder().width(0).height(0).build(),
+        });
+        Ok(image)
+    }
+
+    pub fn add_pass(&mut self, name: &str, desc: PassDesc) -> PassId {
+        self.passes.push(PassDecl {
+            name: name.to_string(),
+            desc,
+        });
+        PassId(self.passes.len() - 1)
+    }
+
+    fn is_output(&self, image: ImageId) -> bool {
+        self.output.as_ref().map_or(false, |output| output.image == image)
+    }
+
+    /// Orders the passes so every image is drawn before it is sampled.
+    /// Passes using the same image otherwise keep the order they were added
+    /// in.
+    fn sort_passes(&self) -> VdResult<Vec<usize>> {
+        let pass_count = self.passes.len();
+        let mut dependencies = vec![Vec::new(); pass_count];
+        for (later, pass) in self.passes.iter().enumerate() {
+            for (image, usage) in pass.desc.uses() {
+                let writers = self.passes.iter().enumerate().filter(|&(_, other)| {
+                    other.desc.uses().any(|(other_image, other_usage)| {
+                        other_image == image && other_usage.writes()
+                    })
+                }).map(|(earlier, _)| earlier).collect::<Vec<_>>();
+                if writers.is_empty() {
+                    return Err(format!("pass {} uses image {}, which no pass draws to",
+                        pass.name, self.images[image.0].name).into());
+                }
+                for earlier in writers {
+                    // Sampling waits for all writers, writers go in order
+                    let before = if usage.writes() { earlier < later } else { earlier != later };
+                    if before && !dependencies[later].contains(&earlier) {
+                        dependencies[later].push(earlier);
+                    }
+                }
+            }
+        }
+
+        let mut order = Vec::with_capacity(pass_count);
+        let mut done = vec![false; pass_count];
+        while order.len() < pass_count {
+            let next = (0..pass_count).find(|&i| {
+                !done[i] && dependencies[i].iter().all(|&dependency| done[dependency])
+            });
+            match next {
+                Some(i) => {
+                    done[i] = true;
+                    order.push(i);
+                },
+                None => return Err("render graph passes depend on each other in a cycle".into()),
+            }
+        }
+        Ok(order)
+    }
+
+    /// True if a pass recorded after the `position`th uses `image`, or it is
+    /// the output.
+    fn used_after(&self, image: ImageId, position: usize) -> bool {
+        self.is_output(image) || self.order[position + 1..].iter().any(|&pass| {
+            self.passes[pass].desc.uses().any(|(other, _)| other == image)
+        })
+    }
+
+    /// Orders the passes and creates their render passes.
+    pub fn compile(&mut self, device: &Device) -> VdResult<()> {
+        if self.output.is_none() {
+            return Err("render graph has no output".into());
+        }
+        let output = self.output.as_ref().unwrap().image;
+        let output_written = self.passes.iter().any(|pass| {
+            pass.desc.uses().any(|(image, usage)| image == output && usage.writes())
+        });
+        if !output_written {
+            return Err("no pass draws to the render graph output".into());
+        }
+        self.order = self.sort_passes()?;
+
+        let mut render_passes = Vec::with_capacity(self.passes.len());
+        for pass in 0..self.passes.len() {
+            let position = self.order.iter().position(|&p| p == pass).unwrap();
+            let desc = &self.passes[pass].desc;
+            if desc.colors.is_empty() && desc.depth.is_none() {
+                return Err(format!("pass {} draws to no image", self.passes[pass].name).into());
+            }
+
+            let attachment = |(image, usage): (ImageId, Usage)| {
+                let load_op = match usage {
+                    Usage::Color(load_op) | Usage::Depth(load_op) => load_op,
+                    Usage::Sampled => unreachable!(),
+                };
+                let store_op = if self.used_after(image, position) {
+                    AttachmentStoreOp::Store
+                } else {
+                    AttachmentStoreOp::DontCare
+                };
+                AttachmentInfo {
+                    format: self.images[image.0].format,
+                    load_op,
+                    store_op,
+                    layout: usage.access().layout,
+                }
+            };
+            let colors = desc.colors.iter()
+                .map(|&(image, load_op)| attachment((image, Usage::Color(load_op))))
+                .collect::<Vec<_>>();
+            let depth = desc.depth
+                .map(|(image, load_op)| attachment((image, Usage::Depth(load_op))));
+
+            render_passes.push(vulkan::create_render_pass(device.clone(), &colors,
+                depth.as_ref())?);
+        }
+        self.render_passes = render_passes;
+        Ok(())
+    }
+
+    /// The render pass of `pass`, which pipelines drawing in it are made for.
+    pub fn render_pass(&self, pass: PassId) -> &RenderPass {
+        &self.render_passes[pass.0]
+    }
+
+    /// Number of output images, e.g. swapchain images.
+    pub fn output_count(&self) -> usize {
+        self.output.as_ref().map_or(0, |output| output.images.len())
+    }
+
+    pub fn output_extent(&self) -> Extent2d {
+        self.output.as_ref().unwrap().extent.clone()
+    }
+
+    fn image_extent(&self, image: ImageId) -> Extent2d {
+        match self.images[image.0].size {
+            ImageSize::Output => self.output_extent(),
+        }
+    }
+
+    /// The size of the images `pass` draws to.
+    fn pass_extent(&self, pass: usize) -> Extent2d {
+        self.image_extent(self.passes[pass].desc.attachments()[0])
+    }
+
+    /// Allocates the images and creates the framebuffers for drawing to the
+    /// `output` images of size `extent`. The device must no longer use the
+    /// previous ones.
+    pub fn resize(&mut self,
+                  device: &Device,
+                  allocator: &Allocator,
+                  output: Vec<(ImageHandle, ImageView)>,
+                  extent: Extent2d) -> VdResult<()> {
+        if self.render_passes.is_empty() {
+            return Err("render graph isn't compiled".into());
+        }
+        {
+            let graph_output = self.output.as_mut().unwrap();
+            graph_output.images = output;
+            graph_output.extent = extent;
+        }
+
+        self.framebuffers.clear();
+        self.transients.clear();
+        let mut transients = Vec::with_capacity(self.images.len());
+        for (i, decl) in self.images.iter().enumerate() {
+            let image = ImageId(i);
+            if self.is_output(image) {
+                transients.push(None);
+                continue;
+            }
+
+            let mut usage = ImageUsageFlags::empty();
+            let mut pass_count = 0;
+            for pass in &self.passes {
+                let pass_usages = pass.desc.uses()
+                    .filter(|&(other, _)| other == image)
+                    .map(|(_, pass_usage)| pass_usage)
+                    .collect::<Vec<_>>();
+                if !pass_usages.is_empty() {
+                    pass_count += 1;
+                }
+                for pass_usage in pass_usages {
+                    usage |= match pass_usage {
+                        Usage::Color(_) => ImageUsageFlags::COLOR_ATTACHMENT,
+                        Usage::Depth(_) => ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                        Usage::Sampled => ImageUsageFlags::SAMPLED,
+                    };
+                }
+            }
+            if pass_count == 0 {
+                // Declared but unused, nothing to allocate
+                transients.push(None);
+                continue;
+            }
+            // Contents which never leave a pass need not be backed by memory
+            // on tiled GPUs
+            if pass_count == 1 && !usage.contains(ImageUsageFlags::SAMPLED) {
+                usage |= ImageUsageFlags::TRANSIENT_ATTACHMENT;
+            }
+
+            let (image, memory, view) = vulkan::create_attachment_image(device, allocator,
+                decl.format, &self.image_extent(image), usage)?;
+            transients.push(Some(Transient { image, _memory: memory, view }));
+        }
+        self.transients = transients;
+
+        let mut framebuffers = Vec::with_capacity(self.passes.len());
+        for (pass, decl) in self.passes.iter().enumerate() {
+            let attachments = decl.desc.attachments();
+            let extent = self.pass_extent(pass);
+            if attachments.iter().any(|&image| self.image_extent(image) != extent) {
+                return Err(format!("the images pass {} draws to differ in size",
+                    decl.name).into());
+            }
+
+            let writes_output = attachments.iter().any(|&image| self.is_output(image));
+            let output_count = if writes_output { self.output_count() } else { 1 };
+            let pass_framebuffers = (0..output_count).map(|output_index| {
+                let views = attachments.iter().map(|&image| {
+                    match self.transients[image.0] {
+                        Some(ref transient) => &transient.view,
+                        None => &self.output.as_ref().unwrap().images[output_index].1,
+                    }
+                }).collect::<Vec<_>>();
+                vulkan::create_framebuffer(device, &self.render_passes[pass], &views, &extent)
+            }).collect::<VdResult<Vec<_>>>()?;
+            framebuffers.push(pass_framebuffers);
+        }
+        self.framebuffers = framebuffers;
+        Ok(())
+    }
+
+    fn image_handle(&self, image: ImageId, output_index: usize) -> ImageHandle {
+        match self.transients[image.0] {
+            Some(ref transient) => transient.image.handle(),
+            None => self.output.as_ref().unwrap().images[output_index].0,
+        }
+    }
+
+    /// How `image` is used last in a frame, which the next frame has to wait
+    /// for before reusing it.
+    fn last_access(&self, image: ImageId) -> ImageAccess {
+        self.order.iter().rev()
+            .filter_map(|&pass| {
+                self.passes[pass].desc.uses().find(|&(other, _)| other == image)
+            })
+            .next()
+            .map(|(_, usage)| usage.access())
+            .unwrap_or_else(|| ImageAccess::for_layout(ImageLayout::Undefined).unwrap())
+    }
+
+    /// The transitions before each pass, in recording order, and the
+    /// access each image is left in after the last pass.
+    fn transitions(&self) -> (Vec<Vec<Transition>>, Vec<ImageAccess>) {
+        // Previous contents are never kept, but the last frame's accesses
+        // have to finish. The output waits on the acquire semaphore, which
+        // the submission ties to the color attachment output stage.
+        let mut states = (0..self.images.len()).map(|i| {
+            let image = ImageId(i);
+            let last = if self.is_output(image) {
+                ImageAccess::new(ImageLayout::Undefined,
+                    PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, AccessFlags::empty())
+            } else {
+                self.last_access(image)
+            };
+            ImageAccess::new(ImageLayout::Undefined, last.stages, last.access)
+        }).collect::<Vec<_>>();
+
+        let transitions = self.order.iter().map(|&pass| {
+            let mut pass_transitions = Vec::new();
+            for (image, usage) in self.passes[pass].desc.uses() {
+                let from = states[image.0];
+                let to = usage.access();
+                // Reads in the same layout need no barrier between them
+                if from.layout == to.layout && !from.writes() && !to.writes() {
+                    continue;
+                }
+                pass_transitions.push(Transition { image, from, to });
+                states[image.0] = to;
+            }
+            pass_transitions
+        }).collect();
+        (transitions, states)
+    }
+
+    /// Records all passes drawing to the `output_index`th output image, in
+    /// dependency order with barriers between them.
+    ///
+    /// `record_pass` is called inside each render pass with the pass and
+    /// the size of the images it draws to, to record its draws. Pipelines
+    /// are expected to have a dynamic viewport and scissor.
+    pub fn record<F>(&self,
+                     command_buffer: &CommandBuffer,
+                     output_index: usize,
+                     mut record_pass: F) -> VdResult<()>
+        where F: FnMut(PassId, &CommandBuffer, &Extent2d) -> VdResult<()>
+    {
+        if self.framebuffers.is_empty() {
+            return Err("render graph has no framebuffers, resize it first".into());
+        }
+        let output = self.output.as_ref().unwrap();
+        let (transitions, states) = self.transitions();
+
+        let clear_color = ClearValue { color: ClearColorValue {
+            float32: [0.0f32, 0.0f32, 0.0f32, 1.0f32] } };
+        let clear_depth = ClearValue { depthStencil: vks::VkClearDepthStencilValue {
+            depth: 1.0, stencil: 0, } };
+
+        for (&pass, pass_transitions) in self.order.iter().zip(&transitions) {
+            let desc = &self.passes[pass].desc;
+
+            if !pass_transitions.is_empty() {
+                let mut src_stages = PipelineStageFlags::empty();
+                let mut dst_stages = PipelineStageFlags::empty();
+                let barriers = pass_transitions.iter().map(|transition| {
+                    src_stages |= transition.from.stages;
+                    dst_stages |= transition.to.stages;
+                    vulkan::image_barrier(self.image_handle(transition.image, output_index),
+                        vulkan::format_aspect_mask(self.images[transition.image.0].format),
+                        0, 1, &transition.from, &transition.to)
+                }).collect::<Vec<_>>();
+                command_buffer.pipeline_barrier(src_stages, dst_stages,
+                    DependencyFlags::empty(), &[], &[], &barriers);
+            }
+
+            let framebuffers = &self.framebuffers[pass];
+            let framebuffer = &framebuffers[output_index.min(framebuffers.len() - 1)];
+            let extent = self.pass_extent(pass);
+            let clear_values = desc.colors.iter().map(|_| clear_color)
+                .chain(desc.depth.iter().map(|_| clear_depth))
+                .collect::<Vec<_>>();
+
+            let render_pass_info = RenderPassBeginInfo::builder()
+                .render_pass(&self.render_passes[pass])
+                .framebuffer(framebuffer)
+                .render_area(Rect2d::builder()
+                    .offset(Offset2d::builder().x(0).y(0).build())
+                    .extent(extent.clone())
+                    .build())
+                .clear_values(&clear_values[..])
+                .build();
+
+            command_buffer.begin_render_pass(&render_pass_info, SubpassContents::Inline);
+            record_pass(PassId(pass), command_buffer, &extent)?;
+            command_buffer.end_render_pass();
+        }
+
+        let from = states[output.image.0];
+        let barrier = vulkan::image_barrier(output.images[output_index].0,
+            vulkan::format_aspect_mask(self.images[output.image.0].format), 0, 1,
+            &from, &output.final_access);
+        command_buffer.pipeline_barrier(from.stages, output.final_access.stages,
+            DependencyFlags::empty(), &[], &[], &[barrier]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Orders the passes as `compile` does, without creating render passes.
+    fn sorted(graph: &mut RenderGraph) -> VdResult<Vec<usize>> {
+        graph.order = graph.sort_passes()?;
+        Ok(graph.order.clone())
+    }
+
+    /// A graph with an output, an HDR color image and a depth image.
+    fn graph() -> (RenderGraph, ImageId, ImageId, ImageId) {
+        let mut graph = RenderGraph::new();
+        let output = graph.add_output("output", Format::B8G8R8A8Unorm,
+            ImageLayout::PresentSrcKhr).unwrap();
+        let hdr = graph.add_image("hdr", Format::R16G16B16A16Sfloat, ImageSize::Output);
+        let depth = graph.add_image("depth", Format::D32Sfloat, ImageSize::Output);
+        (graph, output, hdr, depth)
+    }
+
+    #[test]
+    fn sampled_images_are_drawn_first() {
+        let (mut graph, output, hdr, depth) = graph();
+        graph.add_pass("tonemap", PassDesc::new()
+            .color(output, AttachmentLoadOp::DontCare)
+            .sample(hdr));
+        graph.add_pass("scene", PassDesc::new()
+            .color(hdr, AttachmentLoadOp::Clear)
+            .depth(depth, AttachmentLoadOp::Clear));
+        assert_eq!(sorted(&mut graph).unwrap(), vec![1, 0]);
+    }
+
+    #[test]
+    fn writers_keep_their_order() {
+        let (mut graph, output, _, depth) = graph();
+        graph.add_pass("opaque", PassDesc::new()
+            .color(output, AttachmentLoadOp::Clear)
+            .depth(depth, AttachmentLoadOp::Clear));
+        graph.add_pass("transparent", PassDesc::new()
+            .color(output, AttachmentLoadOp::Load)
+            .depth(depth, AttachmentLoadOp::Load));
+        assert_eq!(sorted(&mut graph).unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let (mut graph, output, hdr, _) = graph();
+        graph.add_pass("a", PassDesc::new()
+            .color(output, AttachmentLoadOp::Clear)
+            .sample(hdr));
+        graph.add_pass("b", PassDesc::new()
+            .color(hdr, AttachmentLoadOp::Clear)
+            .sample(output));
+        assert!(sorted(&mut graph).is_err());
+    }
+
+    #[test]
+    fn images_nobody_draws_are_rejected() {
+        let (mut graph, output, hdr, _) = graph();
+        graph.add_pass("tonemap", PassDesc::new()
+            .color(output, AttachmentLoadOp::Clear)
+            .sample(hdr));
+        assert!(sorted(&mut graph).is_err());
+    }
+
+    #[test]
+    fn barriers_follow_the_usage() {
+        let (mut graph, output, hdr, depth) = graph();
+        graph.add_pass("tonemap", PassDesc::new()
+            .color(output, AttachmentLoadOp::DontCare)
+            .sample(hdr));
+        graph.add_pass("scene", PassDesc::new()
+            .color(hdr, AttachmentLoadOp::Clear)
+            .depth(depth, AttachmentLoadOp::Clear));
+        sorted(&mut graph).unwrap();
+        let (transitions, states) = graph.transitions();
+
+        let color = ImageAccess::for_layout(ImageLayout::ColorAttachmentOptimal).unwrap();
+        let depth_access =
+            ImageAccess::for_layout(ImageLayout::DepthStencilAttachmentOptimal).unwrap();
+        let sampled = ImageAccess::for_layout(ImageLayout::ShaderReadOnlyOptimal).unwrap();
+
+        // The scene waits for the previous frame's tonemapping to stop
+        // sampling, and for its own depth tests
+        assert_eq!(transitions[0].len(), 2);
+        assert_eq!(transitions[0][0], Transition {
+            image: hdr,
+            from: ImageAccess::new(ImageLayout::Undefined, sampled.stages, sampled.access),
+            to: color,
+        });
+        assert_eq!(transitions[0][1], Transition {
+            image: depth,
+            from: ImageAccess::new(ImageLayout::Undefined, depth_access.stages,
+                depth_access.access),
+            to: depth_access,
+        });
+
+        // Tonemapping waits for the output to be acquired and the scene
+        // to be drawn
+        assert_eq!(transitions[1].len(), 2);
+        assert_eq!(transitions[1][0], Transition {
+            image: output,
+            from: ImageAccess::new(ImageLayout::Undefined,
+                PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, AccessFlags::empty()),
+            to: color,
+        });
+        assert_eq!(transitions[1][1], Transition { image: hdr, from: color, to: sampled });
+
+        assert_eq!(states[output.0], color);
+        assert_eq!(states[hdr.0], sampled);
+    }
+
+    #[test]
+    fn reads_in_the_same_layout_need_no_barrier() {
+        let (mut graph, output, hdr, _) = graph();
+        graph.add_pass("scene", PassDesc::new()
+            .color(hdr, AttachmentLoadOp::Clear));
+        graph.add_pass("bloom", PassDesc::new()
+            .color(output, AttachmentLoadOp::Clear)
+            .sample(hdr));
+        graph.add_pass("tonemap", PassDesc::new()
+            .color(output, AttachmentLoadOp::Load)
+            .sample(hdr));
+        assert_eq!(sorted(&mut graph).unwrap(), vec![0, 1, 2]);
+        let (transitions, _) = graph.transitions();
+        assert!(transitions[2].iter().all(|transition| transition.image != hdr));
+    }
+}
@@ -0,0 +1,352 @@
+/*
+ * scene.rs
+ * The meshes on the device and the objects drawing them,
+ * turned into a sorted draw list whenever they change.
+ */
+use std::collections::BTreeMap;
+
+use cgmath::{Matrix4, SquareMatrix};
+
+use vulkan::{InstanceData, InstanceVertex, DrawData};
+use mesh::Mesh;
+use pipeline::PipelineDesc;
+use culling::BoundingSphere;
+
+/// A mesh added to a scene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MeshId(usize);
+
+/// An object in a scene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectId(u64);
+
+/// A mesh together with the texture array index of each of its sub-meshes.
+pub struct SceneMesh<M = Mesh> {
+    pub mesh: M,
+    pub texture_indices: Vec<u32>,
+    /// Encloses all vertices of the mesh.
+    pub bounds: BoundingSphere,
+}
+
+/// An instance of a mesh drawn every frame.
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub mesh: MeshId,
+    pub pipeline: PipelineDesc,
+    /// Places the mesh in the world.
+    pub transform: Matrix4<f32>,
+    /// The copies of the mesh drawn, all in the same draw calls. Objects
+    /// without instances aren't drawn.
+    pub instances: Vec<InstanceData>,
+    /// Encloses all instances in object space, for culling. Defaults to the
+    /// bounds of the mesh, which only fit untransformed instances.
+    pub bounds: Option<BoundingSphere>,
+    /// Hidden objects stay in the scene but aren't drawn.
+    pub visible: bool,
+}
+
+impl Object {
+    pub fn new(mesh: MeshId, pipeline: PipelineDesc) -> Object {
+        Object {
+            mesh,
+            pipeline,
+            transform: Matrix4::identity(),
+            instances: vec![InstanceData::default()],
+            bounds: None,
+            visible: true,
+        }
+    }
+}
+
+/// One draw of a draw list.
+#[derive(Debug, Clone, Copy)]
+pub struct Draw {
+    pub object: ObjectId,
+    pub mesh: MeshId,
+    /// Index into the sub-meshes of the mesh.
+    pub sub_mesh: usize,
+    /// Index into `DrawList::pipelines`.
+    pub pipeline: usize,
+    /// Range of `DrawList::instances` to draw.
+    pub first_instance: u32,
+    pub instance_count: u32,
+    /// Encloses the instances drawn, in object space.
+    pub bounds: BoundingSphere,
+    pub texture_index: u32,
+}
+
+/// A run of draws with the same pipeline and mesh, recorded together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Batch {
+    /// Index into `DrawList::pipelines`.
+    pub pipeline: usize,
+    pub mesh: MeshId,
+    /// Range of `DrawList::draws` in the batch.
+    pub first_draw: usize,
+    pub draw_count: usize,
+}
+
+/// The draws of a scene, sorted so pipelines and vertex buffers are bound as
+/// rarely as possible.
+#[derive(Debug, Clone, Default)]
+pub struct DrawList {
+    /// The distinct pipelines the draws use.
+    pub pipelines: Vec<PipelineDesc>,
+    pub draws: Vec<Draw>,
+    pub batches: Vec<Batch>,
+    /// The instances of all draws, for the instance buffer. Each points at
+    /// the draw data of its draw.
+    pub instances: Vec<InstanceVertex>,
+}
+
+impl DrawList {
+    pub fn len(&self) -> usize {
+        self.draws.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.draws.is_empty()
+    }
+}
+
+/// Meshes and the objects drawing them. Objects can be added and removed
+/// at any time, the next frame draws the scene as it is then.
+///
+/// Building draw lists only needs the texture indices and bounds of each
+/// mesh, so tests use a scene of `()` meshes instead of device buffers.
+pub struct Scene<M = Mesh> {
+    meshes: Vec<SceneMesh<M>>,
+    objects: BTreeMap<ObjectId, Object>,
+    next_object_id: u64,
+    revision: u64,
+    transform_revision: u64,
+}
+
+impl<M> Scene<M> {
+    pub fn new() -> Scene<M> {
+        Scene {
+            meshes: Vec::new(),
+            objects: BTreeMap::new(),
+            next_object_id: 0,
+            revision: 0,
+            transform_revision: 0,
+        }
+    }
+
+    /// Changes whenever objects are added, removed or borrowed mutably,
+    /// invalidating draw lists built before.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Changes whenever `revision` does or a transform is set, invalidating
+    /// draw data built before.
+    pub fn transform_revision(&self) -> u64 {
+        self.transform_revision
+    }
+
+    fn changed(&mut self) {
+        self.revision += 1;
+        self.transform_revision += 1;
+    }
+
+    /// Adds a mesh whose uploads have been queued. It must not be drawn
+    /// before they have completed.
+    pub fn add_mesh(&mut self, mesh: SceneMesh<M>) -> MeshId {
+        self.meshes.push(mesh);
+        MeshId(self.meshes.len() - 1)
+    }
+
+    pub fn mesh(&self, id: MeshId) -> &SceneMesh<M> {
+        &self.meshes[id.0]
+    }
+
+    pub fn add_object(&mut self, object: Object) -> ObjectId {
+        assert!(object.mesh.0 < self.meshes.len(), "object of an unknown mesh");
+        let id = ObjectId(self.next_object_id);
+        self.next_object_id += 1;
+        self.objects.insert(id, object);
+        self.changed();
+        id
+    }
+
+    /// Removes an object, returning it if it was in the scene.
+    pub fn remove_object(&mut self, id: ObjectId) -> Option<Object> {
+        self.changed();
+        self.objects.remove(&id)
+    }
+
+    pub fn object(&self, id: ObjectId) -> Option<&Object> {
+        self.objects.get(&id)
+    }
+
+    /// Borrowing the objects mutably rebuilds the draw list of the next
+    /// frame, prefer `set_transform` to only move one.
+    pub fn objects_mut(&mut self) -> impl Iterator<Item=&mut Object> {
+        self.changed();
+        self.objects.values_mut()
+    }
+
+    /// Moves an object, returning false if it isn't in the scene. Only
+    /// rewrites the draw data of the next frame.
+    pub fn set_transform(&mut self, id: ObjectId, transform: Matrix4<f32>) -> bool {
+        match self.objects.get_mut(&id) {
+            Some(object) => {
+                object.transform = transform;
+                self.transform_revision += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Collects a draw per sub-mesh of each visible object, drawing all its
+    /// instances, grouped by pipeline, then by mesh.
+    pub fn draw_list(&self) -> DrawList {
+        let mut list = DrawList::default();
+        let drawn = self.objects.iter()
+            .filter(|&(_, object)| object.visible && !object.instances.is_empty());
+        for (&id, object) in drawn {
+            let pipeline = match list.pipelines.iter().position(|&desc| desc == object.pipeline) {
+                Some(pipeline) => pipeline,
+                None => {
+                    list.pipelines.push(object.pipeline);
+                    list.pipelines.len() - 1
+                },
+            };
+            let scene_mesh = &self.meshes[object.mesh.0];
+            let bounds = object.bounds.unwrap_or(scene_mesh.bounds);
+            for (sub_mesh, &texture_index) in scene_mesh.texture_indices.iter().enumerate() {
+                list.draws.push(Draw {
+                    object: id,
+                    mesh: object.mesh,
+                    sub_mesh,
+                    pipeline,
+                    first_instance: 0,
+                    instance_count: object.instances.len() as u32,
+                    bounds,
+                    texture_index,
+                });
+            }
+        }
+        // Stable, so objects with equal state keep the order they were added in
+        list.draws.sort_by_key(|draw| (draw.pipeline, draw.mesh));
+
+        // Instances know their draw by index, so each draw takes its own copy
+        // of its object's instances once the draws are in their final order
+        for (i, draw) in list.draws.iter_mut().enumerate() {
+            draw.first_instance = list.instances.len() as u32;
+            let instances = self.objects[&draw.object].instances.iter()
+                .map(|&instance| InstanceVertex { instance, draw_index: i as u32 });
+            list.instances.extend(instances);
+
+            let extends_batch = list.batches.last().map_or(false, |batch| {
+                batch.pipeline == draw.pipeline && batch.mesh == draw.mesh
+            });
+            if extends_batch {
+                list.batches.last_mut().unwrap().draw_count += 1;
+            } else {
+                list.batches.push(Batch {
+                    pipeline: draw.pipeline,
+                    mesh: draw.mesh,
+                    first_draw: i,
+                    draw_count: 1,
+                });
+            }
+        }
+        list
+    }
+
+    /// The draw data of each draw of `draw_list`, which must have been built
+    /// at the current revision, with the current transforms.
+    pub fn draw_data(&self, draw_list: &DrawList) -> Vec<DrawData> {
+        draw_list.draws.iter().map(|draw| {
+            DrawData {
+                model: self.objects[&draw.object].transform.into(),
+                texture_index: draw.texture_index,
+                _padding: [0; 3],
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(texture_indices: Vec<u32>) -> SceneMesh<()> {
+        SceneMesh {
+            mesh: (),
+            texture_indices,
+            bounds: BoundingSphere { center: [0.0; 3], radius: 1.0 },
+        }
+    }
+
+    #[test]
+    fn draw_list_batches_by_pipeline_and_mesh() {
+        let mut scene = Scene::new();
+        let two_sub_meshes = scene.add_mesh(mesh(vec![3, 4]));
+        let one_sub_mesh = scene.add_mesh(mesh(vec![5]));
+        let opaque = PipelineDesc::opaque();
+        let wireframe = PipelineDesc::wireframe();
+
+        let a = scene.add_object(Object::new(two_sub_meshes, opaque));
+        let b = scene.add_object(Object::new(one_sub_mesh, opaque));
+        let c = scene.add_object(Object::new(two_sub_meshes, wireframe));
+        let mut object = Object::new(two_sub_meshes, opaque);
+        object.instances.push(InstanceData::default());
+        let d = scene.add_object(object);
+        let mut hidden = Object::new(two_sub_meshes, opaque);
+        hidden.visible = false;
+        scene.add_object(hidden);
+        let mut empty = Object::new(one_sub_mesh, opaque);
+        empty.instances.clear();
+        scene.add_object(empty);
+
+        let list = scene.draw_list();
+        assert_eq!(list.pipelines, vec![opaque, wireframe]);
+        assert_eq!(list.batches, vec![
+            Batch { pipeline: 0, mesh: two_sub_meshes, first_draw: 0, draw_count: 4 },
+            Batch { pipeline: 0, mesh: one_sub_mesh, first_draw: 4, draw_count: 1 },
+            Batch { pipeline: 1, mesh: two_sub_meshes, first_draw: 5, draw_count: 2 },
+        ]);
+
+        let draws = list.draws.iter()
+            .map(|draw| (draw.object, draw.sub_mesh, draw.texture_index))
+            .collect::<Vec<_>>();
+        assert_eq!(draws, vec![
+            (a, 0, 3), (a, 1, 4), (d, 0, 3), (d, 1, 4), (b, 0, 5), (c, 0, 3), (c, 1, 4),
+        ]);
+        let instance_ranges = list.draws.iter()
+            .map(|draw| (draw.first_instance, draw.instance_count))
+            .collect::<Vec<_>>();
+        assert_eq!(instance_ranges, vec![(0, 1), (1, 1), (2, 2), (4, 2), (6, 1), (7, 1), (8, 1)]);
+
+        let draw_indices = list.instances.iter()
+            .map(|instance| instance.draw_index)
+            .collect::<Vec<_>>();
+        assert_eq!(draw_indices, vec![0, 1, 2, 2, 3, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn only_changes_revision_when_draws_change() {
+        let mut scene = Scene::new();
+        let mesh_id = scene.add_mesh(mesh(vec![0]));
+        let id = scene.add_object(Object::new(mesh_id, PipelineDesc::opaque()));
+
+        let revision = scene.revision();
+        let transform_revision = scene.transform_revision();
+        assert!(scene.set_transform(id, Matrix4::from_scale(2.0)));
+        assert_eq!(scene.revision(), revision);
+        assert!(scene.transform_revision() > transform_revision);
+
+        assert!(scene.remove_object(id).is_some());
+        assert!(scene.revision() > revision);
+        assert!(!scene.set_transform(id, Matrix4::identity()));
+        assert!(scene.draw_list().is_empty());
+    }
+}
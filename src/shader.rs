@@ -0,0 +1,96 @@
+/*
+ * shader.rs
+ * Compiles GLSL shader sources to SPIR-V at runtime and
+ * watches them for changes.
+ */
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use glsl_to_spirv::{self, ShaderType};
+use notify::{self, Watcher, RecommendedWatcher, RecursiveMode, DebouncedEvent};
+
+use error::{Result, ResultExt};
+
+/// Milliseconds the watcher waits for writes to a file to settle.
+const WATCH_DELAY_MS: u64 = 200;
+
+/// The first word of every SPIR-V module.
+const SPIR_V_MAGIC: u32 = 0x0723_0203;
+
+/// The shader stage of a source file, from its extension.
+fn shader_type(path: &Path) -> Option<ShaderType> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("vert") => Some(ShaderType::Vertex),
+        Some("frag") => Some(ShaderType::Fragment),
+        Some("geom") => Some(ShaderType::Geometry),
+        Some("tesc") => Some(ShaderType::TessellationControl),
+        Some("tese") => Some(ShaderType::TessellationEvaluation),
+        Some("comp") => Some(ShaderType::Compute),
+        _ => None,
+    }
+}
+
+/// Compiles the GLSL source at `path` to SPIR-V words. The stage is taken
+/// from the file extension.
+pub fn compile_glsl<P: AsRef<Path>>(path: P) -> Result<Vec<u32>> {
+    let path = path.as_ref();
+    let ty = shader_type(path)
+        .ok_or_else(|| format!("unknown shader stage of {}", path.display()))?;
+    let source = fs::read_to_string(path)?;
+
+    let mut output = glsl_to_spirv::compile(&source, ty)
+        .context(format!("compiling {}", path.display()))?;
+    let mut bytes = Vec::new();
+    output.read_to_end(&mut bytes)?;
+
+    let words = bytes.chunks(4)
+        .filter(|word| word.len() == 4)
+        .map(|word| word[0] as u32 | (word[1] as u32) << 8 |
+            (word[2] as u32) << 16 | (word[3] as u32) << 24)
+        .collect::<Vec<_>>();
+    if words.first() != Some(&SPIR_V_MAGIC) || bytes.len() % 4 != 0 {
+        return Err(format!("compiling {} produced invalid SPIR-V", path.display()).into());
+    }
+    Ok(words)
+}
+
+/// Reports changes to the shader sources in a directory.
+pub struct ShaderWatcher {
+    // Watching stops when the watcher is dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<DebouncedEvent>,
+}
+
+impl ShaderWatcher {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<ShaderWatcher> {
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::watcher(sender, Duration::from_millis(WATCH_DELAY_MS))?;
+        watcher.watch(dir.as_ref(), RecursiveMode::NonRecursive)?;
+        Ok(ShaderWatcher {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Returns the shader sources written, created or renamed since the
+    /// last call, each once. Never blocks.
+    pub fn poll_changed(&self) -> Vec<PathBuf> {
+        let mut changed = Vec::new();
+        for event in self.events.try_iter() {
+            // Editors often save by renaming a temporary file over the source
+            let path = match event {
+                DebouncedEvent::Write(path) |
+                DebouncedEvent::Create(path) |
+                DebouncedEvent::Rename(_, path) => path,
+                _ => continue,
+            };
+            if shader_type(&path).is_some() && !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+        changed
+    }
+}
@@ -0,0 +1,117 @@
+/*
+ * texture.rs
+ * Textures loaded by path, deduplicated and exposed to the
+ * shaders as one array of combined image samplers.
+ */
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use voodoo::{Device, ImageView, Sampler, ImageLayout, DescriptorImageInfo};
+
+use vulkan::{self, TextureImage};
+use memory::Allocator;
+use upload::UploadManager;
+use error::{Result, ResultExt};
+
+/// Length of the texture array in the shaders. Vulkan guarantees at least
+/// 16 sampled images per shader stage.
+pub const MAX_TEXTURES: u32 = 16;
+
+/// Index of a texture in the descriptor array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureHandle(u32);
+
+impl TextureHandle {
+    /// The index shaders use to look up this texture.
+    pub fn index(&self) -> u32 {
+        self.0
+    }
+}
+
+pub struct Texture {
+    pub path: PathBuf,
+    pub image: TextureImage,
+    pub image_view: ImageView,
+    pub sampler: Sampler,
+}
+
+pub struct TextureManager {
+    device: Device,
+    allocator: Allocator,
+    textures: Vec<Texture>,
+    handles: HashMap<PathBuf, TextureHandle>,
+}
+
+impl TextureManager {
+    pub fn new(device: &Device, allocator: &Allocator) -> TextureManager {
+        TextureManager {
+            device: device.clone(),
+            allocator: allocator.clone(),
+            textures: Vec::new(),
+            handles: HashMap::new(),
+        }
+    }
+
+    /// Loads the image at `path` and queues uploading it, unless it was
+    /// loaded before. The first texture loaded is the default texture.
+    pub fn load<P: AsRef<Path>>(&mut self,
+                                uploads: &mut UploadManager,
+                                path: P) -> Result<TextureHandle> {
+        let path = path.as_ref();
+        // Different spellings of a path refer to the same texture
+        let key = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        if let Some(&handle) = self.handles.get(&key) {
+            return Ok(handle);
+        }
+        if self.textures.len() as u32 == MAX_TEXTURES {
+            return Err(format!("unable to load {}, all {} texture slots are in use",
+                path.display(), MAX_TEXTURES).into());
+        }
+
+        let image = vulkan::create_texture_image(&self.device,
+                                                 &self.allocator,
+                                                 uploads,
+                                                 &path.to_string_lossy())
+            .context(format!("loading texture {}", path.display()))?;
+        let image_view = vulkan::create_texture_image_view(self.device.clone(), &image)?;
+        let sampler = vulkan::create_texture_sampler(self.device.clone(),
+                                                     image.mip_levels)?;
+
+        let handle = TextureHandle(self.textures.len() as u32);
+        self.textures.push(Texture {
+            path: key.clone(),
+            image,
+            image_view,
+            sampler,
+        });
+        self.handles.insert(key, handle);
+        Ok(handle)
+    }
+
+    /// The texture used where none is assigned.
+    pub fn default_texture(&self) -> TextureHandle {
+        TextureHandle(0)
+    }
+
+    pub fn get(&self, handle: TextureHandle) -> &Texture {
+        &self.textures[handle.0 as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    /// Descriptors for all `MAX_TEXTURES` array elements. Unused elements
+    /// repeat the default texture, as every element has to be valid.
+    pub fn descriptor_image_infos(&self) -> Vec<DescriptorImageInfo> {
+        assert!(!self.textures.is_empty(), "no textures loaded");
+        (0..MAX_TEXTURES as usize).map(|i| {
+            let texture = self.textures.get(i).unwrap_or(&self.textures[0]);
+            DescriptorImageInfo::builder()
+                .sampler(&texture.sampler)
+                .image_view(&texture.image_view)
+                .image_layout(ImageLayout::ShaderReadOnlyOptimal)
+                .build()
+        }).collect()
+    }
+}
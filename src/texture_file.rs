@@ -0,0 +1,502 @@
+/*
+ * texture_file.rs
+ * Loading of pre-compressed textures with all of their mip
+ * levels and array layers from KTX2 and DDS containers.
+ */
+use std::fs;
+use std::path::Path;
+
+use voodoo::Format;
+
+use bcn::{self, BlockFormat};
+use error::Result;
+
+/// The pixels of a texture file, ready to be copied into an image.
+pub struct TextureData {
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    pub mip_levels: u32,
+    pub array_layers: u32,
+    /// The data of each mip level, with the layers of a level back to back.
+    pub levels: Vec<Vec<u8>>,
+}
+
+/// Returns true if `path` names a container `load` understands.
+pub fn is_texture_file(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("ktx2") || ext.eq_ignore_ascii_case("dds"),
+        None => false,
+    }
+}
+
+/// Reads the KTX2 or DDS file at `path`.
+pub fn load(path: &Path) -> Result<TextureData> {
+    let data = fs::read(path)?;
+    if data.starts_with(&KTX2_IDENTIFIER) {
+        parse_ktx2(&data)
+    } else if data.starts_with(b"DDS ") {
+        parse_dds(&data)
+    } else {
+        Err("not a KTX2 or DDS file".into())
+    }
+}
+
+/// The edge length in texels and the size in bytes of the blocks `format`
+/// is stored in. Uncompressed formats have blocks of one texel.
+pub fn block_layout(format: Format) -> Option<(u32, usize)> {
+    match format {
+        Format::R8G8B8A8Unorm | Format::R8G8B8A8Srgb | Format::R8G8B8A8Snorm |
+        Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb => Some((1, 4)),
+        Format::R16G16B16A16Sfloat => Some((1, 8)),
+        Format::R32G32B32A32Sfloat => Some((1, 16)),
+        Format::Bc1RgbUnormBlock | Format::Bc1RgbSrgbBlock |
+        Format::Bc1RgbaUnormBlock | Format::Bc1RgbaSrgbBlock |
+        Format::Bc4UnormBlock | Format::Bc4SnormBlock => Some((4, 8)),
+        Format::Bc2UnormBlock | Format::Bc2SrgbBlock |
+        Format::Bc3UnormBlock | Format::Bc3SrgbBlock |
+        Format::Bc5UnormBlock | Format::Bc5SnormBlock |
+        Format::Bc6hUfloatBlock | Format::Bc6hSfloatBlock |
+        Format::Bc7UnormBlock | Format::Bc7SrgbBlock => Some((4, 16)),
+        _ => None,
+    }
+}
+
+/// The block compression of `format` and whether it is sRGB encoded.
+fn block_format(format: Format) -> Option<(BlockFormat, bool)> {
+    match format {
+        Format::Bc1RgbUnormBlock | Format::Bc1RgbaUnormBlock => Some((BlockFormat::Bc1, false)),
+        Format::Bc1RgbSrgbBlock | Format::Bc1RgbaSrgbBlock => Some((BlockFormat::Bc1, true)),
+        Format::Bc2UnormBlock => Some((BlockFormat::Bc2, false)),
+        Format::Bc2SrgbBlock => Some((BlockFormat::Bc2, true)),
+        Format::Bc3UnormBlock => Some((BlockFormat::Bc3, false)),
+        Format::Bc3SrgbBlock => Some((BlockFormat::Bc3, true)),
+        Format::Bc4UnormBlock => Some((BlockFormat::Bc4 { signed: false }, false)),
+        Format::Bc4SnormBlock => Some((BlockFormat::Bc4 { signed: true }, false)),
+        Format::Bc5UnormBlock => Some((BlockFormat::Bc5 { signed: false }, false)),
+        Format::Bc5SnormBlock => Some((BlockFormat::Bc5 { signed: true }, false)),
+        Format::Bc7UnormBlock => Some((BlockFormat::Bc7, false)),
+        Format::Bc7SrgbBlock => Some((BlockFormat::Bc7, true)),
+        _ => None,
+    }
+}
+
+fn mip_extent(size: u32, level: u32) -> u32 {
+    (size >> level).max(1)
+}
+
+impl TextureData {
+    /// Size in bytes of one layer of mip level `level`.
+    pub fn layer_size(&self, level: u32) -> usize {
+        let (block_dim, block_bytes) = block_layout(self.format)
+            .expect("texture data of unknown format");
+        let blocks_x = (mip_extent(self.width, level) + block_dim - 1) / block_dim;
+        let blocks_y = (mip_extent(self.height, level) + block_dim - 1) / block_dim;
+        blocks_x as usize * blocks_y as usize * block_bytes
+    }
+
+    pub fn is_block_compressed(&self) -> bool {
+        block_layout(self.format).map_or(false, |(block_dim, _)| block_dim > 1)
+    }
+
+    /// Decompresses block compressed data to RGBA8, for devices which can't
+    /// sample `format`. The result keeps the sRGB encoding of the source.
+    ///
+    /// BC6H isn't supported: its HDR texels don't fit RGBA8, so those
+    /// textures need a device which samples BC6H.
+    pub fn decompress(&self) -> Result<TextureData> {
+        let (block, srgb) = match block_format(self.format) {
+            Some(found) => found,
+            None if self.format == Format::Bc6hUfloatBlock ||
+                    self.format == Format::Bc6hSfloatBlock => {
+                return Err("BC6H textures can't be decompressed on the CPU, the device \
+                    has to support sampling them".into());
+            },
+            None => return Err(format!("no CPU decompression for {:?}", self.format).into()),
+        };
+        let format = match block {
+            BlockFormat::Bc4 { signed: true } | BlockFormat::Bc5 { signed: true } =>
+                Format::R8G8B8A8Snorm,
+            _ if srgb => Format::R8G8B8A8Srgb,
+            _ => Format::R8G8B8A8Unorm,
+        };
+        let opaque = self.format == Format::Bc1RgbUnormBlock ||
+            self.format == Format::Bc1RgbSrgbBlock;
+
+        let mut levels = Vec::with_capacity(self.levels.len());
+        for (level, data) in self.levels.iter().enumerate() {
+            let layer_size = self.layer_size(level as u32);
+            let width = mip_extent(self.width, level as u32);
+            let height = mip_extent(self.height, level as u32);
+
+            let mut pixels = Vec::with_capacity(width as usize * height as usize * 4 *
+                self.array_layers as usize);
+            for layer in data.chunks(layer_size) {
+                let decompressed = bcn::decompress(block, width, height, layer)
+                    .ok_or("truncated block data")?;
+                pixels.extend_from_slice(&decompressed);
+            }
+            // BC1 without alpha has black where the RGBA variant is transparent
+            if opaque {
+                for texel in pixels.chunks_mut(4) {
+                    texel[3] = 255;
+                }
+            }
+            levels.push(pixels);
+        }
+
+        Ok(TextureData {
+            format,
+            width: self.width,
+            height: self.height,
+            mip_levels: self.mip_levels,
+            array_layers: self.array_layers,
+            levels,
+        })
+    }
+
+    /// Checks the size and level count read from the header.
+    fn check_header(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 || self.array_layers == 0 {
+            return Err(format!("invalid texture size {}x{} with {} layers",
+                self.width, self.height, self.array_layers).into());
+        }
+        let max_levels = 32 - self.width.max(self.height).leading_zeros();
+        if self.mip_levels == 0 || self.mip_levels > max_levels {
+            return Err(format!("{} mip levels for a {}x{} texture",
+                self.mip_levels, self.width, self.height).into());
+        }
+        Ok(())
+    }
+
+    /// Checks the size of every level against the header.
+    fn validate(self) -> Result<TextureData> {
+        for (level, data) in self.levels.iter().enumerate() {
+            let expected = self.layer_size(level as u32) * self.array_layers as usize;
+            if data.len() != expected {
+                return Err(format!("mip level {} has {} bytes, expected {}",
+                    level, data.len(), expected).into());
+            }
+        }
+        Ok(self)
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+        .ok_or_else(|| "unexpected end of file".into())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    Ok(read_u32(data, offset)? as u64 | (read_u32(data, offset + 4)? as u64) << 32)
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    offset.checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| "unexpected end of file".into())
+}
+
+static KTX2_IDENTIFIER: [u8; 12] = [
+    0xab, 0x4b, 0x54, 0x58, 0x20, 0x32, 0x30, 0xbb, 0x0d, 0x0a, 0x1a, 0x0a,
+];
+
+fn ktx2_format(vk_format: u32) -> Option<Format> {
+    Some(match vk_format {
+        37 => Format::R8G8B8A8Unorm,
+        38 => Format::R8G8B8A8Snorm,
+        43 => Format::R8G8B8A8Srgb,
+        44 => Format::B8G8R8A8Unorm,
+        50 => Format::B8G8R8A8Srgb,
+        97 => Format::R16G16B16A16Sfloat,
+        109 => Format::R32G32B32A32Sfloat,
+        131 => Format::Bc1RgbUnormBlock,
+        132 => Format::Bc1RgbSrgbBlock,
+        133 => Format::Bc1RgbaUnormBlock,
+        134 => Format::Bc1RgbaSrgbBlock,
+        135 => Format::Bc2UnormBlock,
+        136 => Format::Bc2SrgbBlock,
+        137 => Format::Bc3UnormBlock,
+        138 => Format::Bc3SrgbBlock,
+        139 => Format::Bc4UnormBlock,
+        140 => Format::Bc4SnormBlock,
+        141 => Format::Bc5UnormBlock,
+        142 => Format::Bc5SnormBlock,
+        143 => Format::Bc6hUfloatBlock,
+        144 => Format::Bc6hSfloatBlock,
+        145 => Format::Bc7UnormBlock,
+        146 => Format::Bc7SrgbBlock,
+        _ => return None,
+    })
+}
+
+fn parse_ktx2(data: &[u8]) -> Result<TextureData> {
+    let vk_format = read_u32(data, 12)?;
+    let width = read_u32(data, 20)?;
+    let height = read_u32(data, 24)?;
+    let depth = read_u32(data, 28)?;
+    let layer_count = read_u32(data, 32)?;
+    let face_count = read_u32(data, 36)?;
+    let level_count = read_u32(data, 40)?;
+    let supercompression = read_u32(data, 44)?;
+
+    let format = ktx2_format(vk_format)
+        .ok_or_else(|| format!("unsupported KTX2 vkFormat {}", vk_format))?;
+    if supercompression != 0 {
+        return Err(format!("unsupported KTX2 supercompression scheme {}",
+            supercompression).into());
+    }
+    if depth > 1 {
+        return Err("3D KTX2 textures are not supported".into());
+    }
+
+    // Zero layers means not an array. Zero levels leaves generating the
+    // mip chain to the loader, the file only holds the first level.
+    let mut texture = TextureData {
+        format,
+        width,
+        // One dimensional textures have a height of zero
+        height: height.max(1),
+        mip_levels: level_count.max(1),
+        array_layers: layer_count.max(1) * face_count,
+        levels: Vec::new(),
+    };
+    texture.check_header()?;
+
+    for level in 0..texture.mip_levels as usize {
+        let index = 80 + level * 24;
+        let offset = read_u64(data, index)? as usize;
+        let length = read_u64(data, index + 8)? as usize;
+        texture.levels.push(slice(data, offset, length)?.to_vec());
+    }
+    texture.validate()
+}
+
+const DDSD_MIPMAPCOUNT: u32 = 0x2_0000;
+const DDPF_FOURCC: u32 = 0x4;
+const DDPF_RGB: u32 = 0x40;
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+const DDSCAPS2_VOLUME: u32 = 0x20_0000;
+const DDS_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+const DDS_DIMENSION_TEXTURE2D: u32 = 3;
+
+fn fourcc(code: &[u8; 4]) -> u32 {
+    code[0] as u32 | (code[1] as u32) << 8 | (code[2] as u32) << 16 | (code[3] as u32) << 24
+}
+
+fn dxgi_format(dxgi_format: u32) -> Option<Format> {
+    Some(match dxgi_format {
+        2 => Format::R32G32B32A32Sfloat,
+        10 => Format::R16G16B16A16Sfloat,
+        28 => Format::R8G8B8A8Unorm,
+        29 => Format::R8G8B8A8Srgb,
+        31 => Format::R8G8B8A8Snorm,
+        71 => Format::Bc1RgbaUnormBlock,
+        72 => Format::Bc1RgbaSrgbBlock,
+        74 => Format::Bc2UnormBlock,
+        75 => Format::Bc2SrgbBlock,
+        77 => Format::Bc3UnormBlock,
+        78 => Format::Bc3SrgbBlock,
+        80 => Format::Bc4UnormBlock,
+        81 => Format::Bc4SnormBlock,
+        83 => Format::Bc5UnormBlock,
+        84 => Format::Bc5SnormBlock,
+        87 => Format::B8G8R8A8Unorm,
+        91 => Format::B8G8R8A8Srgb,
+        95 => Format::Bc6hUfloatBlock,
+        96 => Format::Bc6hSfloatBlock,
+        98 => Format::Bc7UnormBlock,
+        99 => Format::Bc7SrgbBlock,
+        _ => return None,
+    })
+}
+
+/// The format of a DDS file without the DX10 header extension.
+fn legacy_dds_format(data: &[u8]) -> Result<Format> {
+    let flags = read_u32(data, 80)?;
+    let code = read_u32(data, 84)?;
+
+    if flags & DDPF_FOURCC != 0 {
+        let format = match code {
+            c if c == fourcc(b"DXT1") => Format::Bc1RgbaUnormBlock,
+            c if c == fourcc(b"DXT2") || c == fourcc(b"DXT3") => Format::Bc2UnormBlock,
+            c if c == fourcc(b"DXT4") || c == fourcc(b"DXT5") => Format::Bc3UnormBlock,
+            c if c == fourcc(b"ATI1") || c == fourcc(b"BC4U") => Format::Bc4UnormBlock,
+            c if c == fourcc(b"BC4S") => Format::Bc4SnormBlock,
+            c if c == fourcc(b"ATI2") || c == fourcc(b"BC5U") => Format::Bc5UnormBlock,
+            c if c == fourcc(b"BC5S") => Format::Bc5SnormBlock,
+            // D3DFMT_A16B16G16R16F and D3DFMT_A32B32G32R32F
+            113 => Format::R16G16B16A16Sfloat,
+            116 => Format::R32G32B32A32Sfloat,
+            _ => return Err(format!("unsupported DDS FourCC {:#x}", code).into()),
+        };
+        return Ok(format);
+    }
+
+    let masks = (read_u32(data, 88)?, read_u32(data, 92)?, read_u32(data, 96)?,
+                 read_u32(data, 100)?, read_u32(data, 104)?);
+    if flags & DDPF_RGB != 0 {
+        match masks {
+            (32, 0xff, 0xff00, 0xff_0000, 0xff00_0000) => return Ok(Format::R8G8B8A8Unorm),
+            (32, 0xff_0000, 0xff00, 0xff, 0xff00_0000) => return Ok(Format::B8G8R8A8Unorm),
+            _ => {},
+        }
+    }
+    Err(format!("unsupported DDS pixel format with flags {:#x} and masks {:?}",
+        flags, masks).into())
+}
+
+fn parse_dds(data: &[u8]) -> Result<TextureData> {
+    if read_u32(data, 4)? != 124 {
+        return Err("invalid DDS header size".into());
+    }
+    let flags = read_u32(data, 8)?;
+    let height = read_u32(data, 12)?;
+    let width = read_u32(data, 16)?;
+    let mip_map_count = read_u32(data, 28)?;
+    let caps2 = read_u32(data, 112)?;
+
+    if caps2 & DDSCAPS2_VOLUME != 0 {
+        return Err("3D DDS textures are not supported".into());
+    }
+
+    let (format, array_layers, data_offset) = if read_u32(data, 84)? == fourcc(b"DX10") {
+        let dxgi = read_u32(data, 128)?;
+        let dimension = read_u32(data, 132)?;
+        let misc_flag = read_u32(data, 136)?;
+        let array_size = read_u32(data, 140)?;
+
+        let format = dxgi_format(dxgi)
+            .ok_or_else(|| format!("unsupported DXGI format {}", dxgi))?;
+        if dimension != DDS_DIMENSION_TEXTURE2D {
+            return Err(format!("unsupported DDS resource dimension {}", dimension).into());
+        }
+        let faces = if misc_flag & DDS_RESOURCE_MISC_TEXTURECUBE != 0 { 6 } else { 1 };
+        (format, array_size.max(1) * faces, 148)
+    } else {
+        let faces = if caps2 & DDSCAPS2_CUBEMAP != 0 { 6 } else { 1 };
+        (legacy_dds_format(data)?, faces, 128)
+    };
+
+    let mip_levels = if flags & DDSD_MIPMAPCOUNT != 0 { mip_map_count.max(1) } else { 1 };
+    let mut texture = TextureData {
+        format,
+        width,
+        height,
+        mip_levels,
+        array_layers,
+        levels: Vec::new(),
+    };
+    texture.check_header()?;
+    texture.levels = vec![Vec::new(); mip_levels as usize];
+
+    // DDS stores each layer with all of its mips, gather the layers by level
+    let mut offset = data_offset;
+    for _ in 0..array_layers {
+        for level in 0..mip_levels {
+            let size = texture.layer_size(level);
+            texture.levels[level as usize].extend_from_slice(slice(data, offset, size)?);
+            offset += size;
+        }
+    }
+    texture.validate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        for i in 0..4 {
+            data[offset + i] = (value >> (i * 8)) as u8;
+        }
+    }
+
+    /// A 4x4 DXT1 DDS file with a single solid red block.
+    fn dds_file() -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data[..4].copy_from_slice(b"DDS ");
+        put_u32(&mut data, 4, 124);
+        put_u32(&mut data, 12, 4);
+        put_u32(&mut data, 16, 4);
+        put_u32(&mut data, 80, DDPF_FOURCC);
+        put_u32(&mut data, 84, fourcc(b"DXT1"));
+        data.extend_from_slice(&[0x00, 0xf8, 0x00, 0xf8, 0, 0, 0, 0]);
+        data
+    }
+
+    /// A 2x2 RGBA8 KTX2 file with one level.
+    fn ktx2_file() -> Vec<u8> {
+        let mut data = vec![0; 104];
+        data[..12].copy_from_slice(&KTX2_IDENTIFIER);
+        put_u32(&mut data, 12, 37);
+        put_u32(&mut data, 16, 1);
+        put_u32(&mut data, 20, 2);
+        put_u32(&mut data, 24, 2);
+        put_u32(&mut data, 36, 1);
+        put_u32(&mut data, 40, 1);
+        // Level index: offset, length and uncompressed length
+        put_u32(&mut data, 80, 104);
+        put_u32(&mut data, 88, 16);
+        put_u32(&mut data, 96, 16);
+        data.extend((0..16).map(|i| i as u8));
+        data
+    }
+
+    #[test]
+    fn parses_dds() {
+        let texture = parse_dds(&dds_file()).unwrap();
+        assert_eq!(texture.format, Format::Bc1RgbaUnormBlock);
+        assert_eq!((texture.width, texture.height), (4, 4));
+        assert_eq!((texture.mip_levels, texture.array_layers), (1, 1));
+        assert_eq!(texture.levels, vec![vec![0x00, 0xf8, 0x00, 0xf8, 0, 0, 0, 0]]);
+
+        let decompressed = texture.decompress().unwrap();
+        assert_eq!(decompressed.format, Format::R8G8B8A8Unorm);
+        assert_eq!(&decompressed.levels[0][..4], [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn parses_ktx2() {
+        let texture = parse_ktx2(&ktx2_file()).unwrap();
+        assert_eq!(texture.format, Format::R8G8B8A8Unorm);
+        assert_eq!((texture.width, texture.height), (2, 2));
+        assert_eq!((texture.mip_levels, texture.array_layers), (1, 1));
+        assert_eq!(texture.levels, vec![(0..16).collect::<Vec<u8>>()]);
+    }
+
+    #[test]
+    fn truncated_dds() {
+        let data = dds_file();
+        assert!(parse_dds(&data[..data.len() - 1]).is_err());
+        assert!(parse_dds(&data[..100]).is_err());
+    }
+
+    #[test]
+    fn truncated_ktx2() {
+        let data = ktx2_file();
+        assert!(parse_ktx2(&data[..data.len() - 1]).is_err());
+        assert!(parse_ktx2(&data[..90]).is_err());
+    }
+
+    #[test]
+    fn invalid_dds_header_size() {
+        let mut data = dds_file();
+        put_u32(&mut data, 4, 100);
+        assert!(parse_dds(&data).is_err());
+    }
+
+    #[test]
+    fn bc6h_is_not_decompressed() {
+        let texture = TextureData {
+            format: Format::Bc6hUfloatBlock,
+            width: 4,
+            height: 4,
+            mip_levels: 1,
+            array_layers: 1,
+            levels: vec![vec![0; 16]],
+        };
+        assert!(texture.decompress().is_err());
+    }
+}
@@ -0,0 +1,484 @@
+/*
+ * upload.rs
+ * Batched transfers into device local buffers and images.
+ * Data is staged in a persistent ring buffer and the copies
+ * are recorded into one command buffer per batch, which
+ * signals a fence instead of stalling the queue.
+ */
+use std::collections::VecDeque;
+use std::mem;
+
+use voodoo::{Result as VdResult, Device, DeviceSize, CommandPool, CommandBuffer, CommandBufferLevel,
+            CommandBufferUsageFlags, Buffer, BufferUsageFlags, SharingMode, MemoryPropertyFlags,
+            Image, ImageLayout, ImageAspectFlags, ImageSubresourceLayers, BufferCopy,
+            BufferImageCopy, ImageBlit, Filter, Offset3d, Extent3d, Format, Fence,
+            FenceCreateFlags, SubmitInfo};
+
+use memory::{self, Allocator, Allocation};
+use vulkan;
+
+/// Size of the staging ring. Uploads larger than this get a staging buffer
+/// of their own, which lives until its batch has completed.
+pub const DEFAULT_STAGING_SIZE: DeviceSize = 16 << 20;
+
+/// Staged data starts at a multiple of this, which satisfies the offset
+/// requirements of buffer and image copies for all formats in use.
+const STAGING_ALIGNMENT: DeviceSize = 16;
+
+/// Identifies a submitted batch of uploads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UploadTicket(u64);
+
+/// A batch of uploads being recorded.
+struct Recording {
+    command_buffer: CommandBuffer,
+    /// Bytes of the ring taken by this batch, including padding.
+    ring_bytes: DeviceSize,
+    oversized: Vec<(Buffer, Allocation)>,
+}
+
+/// A submitted batch, whose staging data stays in use until `fence` signals.
+/// The command buffer and oversized staging buffers are only held on to.
+#[allow(dead_code)]
+struct Batch {
+    ticket: UploadTicket,
+    command_buffer: CommandBuffer,
+    fence: Fence,
+    ring_bytes: DeviceSize,
+    oversized: Vec<(Buffer, Allocation)>,
+}
+
+/// The ranges of the staging buffer in use, taken from the head and
+/// released from the tail in the same order.
+#[derive(Debug, Clone, PartialEq)]
+struct Ring {
+    capacity: DeviceSize,
+    /// Next free byte of the ring.
+    head: DeviceSize,
+    /// Bytes of the ring in use by the recording and submitted batches.
+    used: DeviceSize,
+}
+
+impl Ring {
+    fn new(capacity: DeviceSize) -> Ring {
+        Ring {
+            capacity,
+            head: 0,
+            used: 0,
+        }
+    }
+
+    /// Takes `size` contiguous bytes of the ring, wrapping around to its
+    /// start if the end has too little space left. Returns the offset of
+    /// the range and the bytes taken including padding.
+    fn reserve(&mut self, size: DeviceSize) -> Option<(DeviceSize, DeviceSize)> {
+        if self.used == self.capacity {
+            return None;
+        }
+        if self.used == 0 {
+            self.head = 0;
+        }
+        let tail = (self.head + self.capacity - self.used) % self.capacity;
+        let offset = memory::align_up(self.head, STAGING_ALIGNMENT);
+
+        let (offset, taken) = if self.head >= tail {
+            if offset + size <= self.capacity {
+                (offset, offset + size - self.head)
+            } else if size <= tail {
+                // Skip the end of the ring
+                (0, self.capacity - self.head + size)
+            } else {
+                return None;
+            }
+        } else if offset + size <= tail {
+            (offset, offset + size - self.head)
+        } else {
+            return None;
+        };
+
+        self.used += taken;
+        self.head = (offset + size) % self.capacity;
+        Some((offset, taken))
+    }
+
+    /// Releases the `taken` bytes reserved first.
+    fn release(&mut self, taken: DeviceSize) {
+        self.used -= taken;
+        if self.used == 0 {
+            self.head = 0;
+        }
+    }
+}
+
+/// Uploads data to the device in batches.
+///
+/// Destinations must not be used by other submissions until the batch
+/// containing their upload has completed, see `flush` and `wait`.
+pub struct UploadManager {
+    device: Device,
+    allocator: Allocator,
+    command_pool: CommandPool,
+    staging_buffer: Buffer,
+    staging_buffer_memory: Allocation,
+    ring: Ring,
+    recording: Option<Recording>,
+    in_flight: VecDeque<Batch>,
+    next_ticket: u64,
+}
+
+impl UploadManager {
+    pub fn new(device: &Device,
+               allocator: &Allocator,
+               command_pool: &CommandPool,
+               capacity: DeviceSize) -> VdResult<UploadManager> {
+        let capacity = memory::align_up(capacity, STAGING_ALIGNMENT);
+        let (staging_buffer, staging_buffer_memory) =
+            create_staging_buffer(device, allocator, capacity)?;
+
+        Ok(UploadManager {
+            device: device.clone(),
+            allocator: allocator.clone(),
+            command_pool: command_pool.clone(),
+            staging_buffer,
+            staging_buffer_memory,
+            ring: Ring::new(capacity),
+            recording: None,
+            in_flight: VecDeque::new(),
+            // Ticket 0 precedes every batch
+            next_ticket: 1,
+        })
+    }
+
+    /// Queues copying `data` into `dst` at `dst_offset`.
+    pub fn upload_buffer<T: Copy>(&mut self,
+                                  data: &[T],
+                                  dst: &Buffer,
+                                  dst_offset: DeviceSize) -> VdResult<()> {
+        let size = (mem::size_of::<T>() * data.len()) as DeviceSize;
+        let (src, src_offset) = self.stage(data)?;
+
+        let region = BufferCopy::builder()
+            .src_offset(src_offset)
+            .dst_offset(dst_offset)
+            .size(size)
+            .build();
+
+        let recording = self.recording.as_ref().unwrap();
+        let src = src.as_ref().unwrap_or(&self.staging_buffer);
+        unsafe {
+            recording.command_buffer.copy_buffer(src, dst, &[region]);
+        }
+        Ok(())
+    }
+
+    /// Queues copying `levels`, the tightly packed pixels of the first mip
+    /// levels of `image` with the `array_layers` layers of a level back to
+    /// back, and generates its remaining levels up to `mip_levels` by
+    /// blitting each from the one before. Leaves the image in
+    /// `ShaderReadOnlyOptimal` layout.
+    ///
+    /// Generating levels requires `format` to support linearly filtered
+    /// blits, see `vulkan::supports_linear_blit`.
+    pub fn upload_image(&mut self,
+                        image: &Image,
+                        format: Format,
+                        width: u32,
+                        height: u32,
+                        mip_levels: u32,
+                        array_layers: u32,
+                        levels: &[&[u8]]) -> VdResult<()> {
+        let provided = levels.len() as u32;
+        if provided == 0 || provided > mip_levels {
+            return Err(format!("{} mip levels given for an image with {}",
+                provided, mip_levels).into());
+        }
+
+        vulkan::record_layout_transition(&self.recording()?.command_buffer, image, format,
+            ImageLayout::Undefined, ImageLayout::TransferDstOptimal, 0, mip_levels)?;
+
+        for (level, pixels) in levels.iter().enumerate() {
+            let level = level as u32;
+            let (src, src_offset) = self.stage(*pixels)?;
+
+            let region = BufferImageCopy::builder()
+                .buffer_offset(src_offset)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(color_subresource_layers(level, array_layers))
+                .image_offset(Offset3d::builder().x(0).y(0).z(0).build())
+                .image_extent(Extent3d::builder()
+                    .width(mip_extent(width, level))
+                    .height(mip_extent(height, level))
+                    .depth(1)
+                    .build())
+                .build();
+
+            let recording = self.recording.as_ref().unwrap();
+            let src = src.as_ref().unwrap_or(&self.staging_buffer);
+            unsafe {
+                recording.command_buffer.copy_buffer_to_image(src, image,
+                    ImageLayout::TransferDstOptimal, &[region]);
+            }
+        }
+
+        let command_buffer = &self.recording.as_ref().unwrap().command_buffer;
+        for level in provided..mip_levels {
+            vulkan::record_layout_transition(command_buffer, image, format,
+                ImageLayout::TransferDstOptimal, ImageLayout::TransferSrcOptimal, level - 1, 1)?;
+
+            let blit = ImageBlit::builder()
+                .src_subresource(color_subresource_layers(level - 1, array_layers))
+                .src_offsets([Offset3d::builder().x(0).y(0).z(0).build(),
+                              mip_corner(width, height, level - 1)])
+                .dst_subresource(color_subresource_layers(level, array_layers))
+                .dst_offsets([Offset3d::builder().x(0).y(0).z(0).build(),
+                              mip_corner(width, height, level)])
+                .build();
+            unsafe {
+                command_buffer.blit_image(image, ImageLayout::TransferSrcOptimal, image,
+                    ImageLayout::TransferDstOptimal, &[blit], Filter::Linear);
+            }
+        }
+
+        if mip_levels == provided {
+            return vulkan::record_layout_transition(command_buffer, image, format,
+                ImageLayout::TransferDstOptimal, ImageLayout::ShaderReadOnlyOptimal,
+                0, mip_levels);
+        }
+        // Levels which were blitted from are left in TransferSrcOptimal
+        if provided > 1 {
+            vulkan::record_layout_transition(command_buffer, image, format,
+                ImageLayout::TransferDstOptimal, ImageLayout::ShaderReadOnlyOptimal,
+                0, provided - 1)?;
+        }
+        vulkan::record_layout_transition(command_buffer, image, format,
+            ImageLayout::TransferSrcOptimal, ImageLayout::ShaderReadOnlyOptimal,
+            provided - 1, mip_levels - provided)?;
+        vulkan::record_layout_transition(command_buffer, image, format,
+            ImageLayout::TransferDstOptimal, ImageLayout::ShaderReadOnlyOptimal,
+            mip_levels - 1, 1)
+    }
+
+    /// Submits the uploads queued so far and returns the ticket of their
+    /// batch. Returns the ticket of the last batch if nothing was queued.
+    pub fn flush(&mut self) -> VdResult<UploadTicket> {
+        let recording = match self.recording.take() {
+            Some(recording) => recording,
+            None => return Ok(UploadTicket(self.next_ticket - 1)),
+        };
+        recording.command_buffer.end()?;
+
+        let fence = Fence::new(self.device.clone(), FenceCreateFlags::empty())?;
+        let command_buffers = [recording.command_buffer.handle()];
+        let submit_info = SubmitInfo::builder()
+            .command_buffers(&command_buffers[..])
+            .build();
+        self.device.queue(0).unwrap().submit(&[submit_info], Some(fence.handle()))?;
+
+        let ticket = UploadTicket(self.next_ticket);
+        self.next_ticket += 1;
+        self.in_flight.push_back(Batch {
+            ticket,
+            command_buffer: recording.command_buffer,
+            fence,
+            ring_bytes: recording.ring_bytes,
+            oversized: recording.oversized,
+        });
+        Ok(ticket)
+    }
+
+    /// Blocks until the batch `ticket` and all batches before it have
+    /// finished on the device.
+    pub fn wait(&mut self, ticket: UploadTicket) -> VdResult<()> {
+        while self.in_flight.front().map_or(false, |batch| batch.ticket <= ticket) {
+            self.wait_oldest()?;
+        }
+        Ok(())
+    }
+
+    /// Submits all queued uploads and waits for them to finish.
+    pub fn finish(&mut self) -> VdResult<()> {
+        let ticket = self.flush()?;
+        self.wait(ticket)
+    }
+
+    fn wait_oldest(&mut self) -> VdResult<()> {
+        if let Some(batch) = self.in_flight.pop_front() {
+            self.device.wait_for_fences(&[batch.fence.handle()], true, u64::max_value())?;
+            self.retire(batch);
+        }
+        Ok(())
+    }
+
+    /// Releases the staging memory of a completed batch.
+    fn retire(&mut self, batch: Batch) {
+        self.ring.release(batch.ring_bytes);
+    }
+
+    /// The batch being recorded, beginning a new one if necessary.
+    fn recording(&mut self) -> VdResult<&mut Recording> {
+        if self.recording.is_none() {
+            let command_buffer = self.command_pool
+                .allocate_command_buffer(CommandBufferLevel::Primary)?;
+            command_buffer.begin(CommandBufferUsageFlags::ONE_TIME_SUBMIT)?;
+            self.recording = Some(Recording {
+                command_buffer,
+                ring_bytes: 0,
+                oversized: Vec::new(),
+            });
+        }
+        Ok(self.recording.as_mut().unwrap())
+    }
+
+    /// Copies `data` into staging memory for the batch being recorded.
+    ///
+    /// Returns the buffer holding the data, `None` meaning the ring, and
+    /// the offset of the data within it.
+    fn stage<T: Copy>(&mut self, data: &[T]) -> VdResult<(Option<Buffer>, DeviceSize)> {
+        let size = (mem::size_of::<T>() * data.len()) as DeviceSize;
+
+        if size > self.ring.capacity {
+            let (buffer, memory) = create_staging_buffer(&self.device, &self.allocator, size)?;
+            copy_to_mapped(&memory, 0, data)?;
+            self.recording()?.oversized.push((buffer.clone(), memory));
+            return Ok((Some(buffer), 0));
+        }
+
+        let (offset, taken) = loop {
+            if let Some(reserved) = self.ring.reserve(size) {
+                break reserved;
+            }
+            // Make room by completing the oldest batch. If the batch being
+            // recorded holds the whole ring it has to be submitted first.
+            if self.in_flight.is_empty() {
+                self.flush()?;
+            }
+            self.wait_oldest()?;
+        };
+        self.recording()?.ring_bytes += taken;
+
+        copy_to_mapped(&self.staging_buffer_memory, offset, data)?;
+        Ok((None, offset))
+    }
+}
+
+impl Drop for UploadManager {
+    fn drop(&mut self) {
+        // The staging memory must outlive the copies reading from it
+        let _ = self.finish();
+    }
+}
+
+/// Size of mip level `level` along an axis which is `size` at level 0.
+fn mip_extent(size: u32, level: u32) -> u32 {
+    (size >> level).max(1)
+}
+
+/// The far corner of mip level `level`, as used for blit regions.
+fn mip_corner(width: u32, height: u32, level: u32) -> Offset3d {
+    Offset3d::builder()
+        .x(mip_extent(width, level) as i32)
+        .y(mip_extent(height, level) as i32)
+        .z(1)
+        .build()
+}
+
+fn color_subresource_layers(mip_level: u32, layer_count: u32) -> ImageSubresourceLayers {
+    ImageSubresourceLayers::builder()
+        .aspect_mask(ImageAspectFlags::COLOR)
+        .mip_level(mip_level)
+        .base_array_layer(0)
+        .layer_count(layer_count)
+        .build()
+}
+
+fn create_staging_buffer(device: &Device,
+                         allocator: &Allocator,
+                         size: DeviceSize) -> VdResult<(Buffer, Allocation)> {
+    let buffer = Buffer::builder()
+        .size(size)
+        .usage(BufferUsageFlags::TRANSFER_SRC)
+        .sharing_mode(SharingMode::Exclusive)
+        .build(device.clone())?;
+    let memory = allocator.allocate_buffer(&buffer,
+        MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT)?;
+    Ok((buffer, memory))
+}
+
+fn copy_to_mapped<T: Copy>(memory: &Allocation,
+                           offset: DeviceSize,
+                           data: &[T]) -> VdResult<()> {
+    let size = (mem::size_of::<T>() * data.len()) as DeviceSize;
+    let mut mapping = unsafe { memory.map_range(offset, size)? };
+    mapping.copy_from_slice(data);
+    memory.unmap(mapping);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserves_aligned_ranges() {
+        let mut ring = Ring::new(256);
+        assert_eq!(ring.reserve(10), Some((0, 10)));
+        // Padded up to the next aligned offset
+        assert_eq!(ring.reserve(20), Some((16, 26)));
+        assert_eq!(ring.used, 36);
+        assert_eq!(ring.head, 36);
+    }
+
+    #[test]
+    fn wraps_around_to_the_start() {
+        let mut ring = Ring::new(256);
+        assert_eq!(ring.reserve(96), Some((0, 96)));
+        assert_eq!(ring.reserve(128), Some((96, 128)));
+        ring.release(96);
+        // 32 bytes are left at the end, which are skipped
+        assert_eq!(ring.reserve(64), Some((0, 96)));
+        assert_eq!(ring.used, 224);
+        assert_eq!(ring.head, 64);
+
+        ring.release(128);
+        ring.release(96);
+        assert_eq!(ring, Ring::new(256));
+    }
+
+    #[test]
+    fn fails_when_full() {
+        let mut ring = Ring::new(256);
+        assert_eq!(ring.reserve(128), Some((0, 128)));
+        assert_eq!(ring.reserve(128), Some((128, 128)));
+        assert_eq!(ring.reserve(1), None);
+
+        ring.release(128);
+        // Only the first 128 bytes are free
+        assert_eq!(ring.reserve(160), None);
+        assert_eq!(ring.reserve(128), Some((0, 128)));
+    }
+
+    #[test]
+    fn does_not_overtake_the_tail() {
+        let mut ring = Ring::new(256);
+        assert_eq!(ring.reserve(64), Some((0, 64)));
+        assert_eq!(ring.reserve(128), Some((64, 128)));
+        assert_eq!(ring.reserve(64), Some((192, 64)));
+        ring.release(64);
+        assert_eq!(ring.reserve(48), Some((0, 48)));
+        // Only 16 bytes are left before the tail at 64
+        assert_eq!(ring.reserve(17), None);
+        assert_eq!(ring.reserve(16), Some((48, 16)));
+    }
+
+    #[test]
+    fn empty_rings_restart_at_the_start() {
+        let mut ring = Ring::new(256);
+        assert_eq!(ring.reserve(100), Some((0, 100)));
+        assert_eq!(ring.reserve(100), Some((112, 112)));
+        ring.release(100);
+        ring.release(112);
+        // Not split by the ranges released before
+        assert_eq!(ring.reserve(200), Some((0, 200)));
+    }
+}
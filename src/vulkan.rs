@@ -4,20 +4,31 @@
  */
 #![allow(dead_code, unused_variables)]
 
-use std::result::Result;
 use std::mem;
 use std::path::Path;
 use std::hash::{Hash, Hasher};
 use std::collections::{HashMap, BTreeSet};
 use std::ffi::{CStr};
 use std::cmp;
+use std::os::raw::c_void;
+use std::path::PathBuf;
 
 use smallvec::SmallVec;
 use ordered_float::OrderedFloat;
+use image::{self, RgbaImage, FilterType, imageops};
 
 use voodoo::*;
 use voodoo::{Result as VdResult};
 
+use error::{Result, Error};
+use memory::{Allocator, Allocation};
+use upload::UploadManager;
+use texture::MAX_TEXTURES;
+use texture_file::{self, TextureData};
+use reflect::{self, DescriptorBinding};
+use pipeline::{PipelineDesc, BlendMode};
+use scene::{Scene, DrawList};
+
 use voodoo_winit::winit::*;
 use voodoo_winit::winit::{Window, WindowBuilder};
 
@@ -34,6 +45,9 @@ static REQUIRED_DEVICE_EXTENSIONS: &[&str] = &[
     "VK_KHR_swapchain",
 ];
 
+/// `VK_REMAINING_ARRAY_LAYERS`, all layers from the base layer on.
+const REMAINING_ARRAY_LAYERS: u32 = !0;
+
 macro_rules! offset_of {
     ($ty:ty, $field:ident) => {
         unsafe { &(*(0 as *const $ty)).$field as *const _ as u32 }
@@ -95,6 +109,84 @@ impl Vertex {
     }
 }
 
+/// Per instance data of an object, one per copy of its mesh drawn.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct InstanceData {
+    /// Applied before the object's transform.
+    pub model: [[f32; 4]; 4],
+    /// Multiplied with the vertex color.
+    pub color: [f32; 4],
+}
+
+impl Default for InstanceData {
+    /// A single untransformed, uncolored copy.
+    fn default() -> InstanceData {
+        InstanceData {
+            model: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+            color: [1.0; 4],
+        }
+    }
+}
+
+/// An instance as the vertex shader reads it from the second vertex binding.
+/// Each draw gets its own copy of the instances of its object.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct InstanceVertex {
+    pub instance: InstanceData,
+    /// Index into the draw data buffer of the draw this copy belongs to.
+    pub draw_index: u32,
+}
+
+impl InstanceVertex {
+    pub fn binding_description() -> VertexInputBindingDescription {
+        VertexInputBindingDescription::builder()
+            .binding(1)
+            .stride(mem::size_of::<InstanceVertex>() as u32)
+            .input_rate(VertexInputRate::Instance)
+            .build()
+    }
+
+    /// The model matrix takes a location per column, starting after the
+    /// attributes of `Vertex`.
+    pub fn attribute_descriptions() -> [VertexInputAttributeDescription; 6] {
+        let instance = offset_of!(InstanceVertex, instance);
+        let column = |i: u32| {
+            VertexInputAttributeDescription::builder()
+                .binding(1)
+                .location(3 + i)
+                .format(Format::R32G32B32A32Sfloat)
+                .offset(instance + offset_of!(InstanceData, model) +
+                        i * mem::size_of::<[f32; 4]>() as u32)
+                .build()
+        };
+        [
+            column(0),
+            column(1),
+            column(2),
+            column(3),
+            VertexInputAttributeDescription::builder()
+                .binding(1)
+                .location(7)
+                .format(Format::R32G32B32A32Sfloat)
+                .offset(instance + offset_of!(InstanceData, color))
+                .build(),
+            VertexInputAttributeDescription::builder()
+                .binding(1)
+                .location(8)
+                .format(Format::R32Uint)
+                .offset(offset_of!(InstanceVertex, draw_index))
+                .build(),
+        ]
+    }
+}
+
 impl Hash for Vertex {
     fn hash<H: Hasher>(&self, state: &mut H) {
         let pos = [OrderedFloat(self.pos[0]), OrderedFloat(self.pos[1]),
@@ -119,29 +211,39 @@ impl Eq for Vertex {}
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct UniformBufferObject {
-    pub model: [[f32; 4]; 4],
     pub view: [[f32; 4]; 4],
     pub proj: [[f32; 4]; 4],
 }
 
+/// Per draw data, read by the vertex and culling shaders from a storage
+/// buffer. Must match `DrawData` in shader.vert and cull.comp.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct DrawData {
+    pub model: [[f32; 4]; 4],
+    /// Index into the texture array.
+    pub texture_index: u32,
+    /// Pads the struct to its std430 array stride.
+    pub _padding: [u32; 3],
+}
+
+/// Size in bytes of a `VkDrawIndexedIndirectCommand`.
+pub const DRAW_INDEXED_INDIRECT_COMMAND_SIZE: u32 = 20;
+
 pub fn init_instance(info: &ApplicationInfo<'static>) -> VdResult<Instance> {
-    let loader = Loader::new().unwrap();
+    let loader = Loader::new()?;
     Instance::builder()
         .application_info(info)
-        .enabled_extensions(&loader.enumerate_instance_extension_properties().unwrap())
+        .enabled_extensions(&loader.enumerate_instance_extension_properties()?)
         .build(loader)
 }
 
 pub fn init_window(name: String,
-                   events_loop: &EventsLoop) -> VdResult<Window> {
-    let window = WindowBuilder::new()
+                   events_loop: &EventsLoop) -> Result<Window> {
+    WindowBuilder::new()
         .with_title(name)
-        .build(events_loop);
-
-    match window {
-        Ok(window) => Result::<Window, voodoo::Error>::Ok(window),
-        Err(_) => Result::<Window, voodoo::Error>::Err(voodoo::Error::unspecified_dimensions()),
-    }
+        .build(events_loop)
+        .map_err(|err| Error::Message(format!("unable to create window: {}", err)))
 }
 
 /// Returns the list of layer names to be enabled.
@@ -164,7 +266,10 @@ fn enabled_layer_names<'ln>(loader: &Loader) -> SmallVec<[&'ln str; 16]> {
 
 /// Returns the queue family indices for the queue families supporting
 /// graphics and presentation on `physical_device`.
-fn find_queue_families(physical_device: &PhysicalDevice, surface: &SurfaceKhr)
+///
+/// Without a `surface` (headless rendering) the graphics family doubles as
+/// the presentation family.
+fn find_queue_families(physical_device: &PhysicalDevice, surface: Option<&SurfaceKhr>)
         -> VdResult<QueueFamilyIndices> {
     let queue_families = physical_device.queue_family_properties()?;
     let mut graphics_family_idx = None;
@@ -177,7 +282,10 @@ fn find_queue_families(physical_device: &PhysicalDevice, surface: &SurfaceKhr)
             graphics_family_idx = Some(i);
         }
 
-        let presentation_support = physical_device.surface_support_khr(i as u32, surface)?;
+        let presentation_support = match surface {
+            Some(surface) => physical_device.surface_support_khr(i as u32, surface)?,
+            None => queue_family.queue_flags().contains(QueueFlags::GRAPHICS),
+        };
         if queue_family.queue_count() > 0 && presentation_support {
             present_family_idx = Some(i);
         }
@@ -191,13 +299,30 @@ fn find_queue_families(physical_device: &PhysicalDevice, surface: &SurfaceKhr)
     Err("unable to find graphics and/or presentation queue family support".into())
 }
 
+/// Returns true if `features` include everything `create_device` enables.
+fn has_required_features(features: &PhysicalDeviceFeatures) -> bool {
+    features.sampler_anisotropy() &&
+        // The texture array is indexed with push constants
+        features.shader_sampled_image_array_dynamic_indexing()
+}
+
 /// Returns true if the specified physical device has the required features,
 /// extensions, queue families and if the supported swap chain has the correct
 /// presentation modes.
 fn device_is_suitable(physical_device: &PhysicalDevice,
-                      surface: &SurfaceKhr) -> VdResult<bool> {
+                      surface: Option<&SurfaceKhr>) -> VdResult<bool> {
     let device_features = physical_device.features();
 
+    let surface = match surface {
+        Some(surface) => surface,
+        // Headless rendering needs neither the swapchain extension nor a
+        // presentable surface.
+        None => {
+            find_queue_families(&physical_device, None)?;
+            return Ok(has_required_features(&device_features));
+        }
+    };
+
     let extensions_supported = physical_device.verify_extension_support(
         REQUIRED_DEVICE_EXTENSIONS)?;
 
@@ -209,32 +334,125 @@ fn device_is_suitable(physical_device: &PhysicalDevice,
             !swap_chain_details.present_modes.is_empty()
     }
 
-    let queue_family_indices = find_queue_families(&physical_device, surface)?;
+    let queue_family_indices = find_queue_families(&physical_device, Some(surface))?;
 
     Ok(extensions_supported &&
         swap_chain_adequate &&
-        device_features.sampler_anisotropy())
+        has_required_features(&device_features))
+}
+
+/// The environment variable overriding which physical device is used.
+pub static PHYSICAL_DEVICE_VAR: &str = "RUST_GAME_GPU";
+
+/// Selects a physical device by its index in the instance's device list or
+/// by a case insensitive substring of its name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    Index(usize),
+    Name(String),
 }
 
-/// Returns a physical device from the list of available physical devices if
-/// it meets the criteria specified in the above function.
+impl DeviceSelector {
+    pub fn parse(selector: &str) -> DeviceSelector {
+        match selector.trim().parse() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) => DeviceSelector::Name(selector.trim().to_lowercase()),
+        }
+    }
+
+    /// Reads the selector from `PHYSICAL_DEVICE_VAR`, if set.
+    pub fn from_env() -> Option<DeviceSelector> {
+        ::std::env::var(PHYSICAL_DEVICE_VAR).ok()
+            .filter(|selector| !selector.trim().is_empty())
+            .map(|selector| DeviceSelector::parse(&selector))
+    }
+
+    fn matches(&self, index: usize, physical_device: &PhysicalDevice) -> bool {
+        match *self {
+            DeviceSelector::Index(i) => i == index,
+            DeviceSelector::Name(ref name) => physical_device_name(physical_device)
+                .to_lowercase()
+                .contains(name.as_str()),
+        }
+    }
+}
+
+pub fn physical_device_name(physical_device: &PhysicalDevice) -> String {
+    physical_device.properties().device_name().to_string_lossy().into_owned()
+}
+
+/// Rates how well suited a physical device is for rendering. Device types
+/// dominate, ties are broken by the size of device local memory and then by
+/// the number of supported optional features.
+fn score_physical_device(physical_device: &PhysicalDevice) -> u64 {
+    let type_score: u64 = match physical_device.properties().device_type() {
+        PhysicalDeviceType::DiscreteGpu => 4,
+        PhysicalDeviceType::IntegratedGpu => 3,
+        PhysicalDeviceType::VirtualGpu => 2,
+        PhysicalDeviceType::Cpu => 1,
+        _ => 0,
+    };
+
+    let device_local_mib: u64 = physical_device.memory_properties().memory_heaps().iter()
+        .filter(|heap| heap.flags().contains(MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size() >> 20)
+        .sum();
+
+    let features = physical_device.features();
+    let feature_count = [
+        features.geometry_shader(),
+        features.tessellation_shader(),
+        features.multi_draw_indirect(),
+        features.fill_mode_non_solid(),
+        features.wide_lines(),
+        features.texture_compression_bc(),
+        features.shader_sampled_image_array_dynamic_indexing(),
+    ].iter().filter(|&&supported| supported).count() as u64;
+
+    // 2^40 MiB of device local memory leave room for any device type
+    (type_score << 56) + (device_local_mib.min((1 << 40) - 1) << 8) + feature_count
+}
+
+/// Returns the suitable physical device with the highest score, or the one
+/// matching `selector`.
+///
+/// Devices with equal scores are ordered by their index, so the choice stays
+/// the same between runs on the same machine.
 pub fn choose_physical_device(instance: &Instance,
-                              surface: &SurfaceKhr) -> VdResult<PhysicalDevice> {
-    let mut preferred_device = None;
-    for device in instance.physical_devices()? {
-        if device_is_suitable(&device, surface)? {
-            preferred_device = Some(device);
-            break;
+                              surface: Option<&SurfaceKhr>,
+                              selector: Option<&DeviceSelector>) -> VdResult<PhysicalDevice> {
+    let mut candidates = Vec::new();
+    for (index, device) in instance.physical_devices()?.into_iter().enumerate() {
+        // Devices lacking the queue families we need are unsuitable, not an error
+        let suitable = device_is_suitable(&device, surface).unwrap_or(false);
+        let score = score_physical_device(&device);
+        println!("Found device {}: {} (type: {:?}, score: {}{})", index,
+            physical_device_name(&device), device.properties().device_type(), score,
+            if suitable { "" } else { ", unsuitable" });
+        if suitable {
+            candidates.push((index, score, device));
         }
     }
-    if let Some(preferred_device) = preferred_device {
-        Ok(preferred_device)
-    } else {
-        panic!("Failed to find a suitable device.");
+
+    if let Some(selector) = selector {
+        return match candidates.into_iter().find(|&(index, _, ref device)| {
+            selector.matches(index, device)
+        }) {
+            Some((_, _, device)) => Ok(device),
+            None => Err(format!("no suitable physical device matches {:?}", selector).into()),
+        };
+    }
+
+    // Stable, so equal scores keep the lower index first
+    candidates.sort_by(|a, b| b.1.cmp(&a.1));
+
+    match candidates.into_iter().next() {
+        Some((_, _, device)) => Ok(device),
+        None => Err("no physical device supports the required features and extensions".into()),
     }
 }
 
-pub fn create_device(surface: &SurfaceKhr,
+pub fn create_device(surface: Option<&SurfaceKhr>,
                      physical_device: PhysicalDevice) -> VdResult<Device> {
     let queue_family_indices = find_queue_families(&physical_device, surface)?;
     let unique_queue_family_idxs: BTreeSet<u32> = [queue_family_indices.graphics_family_idx,
@@ -250,11 +468,24 @@ pub fn create_device(surface: &SurfaceKhr,
 
     let features = PhysicalDeviceFeatures::builder()
         .sampler_anisotropy(true)
+        .shader_sampled_image_array_dynamic_indexing(true)
+        // Optional, wireframe pipelines fail without it
+        .fill_mode_non_solid(physical_device.features().fill_mode_non_solid())
+        // Optional, without it instanced objects are drawn unculled
+        .draw_indirect_first_instance(physical_device.features().draw_indirect_first_instance())
+        // Optional, without it culled draws take an indirect draw call each
+        .multi_draw_indirect(physical_device.features().multi_draw_indirect())
         .build();
 
+    let extension_names: &[&str] = if surface.is_some() {
+        REQUIRED_DEVICE_EXTENSIONS
+    } else {
+        &[]
+    };
+
     Device::builder()
         .queue_create_infos(&queue_create_infos)
-        .enabled_extension_names(REQUIRED_DEVICE_EXTENSIONS)
+        .enabled_extension_names(extension_names)
         .enabled_features(&features)
         .build(physical_device)
 }
@@ -281,16 +512,57 @@ fn choose_swap_surface_format(available_formats: &[SurfaceFormatKhr]) -> Surface
         .build()
 }
 
-fn choose_swap_present_mode(available_present_modes: &[PresentModeKhr]) -> PresentModeKhr {
-    let mut best_mode = PresentModeKhr::MailboxKhr;
-    for &available_present_mode in available_present_modes {
-        if available_present_mode == PresentModeKhr::FifoKhr {
-            return available_present_mode;
-        } else if available_present_mode == PresentModeKhr::ImmediateKhr {
-            best_mode = available_present_mode;
+/// How frames are handed to the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresentPolicy {
+    /// Wait for vertical blank, never tears.
+    VSync,
+    /// Replace queued frames with newer ones, never tears.
+    LowLatency,
+    /// Present immediately, may tear.
+    Uncapped,
+    /// Wait for vertical blank unless the frame is late, may tear.
+    Adaptive,
+}
+
+impl PresentPolicy {
+    /// The present modes implementing this policy in order of preference.
+    /// Every list ends in `FifoKhr`, which all devices support.
+    fn preferred_modes(&self) -> &'static [PresentModeKhr] {
+        match *self {
+            PresentPolicy::VSync => &[PresentModeKhr::FifoKhr],
+            PresentPolicy::LowLatency => &[PresentModeKhr::MailboxKhr,
+                PresentModeKhr::FifoKhr],
+            PresentPolicy::Uncapped => &[PresentModeKhr::ImmediateKhr,
+                PresentModeKhr::MailboxKhr, PresentModeKhr::FifoKhr],
+            PresentPolicy::Adaptive => &[PresentModeKhr::FifoRelaxedKhr,
+                PresentModeKhr::FifoKhr],
+        }
+    }
+
+    /// The policy following this one, for cycling through all policies.
+    pub fn next(&self) -> PresentPolicy {
+        match *self {
+            PresentPolicy::VSync => PresentPolicy::LowLatency,
+            PresentPolicy::LowLatency => PresentPolicy::Uncapped,
+            PresentPolicy::Uncapped => PresentPolicy::Adaptive,
+            PresentPolicy::Adaptive => PresentPolicy::VSync,
         }
     }
-    best_mode
+}
+
+impl Default for PresentPolicy {
+    fn default() -> PresentPolicy {
+        PresentPolicy::VSync
+    }
+}
+
+fn choose_swap_present_mode(available_present_modes: &[PresentModeKhr],
+                            policy: PresentPolicy) -> PresentModeKhr {
+    policy.preferred_modes().iter()
+        .find(|mode| available_present_modes.contains(mode))
+        .cloned()
+        .unwrap_or(PresentModeKhr::FifoKhr)
 }
 
 fn choose_swap_extent(capabilities: &SurfaceCapabilitiesKhr,
@@ -314,11 +586,13 @@ fn choose_swap_extent(capabilities: &SurfaceCapabilitiesKhr,
 pub fn create_swapchain(surface: SurfaceKhr,
                         device: Device,
                         window_size: Option<Extent2d>,
+                        present_policy: PresentPolicy,
                         old_swapchain: Option<&SwapchainKhr>) -> VdResult<SwapchainKhr> {
-    let queue_family_indices = find_queue_families(device.physical_device(), &surface)?;
+    let queue_family_indices = find_queue_families(device.physical_device(), Some(&surface))?;
     let swapchain_details = SwapchainSupportDetails::new(&surface, device.physical_device())?;
     let surface_format = choose_swap_surface_format(&swapchain_details.formats);
-    let present_mode = choose_swap_present_mode(&swapchain_details.present_modes);
+    let present_mode = choose_swap_present_mode(&swapchain_details.present_modes,
+        present_policy);
     let extent = choose_swap_extent(&swapchain_details.capabilities, window_size);
 
     let mut image_count = swapchain_details.capabilities.min_image_count() + 1;
@@ -327,6 +601,14 @@ pub fn create_swapchain(surface: SurfaceKhr,
         image_count = swapchain_details.capabilities.max_image_count();
     }
 
+    // Transfers out of swapchain images allow capturing frames, where the
+    // surface supports them
+    let mut image_usage = ImageUsageFlags::COLOR_ATTACHMENT;
+    if swapchain_details.capabilities.supported_usage_flags()
+            .contains(ImageUsageFlags::TRANSFER_SRC) {
+        image_usage |= ImageUsageFlags::TRANSFER_SRC;
+    }
+
     let indices;
 
     let mut bldr = SwapchainKhr::builder();
@@ -336,7 +618,7 @@ pub fn create_swapchain(surface: SurfaceKhr,
         .image_color_space(surface_format.color_space())
         .image_extent(extent.clone())
         .image_array_layers(1)
-        .image_usage(ImageUsageFlags::COLOR_ATTACHMENT)
+        .image_usage(image_usage)
         .pre_transform(swapchain_details.capabilities.current_transform())
         .composite_alpha(CompositeAlphaFlagsKhr::OPAQUE)
         .present_mode(present_mode)
@@ -373,7 +655,7 @@ pub fn create_image_views(swapchain: &SwapchainKhr) -> VdResult<Vec<ImageView>>
             )
             .build(swapchain.device().clone(), Some(swapchain.clone()))
 
-    }).collect::<Result<Vec<_>, _>>()
+    }).collect::<VdResult<Vec<_>>>()
 }
 
 fn find_supported_format(device: &Device,
@@ -392,121 +674,145 @@ fn find_supported_format(device: &Device,
         }
     }
 
-    panic!("Failed to find supported format.")
+    Err(format!("none of the formats {:?} support {:?} with {:?} tiling",
+        candidates, features, tiling).into())
 }
 
-fn find_depth_format(device: &Device) -> VdResult<Format> {
+pub fn find_depth_format(device: &Device) -> VdResult<Format> {
     find_supported_format(device, &[Format::D32Sfloat, Format::D32SfloatS8Uint,
         Format::D24UnormS8Uint], ImageTiling::Optimal,
         FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
 }
 
-pub fn create_render_pass(device: Device,
-                          swapchain_image_format: Format) -> VdResult<RenderPass> {
-
-    let depth_image_format = find_depth_format(&device)?;
-
-    let color_attachment = AttachmentDescription::builder()
-        .format(swapchain_image_format)
-        .samples(SampleCountFlags::COUNT_1)
-        .load_op(AttachmentLoadOp::Clear)
-        .store_op(AttachmentStoreOp::Store)
-        .stencil_load_op(AttachmentLoadOp::DontCare)
-        .stencil_store_op(AttachmentStoreOp::DontCare)
-        .initial_layout(ImageLayout::Undefined)
-        .final_layout(ImageLayout::PresentSrcKhr)
-        .build();
-
-    let depth_attachment = AttachmentDescription::builder()
-        .format(depth_image_format)
-        .samples(SampleCountFlags::COUNT_1)
-        .load_op(AttachmentLoadOp::Clear)
-        .store_op(AttachmentStoreOp::DontCare)
-        .stencil_load_op(AttachmentLoadOp::DontCare)
-        .stencil_store_op(AttachmentStoreOp::DontCare)
-        .initial_layout(ImageLayout::Undefined)
-        .final_layout(ImageLayout::DepthStencilAttachmentOptimal)
-        .build();
+/// An attachment of a render pass, which stays in `layout` for the whole
+/// pass. Barriers recorded outside the pass move it in and out of it.
+#[derive(Debug, Clone, Copy)]
+pub struct AttachmentInfo {
+    pub format: Format,
+    pub load_op: AttachmentLoadOp,
+    pub store_op: AttachmentStoreOp,
+    pub layout: ImageLayout,
+}
 
-    let color_attachment_ref = AttachmentReference::builder()
-        .attachment(0)
-        .layout(ImageLayout::ColorAttachmentOptimal)
-        .build();
+/// Creates a render pass with a single subpass writing `color_attachments`
+/// and the optional `depth_attachment`, numbered in that order.
+pub fn create_render_pass(device: Device,
+                          color_attachments: &[AttachmentInfo],
+                          depth_attachment: Option<&AttachmentInfo>) -> VdResult<RenderPass> {
+    let attachments = color_attachments.iter().chain(depth_attachment).map(|info| {
+        AttachmentDescription::builder()
+            .format(info.format)
+            .samples(SampleCountFlags::COUNT_1)
+            .load_op(info.load_op)
+            .store_op(info.store_op)
+            .stencil_load_op(AttachmentLoadOp::DontCare)
+            .stencil_store_op(AttachmentStoreOp::DontCare)
+            .initial_layout(info.layout)
+            .final_layout(info.layout)
+            .build()
+    }).collect::<Vec<_>>();
 
-    let depth_attachment_ref = AttachmentReference::builder()
-        .attachment(1)
-        .layout(ImageLayout::DepthStencilAttachmentOptimal)
-        .build();
+    let color_attachment_refs = color_attachments.iter().enumerate().map(|(i, info)| {
+        AttachmentReference::builder()
+            .attachment(i as u32)
+            .layout(info.layout)
+            .build()
+    }).collect::<Vec<_>>();
 
-    let color_attachments = [color_attachment_ref];
+    let depth_attachment_ref = depth_attachment.map(|info| {
+        AttachmentReference::builder()
+            .attachment(color_attachments.len() as u32)
+            .layout(info.layout)
+            .build()
+    });
 
-    let subpass = SubpassDescription::builder()
-        .pipeline_bind_point(PipelineBindPoint::Graphics)
-        .color_attachments(&color_attachments[..])
-        .depth_stencil_attachment(&depth_attachment_ref)
-        .build();
+    let mut subpass = SubpassDescription::builder();
+    subpass.pipeline_bind_point(PipelineBindPoint::Graphics)
+        .color_attachments(&color_attachment_refs[..]);
+    if let Some(ref depth_attachment_ref) = depth_attachment_ref {
+        subpass.depth_stencil_attachment(depth_attachment_ref);
+    }
 
+    // Frames in flight share the depth image, so the depth tests of a frame
+    // wait for the writes of the one before, as do color writes
+    let mut stages = PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+    let mut writes = AccessFlags::COLOR_ATTACHMENT_WRITE;
+    let mut accesses = AccessFlags::COLOR_ATTACHMENT_READ | AccessFlags::COLOR_ATTACHMENT_WRITE;
+    if depth_attachment.is_some() {
+        stages |= PipelineStageFlags::EARLY_FRAGMENT_TESTS |
+            PipelineStageFlags::LATE_FRAGMENT_TESTS;
+        writes |= AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+        accesses |= AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+            AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+    }
     let dependency = SubpassDependency::builder()
         .src_subpass(voodoo::SUBPASS_EXTERNAL)
         .dst_subpass(0)
-        .src_stage_mask(PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-        .dst_stage_mask(PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-        .dst_access_mask(AccessFlags::COLOR_ATTACHMENT_READ |
-            AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .src_stage_mask(stages)
+        .dst_stage_mask(stages)
+        .src_access_mask(writes)
+        .dst_access_mask(accesses)
         .build();
 
     RenderPass::builder()
-        .attachments(&[color_attachment, depth_attachment])
-        .subpasses(&[subpass])
+        .attachments(&attachments[..])
+        .subpasses(&[subpass.build()])
         .dependencies(&[dependency])
         .build(device)
 }
 
-pub fn create_descriptor_set_layout(device: Device) -> VdResult<DescriptorSetLayout> {
-    let ubo_layout_binding = DescriptorSetLayoutBinding::builder()
-        .binding(0)
-        .descriptor_type(DescriptorType::UniformBuffer)
-        .descriptor_count(1)
-        .stage_flags(ShaderStageFlags::VERTEX)
-        .build();
-
-    let sampler_layout_binding = DescriptorSetLayoutBinding::builder()
-        .binding(1)
-        .descriptor_type(DescriptorType::CombinedImageSampler)
-        .descriptor_count(1)
-        .stage_flags(ShaderStageFlags::FRAGMENT)
-        .build();
-
-    let bindings = [ubo_layout_binding, sampler_layout_binding];
+/// Creates a descriptor set layout with `bindings`, as reflected from the
+/// shaders.
+pub fn create_descriptor_set_layout(device: Device,
+                                    bindings: &[DescriptorBinding]) -> VdResult<DescriptorSetLayout> {
+    let layout_bindings = bindings.iter().map(|binding| {
+        DescriptorSetLayoutBinding::builder()
+            .binding(binding.binding)
+            .descriptor_type(binding.descriptor_type)
+            .descriptor_count(binding.count)
+            .stage_flags(binding.stage_flags)
+            .build()
+    }).collect::<Vec<_>>();
 
     DescriptorSetLayout::builder()
-        .bindings(&bindings)
+        .bindings(&layout_bindings)
         .build(device)
 }
 
-pub fn create_descriptor_pool(device: Device) -> VdResult<DescriptorPool> {
-    let pool_sizes = [
-        DescriptorPoolSize::builder()
-            .type_of(DescriptorType::UniformBuffer)
-            .descriptor_count(1)
-            .build(),
+/// Creates a descriptor pool with room for `set_count` descriptor sets of
+/// a layout with `bindings`.
+pub fn create_descriptor_pool(device: Device,
+                              set_count: u32,
+                              bindings: &[DescriptorBinding]) -> VdResult<DescriptorPool> {
+    let mut counts: Vec<(DescriptorType, u32)> = Vec::new();
+    for binding in bindings {
+        match counts.iter().position(|&(ty, _)| ty == binding.descriptor_type) {
+            Some(i) => counts[i].1 += binding.count,
+            None => counts.push((binding.descriptor_type, binding.count)),
+        }
+    }
+    let pool_sizes = counts.iter().map(|&(ty, count)| {
         DescriptorPoolSize::builder()
-            .type_of(DescriptorType::CombinedImageSampler)
-            .descriptor_count(1)
-            .build(),
-    ];
+            .type_of(ty)
+            .descriptor_count(set_count * count)
+            .build()
+    }).collect::<Vec<_>>();
 
     DescriptorPool::builder()
-        .max_sets(1)
+        .max_sets(set_count)
         .pool_sizes(&pool_sizes)
         .build(device)
 }
 
+/// Allocates a descriptor set pointing at `uniform_buffer` and the textures
+/// in `image_infos` at the given bindings. The textures fill the texture
+/// array from its start.
 pub fn create_descriptor_sets(layout: &DescriptorSetLayout,
                               pool: &DescriptorPool,
+                              uniform_buffer_binding: u32,
                               uniform_buffer: &Buffer,
-                              texture_image_view: &ImageView,
-                              texture_sampler: &Sampler) -> VdResult<SmallVec<[DescriptorSet; 8]>> {
+                              texture_binding: u32,
+                              image_infos: &[DescriptorImageInfo]) -> VdResult<SmallVec<[DescriptorSet; 8]>> {
     let descriptor_sets = pool.allocate_descriptor_sets(&[layout.handle()])?;
 
     let buffer_info = DescriptorBufferInfo::builder()
@@ -515,38 +821,75 @@ pub fn create_descriptor_sets(layout: &DescriptorSetLayout,
         .range(mem::size_of::<UniformBufferObject>() as u64)
         .build();
 
-    let image_info = DescriptorImageInfo::builder()
-        .sampler(texture_sampler)
-        .image_view(texture_image_view)
-        .image_layout(ImageLayout::ShaderReadOnlyOptimal)
-        .build();
-
     let descriptor_writes = [
         WriteDescriptorSet::builder()
             .dst_set(&descriptor_sets[0])
-            .dst_binding(0)
+            .dst_binding(uniform_buffer_binding)
             .dst_array_element(0)
             .descriptor_count(1)
             .descriptor_type(DescriptorType::UniformBuffer)
             .buffer_info(&buffer_info)
             .build(),
+    ];
+
+    pool.update_descriptor_sets(&descriptor_writes, &[]);
+    update_texture_descriptors(pool, &descriptor_sets[0], texture_binding, image_infos);
+
+    Ok(descriptor_sets)
+}
+
+/// Writes `image_infos` to the texture array at `binding` of
+/// `descriptor_set`, which must not be in use by the device.
+pub fn update_texture_descriptors(pool: &DescriptorPool,
+                                  descriptor_set: &DescriptorSet,
+                                  binding: u32,
+                                  image_infos: &[DescriptorImageInfo]) {
+    // The builder takes a single image info, so each array element gets a
+    // write of its own
+    let descriptor_writes = image_infos.iter().enumerate().map(|(i, image_info)| {
         WriteDescriptorSet::builder()
-            .dst_set(&descriptor_sets[0])
-            .dst_binding(1)
-            .dst_array_element(0)
+            .dst_set(descriptor_set)
+            .dst_binding(binding)
+            .dst_array_element(i as u32)
             .descriptor_count(1)
             .descriptor_type(DescriptorType::CombinedImageSampler)
-            .image_info(&image_info)
+            .image_info(image_info)
+            .build()
+    }).collect::<Vec<_>>();
+
+    pool.update_descriptor_sets(&descriptor_writes, &[]);
+}
+
+/// Points the storage buffer at `binding` of `descriptor_set` at the first
+/// `size` bytes of `buffer`. The set must not be in use by the device.
+pub fn update_storage_buffer_descriptor(pool: &DescriptorPool,
+                                        descriptor_set: &DescriptorSet,
+                                        binding: u32,
+                                        buffer: &Buffer,
+                                        size: u64) {
+    let buffer_info = DescriptorBufferInfo::builder()
+        .buffer(buffer)
+        .offset(0)
+        .range(size)
+        .build();
+
+    let descriptor_writes = [
+        WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(binding)
+            .dst_array_element(0)
+            .descriptor_count(1)
+            .descriptor_type(DescriptorType::StorageBuffer)
+            .buffer_info(&buffer_info)
             .build(),
     ];
 
     pool.update_descriptor_sets(&descriptor_writes, &[]);
-
-    Ok(descriptor_sets)
 }
 
 pub fn create_pipeline_layout(device: Device,
-                              descriptor_set_layout: Option<&DescriptorSetLayout>) -> VdResult<PipelineLayout> {
+                              descriptor_set_layout: Option<&DescriptorSetLayout>,
+                              push_constant_ranges: &[PushConstantRange]) -> VdResult<PipelineLayout> {
     let mut layouts = SmallVec::<[_; 8]>::new();
     if let Some(dsl) = descriptor_set_layout {
         layouts.push(dsl.handle());
@@ -554,15 +897,35 @@ pub fn create_pipeline_layout(device: Device,
 
     PipelineLayout::builder()
         .set_layouts(&layouts)
+        .push_constant_ranges(push_constant_ranges)
         .build(device)
 }
 
+/// Records setting the push constants of `stage_flags` at `offset` to
+/// `constants`.
+pub fn push_constants<T: Copy>(command_buffer: &CommandBuffer,
+                               layout: &PipelineLayout,
+                               stage_flags: ShaderStageFlags,
+                               offset: u32,
+                               constants: &T) {
+    unsafe {
+        command_buffer.push_constants(layout, stage_flags, offset,
+            mem::size_of::<T>() as u32, constants as *const T as *const c_void);
+    }
+}
+
+/// Creates a pipeline with the fixed-function state of `desc`, see
+/// `pipeline::Pipelines` for a cache of these. The viewport and scissor are
+/// dynamic and have to be set in each command buffer using the pipeline.
+///
+/// With a `pipeline_cache` the driver can reuse compiled shaders.
 pub fn create_graphics_pipeline(device: Device,
+                                pipeline_cache: Option<&PipelineCache>,
                                 pipeline_layout: &PipelineLayout,
                                 render_pass: &RenderPass,
-                                swap_chain_extent: Extent2d,
                                 vert_shader_code: &[u32],
-                                frag_shader_code: &[u32]) -> VdResult<GraphicsPipeline> {
+                                frag_shader_code: &[u32],
+                                desc: &PipelineDesc) -> VdResult<GraphicsPipeline> {
     let vert_shader_module = ShaderModule::new(device.clone(), vert_shader_code)?;
     let frag_shader_module = ShaderModule::new(device.clone(), frag_shader_code)?;
 
@@ -581,8 +944,20 @@ pub fn create_graphics_pipeline(device: Device,
         .name(fn_name)
         .build();
 
-    let binding_descriptions = [Vertex::binding_description()];
-    let attribute_descriptions = Vertex::attribute_descriptions();
+    let binding_descriptions = [Vertex::binding_description(),
+                                InstanceVertex::binding_description()];
+    let attribute_descriptions = Vertex::attribute_descriptions().iter()
+        .chain(InstanceVertex::attribute_descriptions().iter())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // A vertex shader reading attributes differently from `Vertex` and
+    // `InstanceVertex` draws garbage
+    let vert_reflection = reflect::reflect(vert_shader_code)
+        .map_err(|err| format!("reflecting vertex shader: {}", err))?;
+    reflect::validate_vertex_input(&vert_reflection, &attribute_descriptions)
+        .map_err(|err| format!("vertex shader doesn't match Vertex and InstanceVertex: {}",
+            err))?;
 
     let vertex_input_info = PipelineVertexInputStateCreateInfo::builder()
         .vertex_binding_descriptions(&binding_descriptions[..])
@@ -590,23 +965,14 @@ pub fn create_graphics_pipeline(device: Device,
         .build();
 
     let input_assembly = PipelineInputAssemblyStateCreateInfo::builder()
-        .topology(PrimitiveTopology::TriangleList)
+        .topology(desc.topology)
         .primitive_restart_enable(false)
         .build();
 
-    let viewport = Viewport::builder()
-        .x(0.0f32)
-        .y(0.0f32)
-        .width(swap_chain_extent.width() as f32)
-        .height(swap_chain_extent.height() as f32)
-        .min_depth(0.0f32)
-        .max_depth(1.0f32)
-        .build();
-
-    let scissor = Rect2d::builder()
-        .offset(Offset2d::builder().x(0).y(0).build())
-        .extent(swap_chain_extent)
-        .build();
+    // Placeholders, only their number counts with a dynamic viewport and
+    // scissor
+    let (viewport, scissor) = ViewportRect::full()
+        .viewport_and_scissor(&Extent2d::builder().width(1).height(1).build());
 
     let viewports = [viewport];
     let scissors = [scissor];
@@ -619,9 +985,9 @@ pub fn create_graphics_pipeline(device: Device,
     let rasterizer = PipelineRasterizationStateCreateInfo::builder()
         .depth_clamp_enable(false)
         .rasterizer_discard_enable(false)
-        .polygon_mode(PolygonMode::Fill)
-        .cull_mode(CullModeFlags::NONE)
-        .front_face(FrontFace::CounterClockwise)
+        .polygon_mode(desc.polygon_mode)
+        .cull_mode(desc.cull_mode)
+        .front_face(desc.front_face)
         .depth_bias_enable(false)
         .depth_bias_constant_factor(0.0f32)
         .depth_bias_clamp(0.0f32)
@@ -648,9 +1014,9 @@ pub fn create_graphics_pipeline(device: Device,
         .build();
 
     let depth_stencil = PipelineDepthStencilStateCreateInfo::builder()
-        .depth_test_enable(true)
-        .depth_write_enable(true)
-        .depth_compare_op(CompareOp::Less)
+        .depth_test_enable(desc.depth_test)
+        .depth_write_enable(desc.depth_write)
+        .depth_compare_op(desc.depth_compare)
         .depth_bounds_test_enable(false)
         .stencil_test_enable(false)
         .front(stencil_op_state.clone())
@@ -659,13 +1025,19 @@ pub fn create_graphics_pipeline(device: Device,
         .max_depth_bounds(1.0)
         .build();
 
+    let (src_color_factor, dst_color_factor) = match desc.blend {
+        BlendMode::Opaque => (BlendFactor::One, BlendFactor::Zero),
+        BlendMode::Alpha => (BlendFactor::SrcAlpha, BlendFactor::OneMinusSrcAlpha),
+        BlendMode::Additive => (BlendFactor::SrcAlpha, BlendFactor::One),
+    };
+
     let color_blend_attachment = PipelineColorBlendAttachmentState::builder()
-        .blend_enable(false)
-        .src_color_blend_factor(BlendFactor::One)
-        .dst_color_blend_factor(BlendFactor::Zero)
+        .blend_enable(desc.blend != BlendMode::Opaque)
+        .src_color_blend_factor(src_color_factor)
+        .dst_color_blend_factor(dst_color_factor)
         .color_blend_op(BlendOp::Add)
         .src_alpha_blend_factor(BlendFactor::One)
-        .dst_alpha_blend_factor(BlendFactor::Zero)
+        .dst_alpha_blend_factor(BlendFactor::OneMinusSrcAlpha)
         .alpha_blend_op(BlendOp::Add)
         .color_write_mask(ColorComponentFlags::R | ColorComponentFlags::G |
             ColorComponentFlags::B | ColorComponentFlags::A)
@@ -682,8 +1054,13 @@ pub fn create_graphics_pipeline(device: Device,
 
     let shader_stages = &[vert_shader_stage_info, frag_shader_stage_info];
 
-    GraphicsPipeline::builder()
-        .stages(shader_stages)
+    let dynamic_states = [DynamicState::Viewport, DynamicState::Scissor];
+    let dynamic_state = PipelineDynamicStateCreateInfo::builder()
+        .dynamic_states(&dynamic_states[..])
+        .build();
+
+    let mut builder = GraphicsPipeline::builder();
+    builder.stages(shader_stages)
         .vertex_input_state(&vertex_input_info)
         .input_assembly_state(&input_assembly)
         .viewport_state(&viewport_state)
@@ -695,34 +1072,71 @@ pub fn create_graphics_pipeline(device: Device,
         .render_pass(render_pass)
         .subpass(0)
         .base_pipeline_index(-1)
-        .build(device)
+        .dynamic_state(&dynamic_state);
+    if let Some(pipeline_cache) = pipeline_cache {
+        builder.pipeline_cache(pipeline_cache);
+    }
+    builder.build(device)
+}
+
+/// Creates a compute pipeline running the `main` function of
+/// `shader_code`.
+pub fn create_compute_pipeline(device: Device,
+                               pipeline_cache: Option<&PipelineCache>,
+                               pipeline_layout: &PipelineLayout,
+                               shader_code: &[u32]) -> VdResult<ComputePipeline> {
+    let shader_module = ShaderModule::new(device.clone(), shader_code)?;
+    let fn_name = CStr::from_bytes_with_nul(b"main\0").unwrap();
+    let shader_stage_info = PipelineShaderStageCreateInfo::builder()
+        .stage(ShaderStageFlags::COMPUTE)
+        .module(&shader_module)
+        .name(fn_name)
+        .build();
+
+    let mut builder = ComputePipeline::builder();
+    builder
+        .stage(shader_stage_info)
+        .layout(pipeline_layout)
+        .base_pipeline_index(-1);
+    if let Some(pipeline_cache) = pipeline_cache {
+        builder.pipeline_cache(pipeline_cache);
+    }
+    builder.build(device)
 }
 
 pub fn create_command_pool(device: Device,
-                           surface: &SurfaceKhr) -> VdResult<CommandPool> {
+                           surface: Option<&SurfaceKhr>) -> VdResult<CommandPool> {
     let queue_family_indices = find_queue_families(device.physical_device(), surface)?;
     CommandPool::builder()
         .queue_family_index(queue_family_indices.graphics_family_idx)
         .build(device)
 }
 
-pub fn create_framebuffers(device: &Device,
-                           render_pass: &RenderPass,
-                           swapchain_image_views: &[ImageView],
-                           depth_image_view: &ImageView,
-                           swapchain_extent: Extent2d) -> VdResult<Vec<Framebuffer>> {
-    swapchain_image_views.iter().map(|image_view| {
-        let attachments = [image_view, depth_image_view];
-        Framebuffer::builder()
-            .render_pass(&render_pass)
-            .attachments(&attachments[..])
-            .width(swapchain_extent.width())
-            .height(swapchain_extent.height())
-            .layers(1)
-            .build(device.clone())
-    }).collect::<Result<Vec<_>, _>>()
+/// Creates a pool for command buffers which are recorded every frame. The
+/// whole pool is reset at once instead of its buffers one by one.
+pub fn create_frame_command_pool(device: Device,
+                                 surface: Option<&SurfaceKhr>) -> VdResult<CommandPool> {
+    let queue_family_indices = find_queue_families(device.physical_device(), surface)?;
+    CommandPool::builder()
+        .flags(CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(queue_family_indices.graphics_family_idx)
+        .build(device)
 }
 
+/// Creates a framebuffer of size `extent` with `attachments` in the order
+/// of the attachments of `render_pass`.
+pub fn create_framebuffer(device: &Device,
+                          render_pass: &RenderPass,
+                          attachments: &[&ImageView],
+                          extent: &Extent2d) -> VdResult<Framebuffer> {
+    Framebuffer::builder()
+        .render_pass(render_pass)
+        .attachments(attachments)
+        .width(extent.width())
+        .height(extent.height())
+        .layers(1)
+        .build(device.clone())
+}
 
 fn begin_single_time_commands(command_pool: &CommandPool) -> VdResult<CommandBuffer> {
     let command_buffer = command_pool.allocate_command_buffer(CommandBufferLevel::Primary)?;
@@ -749,86 +1163,197 @@ fn has_stencil_component(format: Format) -> bool {
     format == Format::D32SfloatS8Uint || format == Format::D24UnormS8Uint
 }
 
-fn transition_image_layout(device: &Device,
-                           command_pool: &CommandPool,
-                           image: &Image,
-                           format: Format,
-                           old_layout: ImageLayout,
-                           new_layout: ImageLayout) -> VdResult<()> {
-    let command_buffer = begin_single_time_commands(command_pool)?;
+pub fn is_depth_format(format: Format) -> bool {
+    match format {
+        Format::D16Unorm | Format::X8D24UnormPack32 | Format::D32Sfloat |
+        Format::D16UnormS8Uint | Format::D24UnormS8Uint | Format::D32SfloatS8Uint => true,
+        _ => false,
+    }
+}
 
+/// The aspects of images of `format` which barriers have to cover.
+pub fn format_aspect_mask(format: Format) -> ImageAspectFlags {
+    if !is_depth_format(format) {
+        ImageAspectFlags::COLOR
+    } else if has_stencil_component(format) {
+        ImageAspectFlags::DEPTH | ImageAspectFlags::STENCIL
+    } else {
+        ImageAspectFlags::DEPTH
+    }
+}
+
+/// How an image is used at one point of a frame: its layout and the
+/// pipeline stages and memory accesses touching it. A barrier between two
+/// accesses waits for the stages of the first before those of the second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageAccess {
+    pub layout: ImageLayout,
+    pub stages: PipelineStageFlags,
+    pub access: AccessFlags,
+}
+
+impl ImageAccess {
+    pub fn new(layout: ImageLayout,
+               stages: PipelineStageFlags,
+               access: AccessFlags) -> ImageAccess {
+        ImageAccess { layout, stages, access }
+    }
+
+    /// The use `layout` is meant for, or `None` for layouts which don't
+    /// imply one, like `General`.
+    pub fn for_layout(layout: ImageLayout) -> Option<ImageAccess> {
+        let (stages, access) = match layout {
+            ImageLayout::Undefined =>
+                (PipelineStageFlags::TOP_OF_PIPE, AccessFlags::empty()),
+            ImageLayout::TransferDstOptimal =>
+                (PipelineStageFlags::TRANSFER, AccessFlags::TRANSFER_WRITE),
+            ImageLayout::TransferSrcOptimal =>
+                (PipelineStageFlags::TRANSFER, AccessFlags::TRANSFER_READ),
+            ImageLayout::ShaderReadOnlyOptimal =>
+                (PipelineStageFlags::FRAGMENT_SHADER, AccessFlags::SHADER_READ),
+            ImageLayout::ColorAttachmentOptimal =>
+                (PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                 AccessFlags::COLOR_ATTACHMENT_READ | AccessFlags::COLOR_ATTACHMENT_WRITE),
+            ImageLayout::DepthStencilAttachmentOptimal =>
+                (PipelineStageFlags::EARLY_FRAGMENT_TESTS |
+                     PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                 AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+                     AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+            // Presentation waits on a semaphore, not on the barrier
+            ImageLayout::PresentSrcKhr =>
+                (PipelineStageFlags::BOTTOM_OF_PIPE, AccessFlags::empty()),
+            _ => return None,
+        };
+        Some(ImageAccess::new(layout, stages, access))
+    }
+
+    /// Returns true if the access writes to the image, so anything after it
+    /// has to wait.
+    pub fn writes(&self) -> bool {
+        self.access.intersects(AccessFlags::TRANSFER_WRITE | AccessFlags::SHADER_WRITE |
+            AccessFlags::COLOR_ATTACHMENT_WRITE | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE |
+            AccessFlags::HOST_WRITE | AccessFlags::MEMORY_WRITE)
+    }
+}
+
+/// A barrier moving the `aspect_mask` aspects of `level_count` mip levels
+/// of `image`, starting at `base_mip_level`, from the access `from` to `to`.
+/// All array layers of the levels are covered.
+pub fn image_barrier(image: ImageHandle,
+                     aspect_mask: ImageAspectFlags,
+                     base_mip_level: u32,
+                     level_count: u32,
+                     from: &ImageAccess,
+                     to: &ImageAccess) -> ImageMemoryBarrier<'static> {
     let subresource_range = ImageSubresourceRange::builder()
-        .aspect_mask(ImageAspectFlags::COLOR)
-        .base_mip_level(0)
-        .level_count(1)
+        .aspect_mask(aspect_mask)
+        .base_mip_level(base_mip_level)
+        .level_count(level_count)
         .base_array_layer(0)
-        .layer_count(1)
+        .layer_count(REMAINING_ARRAY_LAYERS)
         .build();
 
-    let mut barrier = ImageMemoryBarrier::builder()
-        .src_access_mask(AccessFlags::empty())
-        .dst_access_mask(AccessFlags::empty())
-        .old_layout(old_layout)
-        .new_layout(new_layout)
+    ImageMemoryBarrier::builder()
+        // Only writes have to be made available, reads just wait
+        .src_access_mask(if from.writes() { from.access } else { AccessFlags::empty() })
+        .dst_access_mask(to.access)
+        .old_layout(from.layout)
+        .new_layout(to.layout)
         .src_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
         .dst_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
         .image(image)
         .subresource_range(subresource_range)
-        .build();
-
-    if new_layout == ImageLayout::DepthStencilAttachmentOptimal {
-        barrier.subresource_range_mut().set_aspect_mask(ImageAspectFlags::DEPTH);
-        if has_stencil_component(format) {
-            let aspect_mask = barrier.subresource_range().aspect_mask() |
-                ImageAspectFlags::STENCIL;
-            barrier.subresource_range_mut().set_aspect_mask(aspect_mask);
-        }
-    } else {
-        barrier.subresource_range_mut().set_aspect_mask(ImageAspectFlags::COLOR);
-    }
+        .build()
+}
 
-    let source_stage: PipelineStageFlags;
-    let destination_stage: PipelineStageFlags;
-
-    if old_layout == ImageLayout::Undefined &&
-            new_layout == ImageLayout::TransferDstOptimal
-    {
-        barrier.set_src_access_mask(AccessFlags::empty());
-        barrier.set_dst_access_mask(AccessFlags::TRANSFER_WRITE);
-        source_stage = PipelineStageFlags::TOP_OF_PIPE;
-        destination_stage = PipelineStageFlags::TRANSFER;
-    } else if old_layout == ImageLayout::TransferDstOptimal &&
-            new_layout == ImageLayout::ShaderReadOnlyOptimal
-    {
-        barrier.set_src_access_mask(AccessFlags::TRANSFER_WRITE);
-        barrier.set_dst_access_mask(AccessFlags::SHADER_READ);
-        source_stage = PipelineStageFlags::TRANSFER;
-        destination_stage = PipelineStageFlags::FRAGMENT_SHADER;
-    } else if old_layout == ImageLayout::Undefined &&
-            new_layout == ImageLayout::DepthStencilAttachmentOptimal
-        {
-        barrier.set_src_access_mask(AccessFlags::empty());
-        barrier.set_dst_access_mask(AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
-            AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE);
-        source_stage = PipelineStageFlags::TOP_OF_PIPE;
-        destination_stage = PipelineStageFlags::EARLY_FRAGMENT_TESTS;
-    } else {
-        panic!("unsupported layout transition");
-    }
+/// Records a barrier moving `level_count` mip levels of `image`, starting
+/// at `base_mip_level`, from `old_layout` to `new_layout`. All array layers
+/// of the levels are transitioned.
+///
+/// Each layout is taken to be used the way `ImageAccess::for_layout`
+/// describes.
+pub fn record_layout_transition(command_buffer: &CommandBuffer,
+                                image: &Image,
+                                format: Format,
+                                old_layout: ImageLayout,
+                                new_layout: ImageLayout,
+                                base_mip_level: u32,
+                                level_count: u32) -> VdResult<()> {
+    let (from, to) = match (ImageAccess::for_layout(old_layout),
+                            ImageAccess::for_layout(new_layout)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Err(format!("unsupported layout transition from {:?} to {:?}",
+            old_layout, new_layout).into()),
+    };
 
-    command_buffer.pipeline_barrier(source_stage, destination_stage,
+    let barrier = image_barrier(image.handle(), format_aspect_mask(format), base_mip_level,
+        level_count, &from, &to);
+    command_buffer.pipeline_barrier(from.stages, to.stages,
         DependencyFlags::empty(), &[], &[], &[barrier]);
 
-    end_single_time_commands(device, command_buffer)
+    Ok(())
 }
 
-fn copy_buffer_to_image(device: &Device,
+/// Copies a color image into `buffer`. An image in a layout other than
+/// `TransferSrcOptimal` is transitioned for the copy and restored afterwards.
+fn copy_image_to_buffer(device: &Device,
                         command_pool: &CommandPool,
+                        image: ImageHandle,
+                        layout: ImageLayout,
                         buffer: &Buffer,
-                        image: &Image,
                         width: u32,
                         height: u32)  -> VdResult<()> {
     let command_buffer = begin_single_time_commands(command_pool)?;
+    let extent = Extent2d::builder().width(width).height(height).build();
+    record_image_readback(&command_buffer, image, layout, buffer, &extent);
+    end_single_time_commands(device, command_buffer)
+}
+
+/// Records copying the color image `image`, which was last written as a
+/// color attachment and is in `layout`, into `buffer`. The image is left in
+/// `layout` and the copy is made visible to host reads once the command
+/// buffer has finished.
+pub fn record_image_readback(command_buffer: &CommandBuffer,
+                             image: ImageHandle,
+                             layout: ImageLayout,
+                             buffer: &Buffer,
+                             extent: &Extent2d) {
+    let subresource_range = ImageSubresourceRange::builder()
+        .aspect_mask(ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build();
+
+    let to_transfer_src = ImageMemoryBarrier::builder()
+        .src_access_mask(AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_access_mask(AccessFlags::TRANSFER_READ)
+        .old_layout(layout)
+        .new_layout(ImageLayout::TransferSrcOptimal)
+        .src_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(subresource_range.clone())
+        .build();
+
+    let from_transfer_src = ImageMemoryBarrier::builder()
+        .src_access_mask(AccessFlags::TRANSFER_READ)
+        .dst_access_mask(AccessFlags::MEMORY_READ)
+        .old_layout(ImageLayout::TransferSrcOptimal)
+        .new_layout(layout)
+        .src_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(subresource_range)
+        .build();
+
+    let needs_transition = layout != ImageLayout::TransferSrcOptimal;
+    if needs_transition {
+        command_buffer.pipeline_barrier(PipelineStageFlags::ALL_COMMANDS,
+            PipelineStageFlags::TRANSFER, DependencyFlags::empty(), &[], &[],
+            &[to_transfer_src]);
+    }
 
     let image_subresource_layers = ImageSubresourceLayers::builder()
         .aspect_mask(ImageAspectFlags::COLOR)
@@ -843,67 +1368,146 @@ fn copy_buffer_to_image(device: &Device,
         .buffer_image_height(0)
         .image_subresource(image_subresource_layers)
         .image_offset(Offset3d::builder().x(0).y(0).z(0).build())
-        .image_extent(Extent3d::builder().width(width).height(height).depth(1).build())
+        .image_extent(Extent3d::builder()
+            .width(extent.width())
+            .height(extent.height())
+            .depth(1)
+            .build())
         .build();
 
     unsafe {
-        command_buffer.copy_buffer_to_image(buffer, image, ImageLayout::TransferDstOptimal,
+        command_buffer.copy_image_to_buffer(image, ImageLayout::TransferSrcOptimal, buffer,
             &[region]);
     }
 
-    end_single_time_commands(device, command_buffer)
+    if needs_transition {
+        command_buffer.pipeline_barrier(PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::BOTTOM_OF_PIPE, DependencyFlags::empty(), &[], &[],
+            &[from_transfer_src]);
+    }
+
+    let to_host = BufferMemoryBarrier::builder()
+        .src_access_mask(AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(AccessFlags::HOST_READ)
+        .src_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(voodoo::QUEUE_FAMILY_IGNORED)
+        .buffer(buffer)
+        .offset(0)
+        .size(extent.width() as u64 * extent.height() as u64 * 4)
+        .build();
+    command_buffer.pipeline_barrier(PipelineStageFlags::TRANSFER, PipelineStageFlags::HOST,
+        DependencyFlags::empty(), &[], &[to_host], &[]);
 }
 
-fn copy_buffer(device: &Device,
-               command_pool: &CommandPool,
-               src_buffer: &Buffer,
-               dst_buffer: &Buffer,
-               size: DeviceSize)  -> VdResult<()> {
-    let command_buffer = begin_single_time_commands(command_pool)?;
+/// A range of a model's indices drawn with one material.
+#[derive(Debug, Clone)]
+pub struct SubMesh {
+    pub name: String,
+    pub first_index: u32,
+    pub index_count: u32,
+    /// Index into the model's materials.
+    pub material_id: Option<usize>,
+}
 
-    let copy_region = BufferCopy::builder()
-        .src_offset(0)
-        .dst_offset(0)
-        .size(size)
-        .build();
+/// Deduplicated vertices and indices of one or more meshes, split into
+/// sub-meshes by material.
+pub struct Model {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub sub_meshes: Vec<SubMesh>,
+    pub materials: Vec<tobj::Material>,
+    /// Path of the diffuse texture of each material, if it has one.
+    pub material_textures: Vec<Option<PathBuf>>,
+}
 
-    unsafe {
-        command_buffer.copy_buffer(src_buffer, dst_buffer, &[copy_region]);
+impl Model {
+    /// A model made of a single sub-mesh without material.
+    pub fn new(vertices: Vec<Vertex>, indices: Vec<u32>) -> Model {
+        let sub_meshes = vec![SubMesh {
+            name: String::new(),
+            first_index: 0,
+            index_count: indices.len() as u32,
+            material_id: None,
+        }];
+        Model {
+            vertices,
+            indices,
+            sub_meshes,
+            materials: Vec::new(),
+            material_textures: Vec::new(),
+        }
     }
-    end_single_time_commands(device, command_buffer)
-}
 
+    /// Appends the meshes of `other`, offsetting its indices and materials.
+    pub fn append(&mut self, other: Model) {
+        let vertex_offset = self.vertices.len() as u32;
+        let index_offset = self.indices.len() as u32;
+        let material_offset = self.materials.len();
+
+        self.vertices.extend(other.vertices);
+        self.indices.extend(other.indices.iter().map(|&i| i + vertex_offset));
+        self.sub_meshes.extend(other.sub_meshes.into_iter().map(|sub_mesh| SubMesh {
+            first_index: sub_mesh.first_index + index_offset,
+            material_id: sub_mesh.material_id.map(|id| id + material_offset),
+            ..sub_mesh
+        }));
+        self.materials.extend(other.materials);
+        self.material_textures.extend(other.material_textures);
+    }
+}
 
-fn load_model(device: &Device,
-              model_path: &Path) -> VdResult<(Vec<Vertex>, Vec<u32>)> {
-    let (models, materials) = tobj::load_obj(model_path)
-        .expect("Error loading model");
+/// Loads all meshes of an OBJ file, deduplicating their vertices.
+///
+/// Each mesh becomes a sub-mesh with the material assigned to it in the
+/// OBJ's material library, whose diffuse color is used as vertex color and
+/// whose diffuse texture is drawn with the sub-mesh.
+/// Missing texture coordinates default to zero. Normals are not part of
+/// `Vertex` and are ignored.
+pub fn load_model(model_path: &Path) -> Result<Model> {
+    let (models, materials) = tobj::load_obj(model_path)?;
 
     let mut vertices = Vec::with_capacity(4096);
     let mut indices = Vec::with_capacity(4096);
+    let mut sub_meshes = Vec::with_capacity(models.len());
 
     let mut unique_vertices: HashMap<Vertex, u32> = HashMap::with_capacity(1 << 20);
 
-    let mut mesh_id = 0usize;
     for model in models {
         let mesh = &model.mesh;
+        let has_tex_coords = !mesh.texcoords.is_empty();
+        let color = mesh.material_id
+            .and_then(|id| materials.get(id))
+            .map(|material| material.diffuse)
+            .unwrap_or([1.0, 1.0, 1.0]);
+
+        let first_index = indices.len() as u32;
+
         for &index in &mesh.indices {
             let index = index as usize;
             let vert_idz = 3 * index;
             let tex_coord_idz = 2 * index;
 
+            if vert_idz + 2 >= mesh.positions.len() {
+                return Err(format!("{}: mesh {} references missing vertex {}",
+                    model_path.display(), model.name, index).into());
+            }
+
             let pos = [
                 mesh.positions[vert_idz],
                 mesh.positions[vert_idz + 1],
                 mesh.positions[vert_idz + 2],
             ];
-            let tex_coord = [
-                mesh.texcoords[tex_coord_idz],
-                1.0 - mesh.texcoords[tex_coord_idz + 1],
-            ];
+            let tex_coord = if has_tex_coords && tex_coord_idz + 1 < mesh.texcoords.len() {
+                [
+                    mesh.texcoords[tex_coord_idz],
+                    1.0 - mesh.texcoords[tex_coord_idz + 1],
+                ]
+            } else {
+                [0.0, 0.0]
+            };
             let vertex = Vertex {
                 pos,
-                color: [1.0, 1.0, 1.0],
+                color,
                 tex_coord,
             };
             if !unique_vertices.contains_key(&vertex) {
@@ -912,36 +1516,56 @@ fn load_model(device: &Device,
             }
             indices.push(unique_vertices[&vertex]);
         }
-        mesh_id += 1;
+
+        sub_meshes.push(SubMesh {
+            name: model.name.clone(),
+            first_index,
+            index_count: indices.len() as u32 - first_index,
+            material_id: mesh.material_id,
+        });
     }
-    Ok((vertices, indices))
-}
 
-pub fn create_vertex_buffer(device: &Device,
-                            command_pool: &CommandPool,
-                            vertices: &[Vertex]) -> VdResult<(Buffer, DeviceMemory)> {
-    let buffer_bytes = (mem::size_of::<Vertex>() * vertices.len()) as u64;
+    // Texture paths in the material library are relative to the OBJ
+    let model_dir = model_path.parent().unwrap_or(Path::new(""));
+    let material_textures = materials.iter().map(|material| {
+        if material.diffuse_texture.is_empty() {
+            None
+        } else {
+            Some(model_dir.join(&material.diffuse_texture))
+        }
+    }).collect();
 
-    let staging_buffer = Buffer::builder()
-        .size(buffer_bytes)
-        .usage(BufferUsageFlags::TRANSFER_SRC)
-        .sharing_mode(SharingMode::Exclusive)
-        .build(device.clone())?;
+    Ok(Model {
+        vertices,
+        indices,
+        sub_meshes,
+        materials,
+        material_textures,
+    })
+}
 
-    let memory_requirements = staging_buffer.memory_requirements().clone();
-    let memory_type_index = device.memory_type_index(memory_requirements.memory_type_bits(),
-        MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT)?;
-    let staging_buffer_memory = DeviceMemory::new(device.clone(), memory_requirements.size(),
-        memory_type_index)?;
-    unsafe {
-        staging_buffer.bind_memory(&staging_buffer_memory, 0)?;
+/// Loads and concatenates the models of all `model_paths`.
+pub fn load_models<P: AsRef<Path>>(model_paths: &[P]) -> Result<Model> {
+    let mut model = Model {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        sub_meshes: Vec::new(),
+        materials: Vec::new(),
+        material_textures: Vec::new(),
+    };
+    for path in model_paths {
+        model.append(load_model(path.as_ref())?);
     }
+    Ok(model)
+}
 
-    let mut data = unsafe {
-        staging_buffer_memory.map(0, buffer_bytes, MemoryMapFlags::empty())?
-    };
-    data.copy_from_slice(vertices);
-    staging_buffer_memory.unmap(data);
+/// Creates a device local vertex buffer and queues uploading `vertices`
+/// into it.
+pub fn create_vertex_buffer(device: &Device,
+                            allocator: &Allocator,
+                            uploads: &mut UploadManager,
+                            vertices: &[Vertex]) -> VdResult<(Buffer, Allocation)> {
+    let buffer_bytes = (mem::size_of::<Vertex>() * vertices.len()) as u64;
 
     let vertex_buffer = Buffer::builder()
         .size(buffer_bytes)
@@ -949,69 +1573,40 @@ pub fn create_vertex_buffer(device: &Device,
         .sharing_mode(SharingMode::Exclusive)
         .build(device.clone())?;
 
-    let memory_requirements = vertex_buffer.memory_requirements().clone();
-    let memory_type_index = device.memory_type_index(memory_requirements.memory_type_bits(),
+    let vertex_buffer_memory = allocator.allocate_buffer(&vertex_buffer,
         MemoryPropertyFlags::DEVICE_LOCAL)?;
-    let vertex_buffer_memory = DeviceMemory::new(device.clone(), memory_requirements.size(),
-        memory_type_index)?;
-    unsafe {
-        vertex_buffer.bind_memory(&vertex_buffer_memory, 0)?;
-    }
 
-    copy_buffer(device, command_pool, &staging_buffer, &vertex_buffer, buffer_bytes)?;
+    uploads.upload_buffer(vertices, &vertex_buffer, 0)?;
 
     Ok((vertex_buffer, vertex_buffer_memory))
 }
 
+/// Creates a device local index buffer and queues uploading `indices`
+/// into it.
 pub fn create_index_buffer<T: Copy>(device: &Device,
-                                    command_pool: &CommandPool,
-                                    indices: &[T]) -> VdResult<(Buffer, DeviceMemory)> {
+                                    allocator: &Allocator,
+                                    uploads: &mut UploadManager,
+                                    indices: &[T]) -> VdResult<(Buffer, Allocation)> {
     let buffer_bytes = (mem::size_of::<T>() * indices.len()) as u64;
 
-    let staging_buffer = Buffer::builder()
-        .size(buffer_bytes)
-        .usage(BufferUsageFlags::TRANSFER_SRC)
-        .sharing_mode(SharingMode::Exclusive)
-        .build(device.clone())?;
-
-    let memory_requirements = staging_buffer.memory_requirements().clone();
-    let memory_type_index = device.memory_type_index(memory_requirements.memory_type_bits(),
-        MemoryPropertyFlags::HOST_VISIBLE)?;
-    let staging_buffer_memory = DeviceMemory::new(device.clone(), memory_requirements.size(),
-        memory_type_index)?;
-    unsafe {
-        staging_buffer.bind_memory(&staging_buffer_memory, 0)?;
-    }
-
-    let mut data = unsafe {
-        staging_buffer_memory.map(0, buffer_bytes, MemoryMapFlags::empty())?
-    };
-    data.copy_from_slice(indices);
-    staging_buffer_memory.unmap(data);
-
     let index_buffer = Buffer::builder()
         .size(buffer_bytes)
         .usage(BufferUsageFlags::TRANSFER_DST | BufferUsageFlags::INDEX_BUFFER)
         .sharing_mode(SharingMode::Exclusive)
         .build(device.clone())?;
 
-    let memory_requirements = index_buffer.memory_requirements().clone();
-    let memory_type_index = device.memory_type_index(memory_requirements.memory_type_bits(),
+    let index_buffer_memory = allocator.allocate_buffer(&index_buffer,
         MemoryPropertyFlags::DEVICE_LOCAL)?;
-    let index_buffer_memory = DeviceMemory::new(device.clone(), memory_requirements.size(),
-        memory_type_index)?;
-    unsafe {
-        index_buffer.bind_memory(&index_buffer_memory, 0)?;
-    }
 
-    copy_buffer(device, command_pool, &staging_buffer, &index_buffer, buffer_bytes)?;
+    uploads.upload_buffer(indices, &index_buffer, 0)?;
 
     Ok((index_buffer, index_buffer_memory))
 }
 
 pub fn create_uniform_buffer(device: &Device,
+                             allocator: &Allocator,
                              _command_pool: &CommandPool,
-                             _extent: Extent2d) -> VdResult<(Buffer, DeviceMemory)> {
+                             _extent: Extent2d) -> VdResult<(Buffer, Allocation)> {
     let buffer_bytes = mem::size_of::<UniformBufferObject>() as u64;
     let uniform_buffer = Buffer::builder()
         .size(buffer_bytes)
@@ -1019,57 +1614,135 @@ pub fn create_uniform_buffer(device: &Device,
         .sharing_mode(SharingMode::Exclusive)
         .build(device.clone())?;
 
-    let memory_requirements = uniform_buffer.memory_requirements().clone();
-    let memory_type_index = device.memory_type_index(memory_requirements.memory_type_bits(),
+    let uniform_buffer_memory = allocator.allocate_buffer(&uniform_buffer,
         MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT)?;
-    let uniform_buffer_memory = DeviceMemory::new(device.clone(), memory_requirements.size(),
-        memory_type_index)?;
-    unsafe {
-        uniform_buffer.bind_memory(&uniform_buffer_memory, 0)?;
-    }
 
     Ok((uniform_buffer, uniform_buffer_memory))
 }
 
-pub fn create_depth_resources(device: &Device,
-                              command_pool: &CommandPool,
-                              swapchain_extent: Extent2d) -> VdResult<(Image, DeviceMemory, ImageView)> {
-    let depth_format = find_depth_format(device)?;
-    let extent = Extent3d::builder()
-        .width(swapchain_extent.width())
-        .height(swapchain_extent.height())
+/// Creates a buffer of `size` bytes for `usage`, bound to memory with
+/// `properties`.
+pub fn create_buffer(device: &Device,
+                     allocator: &Allocator,
+                     size: u64,
+                     usage: BufferUsageFlags,
+                     properties: MemoryPropertyFlags) -> VdResult<(Buffer, Allocation)> {
+    let buffer = Buffer::builder()
+        .size(size)
+        .usage(usage)
+        .sharing_mode(SharingMode::Exclusive)
+        .build(device.clone())?;
+
+    let buffer_memory = allocator.allocate_buffer(&buffer, properties)?;
+
+    Ok((buffer, buffer_memory))
+}
+
+/// Creates a host visible vertex buffer holding `capacity` instances, to be
+/// rewritten whenever the scene changes.
+pub fn create_instance_buffer(device: &Device,
+                              allocator: &Allocator,
+                              capacity: usize) -> VdResult<(Buffer, Allocation)> {
+    create_buffer(device,
+                  allocator,
+                  (capacity * mem::size_of::<InstanceVertex>()) as u64,
+                  BufferUsageFlags::VERTEX_BUFFER,
+                  MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT)
+}
+
+/// Creates a device local 2D image for rendering to, with a view of its
+/// color or depth aspect.
+pub fn create_attachment_image(device: &Device,
+                               allocator: &Allocator,
+                               format: Format,
+                               extent: &Extent2d,
+                               usage: ImageUsageFlags) -> VdResult<(Image, Allocation, ImageView)> {
+    let image = Image::builder()
+        .image_type(ImageType::Type2d)
+        .format(format)
+        .extent(Extent3d::builder()
+            .width(extent.width())
+            .height(extent.height())
+            .depth(1)
+            .build())
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(SampleCountFlags::COUNT_1)
+        .tiling(ImageTiling::Optimal)
+        .usage(usage)
+        .sharing_mode(SharingMode::Exclusive)
+        .initial_layout(ImageLayout::Undefined)
+        .build(device.clone())?;
+
+    let image_memory = allocator.allocate_image(&image, MemoryPropertyFlags::DEVICE_LOCAL)?;
+
+    // Views can only be sampled through a single aspect
+    let aspect_mask = if is_depth_format(format) {
+        ImageAspectFlags::DEPTH
+    } else {
+        ImageAspectFlags::COLOR
+    };
+    let image_view = ImageView::builder()
+        .image(image.handle())
+        .view_type(ImageViewType::Type2d)
+        .format(format)
+        .components(ComponentMapping::default())
+        .subresource_range(ImageSubresourceRange::builder()
+            .aspect_mask(aspect_mask)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build())
+        .build(device.clone(), None)?;
+
+    Ok((image, image_memory, image_view))
+}
+
+/// The color format of offscreen render targets. Unlike the swapchain's
+/// `B8G8R8A8Unorm` this can be read back as RGBA without swizzling.
+pub const OFFSCREEN_COLOR_FORMAT: Format = Format::R8G8B8A8Unorm;
+
+/// A color image used in place of a swapchain image when rendering headless.
+pub struct OffscreenTarget {
+    pub color_image: Image,
+    pub color_image_memory: Allocation,
+    pub color_image_view: ImageView,
+    pub extent: Extent2d,
+}
+
+pub fn create_offscreen_target(device: &Device,
+                               allocator: &Allocator,
+                               extent: Extent2d) -> VdResult<OffscreenTarget> {
+    let image_extent = Extent3d::builder()
+        .width(extent.width())
+        .height(extent.height())
         .depth(1)
         .build();
 
-    let depth_image = Image::builder()
+    let color_image = Image::builder()
         .image_type(ImageType::Type2d)
-        .format(depth_format)
-        .extent(extent)
+        .format(OFFSCREEN_COLOR_FORMAT)
+        .extent(image_extent)
         .mip_levels(1)
         .array_layers(1)
         .samples(SampleCountFlags::COUNT_1)
         .tiling(ImageTiling::Optimal)
-        .usage(ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+        .usage(ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::TRANSFER_SRC)
         .sharing_mode(SharingMode::Exclusive)
         .initial_layout(ImageLayout::Undefined)
         .build(device.clone())?;
 
-    let memory_requirements = depth_image.memory_requirements().clone();
-    let memory_type_index = device.memory_type_index(memory_requirements.memory_type_bits(),
+    let color_image_memory = allocator.allocate_image(&color_image,
         MemoryPropertyFlags::DEVICE_LOCAL)?;
-    let depth_image_memory = DeviceMemory::new(device.clone(), memory_requirements.size(),
-        memory_type_index)?;
-    unsafe {
-        depth_image.bind_memory(&depth_image_memory, 0)?;
-    }
 
-    let depth_image_view = ImageView::builder()
-        .image(depth_image.handle())
+    let color_image_view = ImageView::builder()
+        .image(color_image.handle())
         .view_type(ImageViewType::Type2d)
-        .format(depth_format)
+        .format(OFFSCREEN_COLOR_FORMAT)
         .components(ComponentMapping::default())
         .subresource_range(ImageSubresourceRange::builder()
-            .aspect_mask(ImageAspectFlags::DEPTH)
+            .aspect_mask(ImageAspectFlags::COLOR)
             .base_mip_level(0)
             .level_count(1)
             .base_array_layer(0)
@@ -1077,155 +1750,383 @@ pub fn create_depth_resources(device: &Device,
             .build())
         .build(device.clone(), None)?;
 
-    transition_image_layout(device, command_pool, &depth_image, depth_format,
-        ImageLayout::Undefined, ImageLayout::DepthStencilAttachmentOptimal)?;
-
-    Ok((depth_image, depth_image_memory, depth_image_view))
-}
-
-#[allow(unused_variables)]
-pub fn create_command_buffers(device: &Device,
-                              command_pool: &CommandPool,
-                              render_pass: &RenderPass,
-                              graphics_pipeline: &GraphicsPipeline,
-                              swapchain_framebuffers: &[Framebuffer],
-                              swapchain_extent: &Extent2d,
-                              vertex_buffer: &Buffer,
-                              index_buffer: &Buffer,
-                              vertex_count: u32,
-                              index_count: u32,
-                              pipeline_layout: &PipelineLayout,
-                              descriptor_set: DescriptorSet) -> VdResult<SmallVec<[CommandBuffer; 16]>>
-{
-    let command_buffers = command_pool.allocate_command_buffers(CommandBufferLevel::Primary,
-            swapchain_framebuffers.len() as u32)?;
-
-    let vertex_buffers = [vertex_buffer];
-    let offsets = [0];
-    let descriptor_sets = [&descriptor_set];
-
-    for (cmd_buf, swapchain_framebuffer) in command_buffers.iter()
-            .zip(swapchain_framebuffers.iter())
-    {
-        cmd_buf.begin(CommandBufferUsageFlags::SIMULTANEOUS_USE)?;
-
-        let clear_values = &[
-            ClearValue { color: ClearColorValue {
-                float32: [0.0f32, 0.0f32, 0.0f32, 1.0f32] } },
-            ClearValue { depthStencil: vks::VkClearDepthStencilValue {
-                depth: 1.0, stencil: 0, } },
-        ];
-
-        let render_pass_info = RenderPassBeginInfo::builder()
-            .render_pass(render_pass)
-            .framebuffer(swapchain_framebuffer)
-            .render_area(Rect2d::builder()
-                .offset(Offset2d::builder().x(0).y(0).build())
-                .extent(swapchain_extent.clone())
-                .build())
-            .clear_values(clear_values)
-            .build();
-
-        cmd_buf.begin_render_pass(&render_pass_info, SubpassContents::Inline);
-        cmd_buf.bind_pipeline(PipelineBindPoint::Graphics, &graphics_pipeline);
-        cmd_buf.bind_vertex_buffers(0, &vertex_buffers, &offsets);
-        cmd_buf.bind_index_buffer(index_buffer, 0, IndexType::Uint32);
-        cmd_buf.bind_descriptor_sets(PipelineBindPoint::Graphics, pipeline_layout,
-            0, &descriptor_sets, &[]);
+    Ok(OffscreenTarget {
+        color_image,
+        color_image_memory,
+        color_image_view,
+        extent,
+    })
+}
 
-        cmd_buf.draw_indexed(index_count, 1, 0, 0, 0);
+/// Copies the color image of `target` into host memory and returns its
+/// tightly packed RGBA8 pixels.
+///
+/// The image is expected to be in `TransferSrcOptimal` layout, which is
+/// where the offscreen render pass leaves it.
+pub fn read_offscreen_target(device: &Device,
+                             allocator: &Allocator,
+                             command_pool: &CommandPool,
+                             target: &OffscreenTarget) -> VdResult<Vec<u8>> {
+    read_color_image(device, allocator, command_pool, target.color_image.handle(),
+        ImageLayout::TransferSrcOptimal, &target.extent)
+}
 
-        cmd_buf.end_render_pass();
-        cmd_buf.end()?;
+/// Returns whether pixels read back from an image of `format` have to be
+/// swizzled to become RGBA8, or None if they can't be read back as RGBA8.
+pub fn rgba8_swizzle(format: Format) -> Option<bool> {
+    match format {
+        Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb => Some(true),
+        Format::R8G8B8A8Unorm | Format::R8G8B8A8Srgb => Some(false),
+        _ => None,
     }
-    Ok(command_buffers)
 }
 
+/// Returns true if swapchain images of `surface` can be the source of
+/// transfers, which reading them back needs.
+pub fn surface_supports_readback(surface: &SurfaceKhr,
+                                 physical_device: &PhysicalDevice) -> VdResult<bool> {
+    let details = SwapchainSupportDetails::new(surface, physical_device)?;
+    Ok(details.capabilities.supported_usage_flags().contains(ImageUsageFlags::TRANSFER_SRC))
+}
 
-pub fn create_texture_image(device: &Device,
-                            command_pool: &CommandPool,
-                            texture_path: &str) -> VdResult<(Image, DeviceMemory)> {
-    let pixels = image::open(texture_path).unwrap().to_rgba();
-    let (tex_width, tex_height) = pixels.dimensions();
-    let image_bytes = (tex_width * tex_height * 4) as u64;
-
-    let staging_buffer = Buffer::builder()
+/// Copies a 4 byte per pixel color image in `layout` into host memory.
+fn read_color_image(device: &Device,
+                    allocator: &Allocator,
+                    command_pool: &CommandPool,
+                    image: ImageHandle,
+                    layout: ImageLayout,
+                    extent: &Extent2d) -> VdResult<Vec<u8>> {
+    let width = extent.width();
+    let height = extent.height();
+    let image_bytes = (width * height * 4) as u64;
+
+    let readback_buffer = Buffer::builder()
         .size(image_bytes)
-        .usage(BufferUsageFlags::TRANSFER_SRC)
+        .usage(BufferUsageFlags::TRANSFER_DST)
         .sharing_mode(SharingMode::Exclusive)
         .build(device.clone())?;
 
-    let memory_requirements = staging_buffer.memory_requirements().clone();
-    let memory_type_index = device.memory_type_index(memory_requirements.memory_type_bits(),
+    let readback_buffer_memory = allocator.allocate_buffer(&readback_buffer,
         MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT)?;
-    let staging_buffer_memory = DeviceMemory::new(device.clone(), memory_requirements.size(),
-        memory_type_index)?;
-    unsafe {
-        staging_buffer.bind_memory(&staging_buffer_memory, 0)?;
+
+    copy_image_to_buffer(device, command_pool, image, layout, &readback_buffer,
+        width, height)?;
+
+    let data = unsafe {
+        readback_buffer_memory.map::<u8>(image_bytes)?
+    };
+    let pixels = data.to_vec();
+    readback_buffer_memory.unmap(data);
+
+    Ok(pixels)
+}
+
+/// A part of the framebuffer the scene is drawn into, as fractions of the
+/// framebuffer size so it stays put when the window is resized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewportRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl ViewportRect {
+    /// The whole framebuffer.
+    pub fn full() -> ViewportRect {
+        ViewportRect { x: 0.0, y: 0.0, width: 1.0, height: 1.0 }
     }
 
-    let mut data = unsafe {
-        staging_buffer_memory.map(0, image_bytes, MemoryMapFlags::empty())?
+    /// `count` side by side columns covering the framebuffer, for split
+    /// screen.
+    pub fn columns(count: u32) -> Vec<ViewportRect> {
+        let width = 1.0 / count.max(1) as f32;
+        (0..count.max(1)).map(|i| {
+            ViewportRect { x: i as f32 * width, y: 0.0, width, height: 1.0 }
+        }).collect()
+    }
+
+    /// The size in pixels of this part of a framebuffer of size `extent`.
+    pub fn extent(&self, extent: &Extent2d) -> Extent2d {
+        self.viewport_and_scissor(extent).1.extent().clone()
+    }
+
+    /// The viewport and scissor covering this part of a framebuffer of size
+    /// `extent`. Edges are rounded to whole pixels, so neighbouring
+    /// rectangles neither overlap nor leave a gap.
+    pub fn viewport_and_scissor(&self, extent: &Extent2d) -> (Viewport, Rect2d) {
+        let round = |fraction: f32, size: u32| {
+            (fraction.max(0.0).min(1.0) * size as f32).round() as u32
+        };
+        let (x0, x1) = (round(self.x, extent.width()),
+                        round(self.x + self.width, extent.width()));
+        let (y0, y1) = (round(self.y, extent.height()),
+                        round(self.y + self.height, extent.height()));
+        let (width, height) = (x1.saturating_sub(x0), y1.saturating_sub(y0));
+
+        let viewport = Viewport::builder()
+            .x(x0 as f32)
+            .y(y0 as f32)
+            .width(width as f32)
+            .height(height as f32)
+            .min_depth(0.0f32)
+            .max_depth(1.0f32)
+            .build();
+
+        let scissor = Rect2d::builder()
+            .offset(Offset2d::builder().x(x0 as i32).y(y0 as i32).build())
+            .extent(Extent2d::builder().width(width).height(height).build())
+            .build();
+
+        (viewport, scissor)
+    }
+}
+
+/// Records the draws of `draw_list` once per rectangle in `viewports`,
+/// with `pipelines` holding a pipeline per entry of `draw_list.pipelines`
+/// and `instance_buffer` the contents of `draw_list.instances`.
+/// `descriptor_set` has to point at the draw data of `draw_list`.
+/// Has to be called inside a render pass of size `extent`.
+///
+/// With `draw_commands` each draw reads its indices and instances from the
+/// indirect command at its position in `draw_list.draws`, as written by
+/// the culling shader. Each batch then takes indirect draw calls of up to
+/// `max_indirect_draws` commands, 1 without `multiDrawIndirect`.
+pub fn record_draw_list(cmd_buf: &CommandBuffer,
+                        draw_list: &DrawList,
+                        pipelines: &[GraphicsPipeline],
+                        instance_buffer: Option<&Buffer>,
+                        draw_commands: Option<&Buffer>,
+                        max_indirect_draws: u32,
+                        scene: &Scene,
+                        extent: &Extent2d,
+                        viewports: &[ViewportRect],
+                        pipeline_layout: &PipelineLayout,
+                        descriptor_set: &DescriptorSet) {
+    let instance_buffer = match instance_buffer {
+        Some(instance_buffer) => instance_buffer,
+        // Without instances there is nothing to draw
+        None => return,
     };
-    data.copy_from_slice(&pixels);
-    staging_buffer_memory.unmap(data);
+    // Stays bound while meshes bind their buffers to binding 0
+    cmd_buf.bind_vertex_buffers(1, &[instance_buffer], &[0]);
+    // The layout is shared, so the set stays bound across pipelines
+    cmd_buf.bind_descriptor_sets(PipelineBindPoint::Graphics, pipeline_layout,
+        0, &[descriptor_set], &[]);
+
+    for rect in viewports {
+        let (viewport, scissor) = rect.viewport_and_scissor(extent);
+        cmd_buf.set_viewport(0, &[viewport]);
+        cmd_buf.set_scissor(0, &[scissor]);
+
+        let mut bound_pipeline = None;
+        let mut bound_mesh = None;
+        for batch in &draw_list.batches {
+            if bound_pipeline != Some(batch.pipeline) {
+                cmd_buf.bind_pipeline(PipelineBindPoint::Graphics, &pipelines[batch.pipeline]);
+                bound_pipeline = Some(batch.pipeline);
+            }
+            let scene_mesh = scene.mesh(batch.mesh);
+            if bound_mesh != Some(batch.mesh) {
+                scene_mesh.mesh.bind(cmd_buf);
+                bound_mesh = Some(batch.mesh);
+            }
+            match draw_commands {
+                Some(draw_commands) => {
+                    let mut first = batch.first_draw;
+                    let end = batch.first_draw + batch.draw_count;
+                    while first < end {
+                        let count = cmp::min(end - first, max_indirect_draws.max(1) as usize);
+                        cmd_buf.draw_indexed_indirect(draw_commands,
+                            first as u64 * DRAW_INDEXED_INDIRECT_COMMAND_SIZE as u64,
+                            count as u32, DRAW_INDEXED_INDIRECT_COMMAND_SIZE);
+                        first += count;
+                    }
+                },
+                None => {
+                    let draws = &draw_list.draws[batch.first_draw..][..batch.draw_count];
+                    for draw in draws {
+                        scene_mesh.mesh.draw_sub_mesh(cmd_buf,
+                            &scene_mesh.mesh.sub_meshes()[draw.sub_mesh],
+                            draw.first_instance, draw.instance_count);
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Number of mip levels in a full chain down to 1x1 for an image of the
+/// given size.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - cmp::max(width, height).max(1).leading_zeros()
+}
+
+/// Returns true if optimally tiled images of `format` can be the source and
+/// destination of linearly filtered blits, as needed to generate mip levels.
+pub fn supports_linear_blit(device: &Device, format: Format) -> bool {
+    let props = device.physical_device().format_properties(format);
+    props.optimal_tiling_features().contains(FormatFeatureFlags::BLIT_SRC |
+        FormatFeatureFlags::BLIT_DST | FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+}
 
-    let extent = Extent3d::builder().width(tex_width).height(tex_height).depth(1).build();
+/// Scales `image` down by halves into `mip_levels` levels, the first being
+/// `image` itself.
+pub fn generate_mip_chain(image: &RgbaImage, mip_levels: u32) -> Vec<RgbaImage> {
+    let mut levels = vec![image.clone()];
+    for level in 1..mip_levels as usize {
+        let next = {
+            let previous = &levels[level - 1];
+            imageops::resize(previous,
+                             cmp::max(previous.width() / 2, 1),
+                             cmp::max(previous.height() / 2, 1),
+                             FilterType::Triangle)
+        };
+        levels.push(next);
+    }
+    levels
+}
 
-    let texture_image = Image::builder()
+/// A sampled image and the layout of its contents.
+pub struct TextureImage {
+    pub image: Image,
+    pub image_memory: Allocation,
+    pub format: Format,
+    pub mip_levels: u32,
+    pub array_layers: u32,
+}
+
+/// Returns true if optimally tiled images of `format` can be sampled.
+pub fn supports_sampling(device: &Device, format: Format) -> bool {
+    let props = device.physical_device().format_properties(format);
+    props.optimal_tiling_features().contains(FormatFeatureFlags::SAMPLED_IMAGE)
+}
+
+fn create_sampled_image(device: &Device,
+                        allocator: &Allocator,
+                        format: Format,
+                        width: u32,
+                        height: u32,
+                        mip_levels: u32,
+                        array_layers: u32) -> VdResult<(Image, Allocation)> {
+    let image = Image::builder()
         .image_type(ImageType::Type2d)
-        // .format(Format::R8G8B8A8Unorm)
-        .format(Format::R8G8B8A8Srgb)
-        .extent(extent.clone())
-        .mip_levels(1)
-        .array_layers(1)
+        .format(format)
+        .extent(Extent3d::builder().width(width).height(height).depth(1).build())
+        .mip_levels(mip_levels)
+        .array_layers(array_layers)
         .samples(SampleCountFlags::COUNT_1)
         .tiling(ImageTiling::Optimal)
-        .usage(ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::SAMPLED)
+        .usage(ImageUsageFlags::TRANSFER_SRC | ImageUsageFlags::TRANSFER_DST |
+            ImageUsageFlags::SAMPLED)
         .sharing_mode(SharingMode::Exclusive)
         .initial_layout(ImageLayout::Undefined)
         .build(device.clone())?;
 
-    let memory_requirements = texture_image.memory_requirements().clone();
-    let memory_type_index = device.memory_type_index(memory_requirements.memory_type_bits(),
-        MemoryPropertyFlags::DEVICE_LOCAL)?;
-    let texture_image_memory = DeviceMemory::new(device.clone(), memory_requirements.size(),
-        memory_type_index)?;
-    unsafe {
-        texture_image.bind_memory(&texture_image_memory, 0)?;
+    let image_memory = allocator.allocate_image(&image, MemoryPropertyFlags::DEVICE_LOCAL)?;
+    Ok((image, image_memory))
+}
+
+/// Loads the texture at `texture_path` and queues uploading it. KTX2 and
+/// DDS files are used as stored, other images are decoded to RGBA8.
+pub fn create_texture_image(device: &Device,
+                            allocator: &Allocator,
+                            uploads: &mut UploadManager,
+                            texture_path: &str) -> Result<TextureImage> {
+    if texture_file::is_texture_file(Path::new(texture_path)) {
+        let data = texture_file::load(Path::new(texture_path))?;
+        return create_texture_image_from_data(device, allocator, uploads, data);
+    }
+
+    let pixels = image::open(texture_path)?.to_rgba();
+    let (tex_width, tex_height) = pixels.dimensions();
+    let mip_levels = mip_level_count(tex_width, tex_height);
+    let format = Format::R8G8B8A8Srgb;
+
+    let (texture_image, texture_image_memory) = create_sampled_image(device, allocator,
+        format, tex_width, tex_height, mip_levels, 1)?;
+
+    if supports_linear_blit(device, format) {
+        uploads.upload_image(&texture_image, format, tex_width, tex_height,
+            mip_levels, 1, &[&*pixels])?;
+    } else {
+        // The device can't filter blits of this format, scale on the CPU
+        let levels = generate_mip_chain(&pixels, mip_levels);
+        let levels: Vec<&[u8]> = levels.iter().map(|level| &**level).collect();
+        uploads.upload_image(&texture_image, format, tex_width, tex_height,
+            mip_levels, 1, &levels)?;
     }
 
-    transition_image_layout(device, command_pool, &texture_image, Format::R8G8B8A8Unorm,
-        ImageLayout::Undefined, ImageLayout::TransferDstOptimal)?;
+    Ok(TextureImage {
+        image: texture_image,
+        image_memory: texture_image_memory,
+        format,
+        mip_levels,
+        array_layers: 1,
+    })
+}
 
-    copy_buffer_to_image(device, command_pool, &staging_buffer, &texture_image,
-        extent.width(), extent.height())?;
+/// Creates an image holding all levels and layers of `data`.
+fn create_texture_image_from_data(device: &Device,
+                                  allocator: &Allocator,
+                                  uploads: &mut UploadManager,
+                                  data: TextureData) -> Result<TextureImage> {
+    let data = if supports_sampling(device, data.format) {
+        data
+    } else if data.is_block_compressed() {
+        // Without BC support the texture is uploaded decompressed
+        data.decompress()?
+    } else {
+        return Err(format!("{:?} textures can't be sampled on this device",
+            data.format).into());
+    };
 
-    transition_image_layout(device, command_pool, &texture_image, Format::R8G8B8A8Unorm,
-        ImageLayout::TransferDstOptimal, ImageLayout::ShaderReadOnlyOptimal)?;
+    // Uncompressed files with a single level get a full mip chain generated
+    // by blits, like decoded images
+    let mip_levels = if data.mip_levels == 1 && !data.is_block_compressed() &&
+        supports_linear_blit(device, data.format) {
+        mip_level_count(data.width, data.height)
+    } else {
+        data.mip_levels
+    };
 
-    Ok((texture_image, texture_image_memory))
+    let (image, image_memory) = create_sampled_image(device, allocator, data.format,
+        data.width, data.height, mip_levels, data.array_layers)?;
+    let levels: Vec<&[u8]> = data.levels.iter().map(|level| &**level).collect();
+    uploads.upload_image(&image, data.format, data.width, data.height, mip_levels,
+        data.array_layers, &levels)?;
+
+    Ok(TextureImage {
+        image,
+        image_memory,
+        format: data.format,
+        mip_levels,
+        array_layers: data.array_layers,
+    })
 }
 
+/// Creates a view of the first layer of `texture`, as the shaders sample
+/// every texture as a `sampler2D`.
 pub fn create_texture_image_view(device: Device,
-                                 image: &Image) -> VdResult<ImageView> {
+                                 texture: &TextureImage) -> VdResult<ImageView> {
     ImageView::builder()
-        .image(image.handle())
+        .image(texture.image.handle())
         .view_type(ImageViewType::Type2d)
-        // .format(Format::R8G8B8A8Unorm)
-        .format(Format::R8G8B8A8Srgb)
+        .format(texture.format)
         .components(ComponentMapping::default())
         .subresource_range(ImageSubresourceRange::builder()
             .aspect_mask(ImageAspectFlags::COLOR)
             .base_mip_level(0)
-            .level_count(1)
+            .level_count(texture.mip_levels)
             .base_array_layer(0)
             .layer_count(1)
             .build())
         .build(device, None)
 }
 
-pub fn create_texture_sampler(device: Device) -> VdResult<Sampler> {
+/// Creates a trilinear sampler for textures with `mip_levels` levels.
+pub fn create_texture_sampler(device: Device,
+                              mip_levels: u32) -> VdResult<Sampler> {
     Sampler::builder()
         .mag_filter(Filter::Linear)
         .min_filter(Filter::Linear)
@@ -1239,7 +2140,7 @@ pub fn create_texture_sampler(device: Device) -> VdResult<Sampler> {
         .compare_enable(false)
         .compare_op(CompareOp::Always)
         .min_lod(0.)
-        .max_lod(0.)
+        .max_lod(mip_levels as f32)
         .border_color(BorderColor::IntOpaqueBlack)
         .unnormalized_coordinates(false)
         .build(device)